@@ -13,6 +13,8 @@ interface QueueItem {
   video_name: string | null
   created_at: number // Unix timestamp in milliseconds for queue ordering
   variant_percentage?: number // 0-100, progress within current encoding variant
+  encode_speed?: number // FFmpeg speed as a multiple of realtime
+  eta_seconds?: number // Estimated seconds left in the current FFmpeg step
 }
 
 interface QueueListResponse {
@@ -22,6 +24,12 @@ interface QueueListResponse {
   failed_count: number
 }
 
+function formatEta(seconds: number) {
+  if (seconds < 60) return `${seconds}s`
+  if (seconds < 3600) return `${Math.floor(seconds / 60)}m ${seconds % 60}s`
+  return `${Math.floor(seconds / 3600)}h ${Math.floor((seconds % 3600) / 60)}m`
+}
+
 function formatSince(timestampMs: number | null | undefined) {
   if (!timestampMs) return '—'
   const deltaSec = Math.max(0, Math.floor((Date.now() - timestampMs) / 1000))
@@ -473,6 +481,8 @@ export default function ProcessingQueues() {
                               max='100'
                             />
                             <span>{item.variant_percentage}%</span>
+                            {item.encode_speed !== undefined && <span>{item.encode_speed.toFixed(2)}x</span>}
+                            {item.eta_seconds !== undefined && <span>ETA {formatEta(item.eta_seconds)}</span>}
                           </div>
                         )}
                       <div className='flex justify-between mt-1 text-xs text-base-content/70'>
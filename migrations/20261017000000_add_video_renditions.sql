@@ -0,0 +1,18 @@
+-- Encoded renditions per video, with the inputs that chose their bitrate
+CREATE TABLE IF NOT EXISTS video_renditions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    video_id TEXT NOT NULL,
+    codec TEXT NOT NULL,
+    label TEXT NOT NULL,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    fps REAL NOT NULL,
+    bitrate INTEGER NOT NULL,
+    model_bitrate INTEGER NOT NULL,
+    complexity REAL,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (video_id) REFERENCES videos(id) ON DELETE CASCADE,
+    UNIQUE(video_id, codec, label)
+);
+
+CREATE INDEX IF NOT EXISTS idx_video_renditions_video_id ON video_renditions(video_id);
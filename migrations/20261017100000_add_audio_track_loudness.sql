@@ -0,0 +1,2 @@
+-- Integrated loudness (LUFS) measured by the loudnorm pass
+ALTER TABLE audio_tracks ADD COLUMN integrated_loudness REAL;
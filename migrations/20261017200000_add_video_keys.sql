@@ -0,0 +1,7 @@
+-- AES-128 content keys for encrypted HLS segments, never stored in the bucket
+CREATE TABLE IF NOT EXISTS video_keys (
+    video_id TEXT PRIMARY KEY NOT NULL,
+    key BLOB NOT NULL,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (video_id) REFERENCES videos(id) ON DELETE CASCADE
+);
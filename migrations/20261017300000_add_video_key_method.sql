@@ -0,0 +1,3 @@
+-- CENC ClearKey keys share video_keys with AES-128 keys
+ALTER TABLE video_keys ADD COLUMN method TEXT NOT NULL DEFAULT 'aes-128';
+ALTER TABLE video_keys ADD COLUMN kid BLOB;
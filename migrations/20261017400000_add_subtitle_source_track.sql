@@ -0,0 +1,2 @@
+-- OCR text tracks point at the bitmap subtitle track they were read from
+ALTER TABLE subtitles ADD COLUMN source_track_index INTEGER;
@@ -0,0 +1,2 @@
+-- WebVTT index of the trickplay sprite sheets; NULL for videos with a single legacy sprite
+ALTER TABLE videos ADD COLUMN thumbnails_vtt_key TEXT;
@@ -0,0 +1,3 @@
+-- Muted hover loop (preview.mp4 / animated preview.webp); NULL when previews were off
+ALTER TABLE videos ADD COLUMN preview_mp4_key TEXT;
+ALTER TABLE videos ADD COLUMN preview_webp_key TEXT;
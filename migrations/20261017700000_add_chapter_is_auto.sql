@@ -0,0 +1,2 @@
+-- Chapters generated from scene detection rather than read from the container
+ALTER TABLE chapters ADD COLUMN is_auto INTEGER NOT NULL DEFAULT 0;
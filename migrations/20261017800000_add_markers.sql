@@ -0,0 +1,25 @@
+-- Chromaprint fingerprints of the intro and credits windows, matched across a series
+CREATE TABLE IF NOT EXISTS audio_fingerprints (
+    video_id TEXT NOT NULL,
+    region TEXT NOT NULL,      -- 'intro' or 'credits'
+    start_time REAL NOT NULL,  -- Window start in seconds
+    data BLOB NOT NULL,        -- Little-endian u32 fingerprint items
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (video_id) REFERENCES videos(id) ON DELETE CASCADE,
+    PRIMARY KEY (video_id, region)
+);
+
+-- Skippable ranges (intro, recap, credits), detected or set by an admin
+CREATE TABLE IF NOT EXISTS markers (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    video_id TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    start_time REAL NOT NULL,
+    end_time REAL NOT NULL,
+    is_auto INTEGER NOT NULL DEFAULT 0,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (video_id) REFERENCES videos(id) ON DELETE CASCADE,
+    UNIQUE(video_id, kind)
+);
+
+CREATE INDEX IF NOT EXISTS idx_markers_video_id ON markers(video_id);
@@ -0,0 +1,4 @@
+-- Archived original upload (NULL when archiving was off) and the storage
+-- revision the encoded files are served from; reprocessing bumps it
+ALTER TABLE videos ADD COLUMN source_key TEXT;
+ALTER TABLE videos ADD COLUMN revision INTEGER NOT NULL DEFAULT 0;
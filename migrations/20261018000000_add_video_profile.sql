@@ -0,0 +1,3 @@
+-- Encoding profile a video was processed with, NULL for the built-in defaults;
+-- renditions added later and reprocessing reuse it
+ALTER TABLE videos ADD COLUMN profile TEXT;
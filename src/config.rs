@@ -1,7 +1,10 @@
+use crate::types::{AudioCodec, VideoCodec};
 use anyhow::{Context, Result};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::Path;
 use tokio::fs;
+use tracing::warn;
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
@@ -9,6 +12,9 @@ pub struct Config {
     pub r2: R2Config,
     pub video: VideoConfig,
     pub clickhouse: ClickHouseConfig,
+    /// Connection of an `akane worker` process to the main server
+    #[serde(default)]
+    pub worker: WorkerConfig,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -21,6 +27,18 @@ pub struct ServerConfig {
     pub max_concurrent_uploads: usize,
     #[serde(default)]
     pub root_redirect_url: Option<String>,
+    /// Bearer token of remote encoding workers. When set, uploads are encoded by
+    /// `akane worker` processes claiming jobs from `/api/worker/jobs` instead of here.
+    #[serde(default)]
+    pub worker_token: Option<String>,
+    /// Seconds a queued encode may go without a worker holding it before the server
+    /// encodes it itself; 0 waits for a worker indefinitely
+    #[serde(default = "default_worker_claim_timeout")]
+    pub worker_claim_timeout: u64,
+}
+
+fn default_worker_claim_timeout() -> u64 {
+    300
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -32,9 +50,389 @@ pub struct R2Config {
     pub public_base_url: String,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct VideoConfig {
     pub encoder: String,
+    /// Named encoding profiles uploads can pick with the `profile` option
+    #[serde(default)]
+    pub profiles: HashMap<String, EncodingProfile>,
+    /// Profile used when an upload names none; the built-in defaults when unset
+    #[serde(default)]
+    pub default_profile: Option<String>,
+    /// Additional codec families ("hevc", "av1") encoded as CMAF next to the H.264 ladder
+    #[serde(default)]
+    pub extra_codecs: Vec<String>,
+    #[serde(default)]
+    pub per_title: PerTitleConfig,
+    /// Decode the source once per codec family and encode all variants from one FFmpeg process
+    #[serde(default)]
+    pub decode_once: bool,
+    #[serde(default)]
+    pub chunked_encoding: ChunkedEncodingConfig,
+    /// Keep HDR sources as an extra 10-bit HEVC ladder next to the tone-mapped SDR one
+    #[serde(default)]
+    pub hdr_renditions: bool,
+    /// Codec of the extra multichannel rendition made for surround audio tracks ("aac" or "eac3")
+    #[serde(default)]
+    pub surround_codec: AudioCodec,
+    #[serde(default)]
+    pub loudness: LoudnessConfig,
+    #[serde(default)]
+    pub ocr: OcrConfig,
+    #[serde(default)]
+    pub trickplay: TrickplayConfig,
+    #[serde(default)]
+    pub hover_preview: HoverPreviewConfig,
+    #[serde(default)]
+    pub auto_chapters: AutoChaptersConfig,
+    #[serde(default)]
+    pub intro_detection: IntroDetectionConfig,
+    /// Keep the original upload at `{id}/source/` so the video can be reprocessed later
+    #[serde(default)]
+    pub archive_source: bool,
+    /// Package every rendition as CMAF and write a DASH `manifest.mpd` next to the HLS master
+    #[serde(default)]
+    pub dash: bool,
+    /// AES-128 encrypt HLS segments with a per-video key served by `/api/videos/{id}/key`
+    #[serde(default)]
+    pub encryption: bool,
+    /// Common Encryption (cenc) of CMAF renditions with a ClearKey license at `/api/videos/{id}/license`.
+    /// Takes precedence over `encryption`.
+    #[serde(default)]
+    pub clearkey_drm: bool,
+}
+
+/// Rate control of the video encoders. CRF and CQ use the profile's `quality`;
+/// hardware encoders map CRF to their constant-quality VBR mode.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateControl {
+    Cbr,
+    #[default]
+    Vbr,
+    Crf,
+    Cq,
+}
+
+/// How a video is encoded: ladder, rate control, GOP and segmenting, audio and sprites
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct EncodingProfile {
+    /// Ladder tiers by short edge in pixels; 2160 is labelled "4K"
+    pub tiers: Vec<u32>,
+    pub rate_control: RateControl,
+    /// CRF or constant quantizer for the `crf` and `cq` modes
+    pub quality: u32,
+    /// Encoder preset (x264/x265 name, NVENC p1-p7, QSV name, AMF quality);
+    /// the per-encoder default when unset
+    pub preset: Option<String>,
+    /// SVT-AV1 preset (0-13) for the AV1 ladder
+    pub av1_preset: u32,
+    /// HLS segment length in seconds; keyframes are forced on segment boundaries
+    pub segment_duration: u32,
+    /// Seconds between keyframes
+    pub keyframe_interval: f64,
+    pub audio: AudioProfile,
+    /// Width of the poster thumbnail and its candidates
+    pub thumbnail_width: u32,
+    /// Sprite sheet settings, the global `trickplay` block when unset
+    pub trickplay: Option<TrickplayConfig>,
+}
+
+impl Default for EncodingProfile {
+    fn default() -> Self {
+        Self {
+            tiers: vec![360, 480, 720, 1080, 1440, 2160],
+            rate_control: RateControl::Vbr,
+            quality: 23,
+            preset: None,
+            av1_preset: 8,
+            segment_duration: 4,
+            keyframe_interval: 2.0,
+            audio: AudioProfile::default(),
+            thumbnail_width: 480,
+            trickplay: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AudioProfile {
+    /// Bitrate per channel in kbps
+    pub bitrate_per_channel: u32,
+    /// Floor for mono and stereo renditions in kbps
+    pub min_bitrate: u32,
+    /// Output sample rate in Hz; the source rate when unset
+    pub sample_rate: Option<u32>,
+}
+
+impl Default for AudioProfile {
+    fn default() -> Self {
+        Self {
+            bitrate_per_channel: 64,
+            min_bitrate: 96,
+            sample_rate: None,
+        }
+    }
+}
+
+impl AudioProfile {
+    /// Target bitrate in kbps for a channel count
+    pub fn bitrate(&self, channels: u32) -> u32 {
+        (channels.max(1) * self.bitrate_per_channel).max(self.min_bitrate)
+    }
+}
+
+/// EBU R128 normalization: a two-pass `loudnorm` measures each track, then applies linear gain
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LoudnessConfig {
+    pub enabled: bool,
+    /// Integrated loudness target in LUFS
+    pub target_lufs: f64,
+    /// Maximum true peak in dBTP
+    pub true_peak: f64,
+    /// Loudness range target in LU
+    pub loudness_range: f64,
+}
+
+impl Default for LoudnessConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            target_lufs: -16.0,
+            true_peak: -1.5,
+            loudness_range: 11.0,
+        }
+    }
+}
+
+/// Split long sources into time ranges encoded side by side on idle encode slots,
+/// then joined into continuous media playlists
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ChunkedEncodingConfig {
+    pub enabled: bool,
+    /// Sources shorter than this many seconds are encoded in one pass
+    pub min_duration: u32,
+    /// Length of each range in seconds, rounded to whole segments
+    pub chunk_duration: u32,
+}
+
+impl Default for ChunkedEncodingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_duration: 1800,
+            chunk_duration: 300,
+        }
+    }
+}
+
+/// Content-aware ladder: a CRF probe encode of sampled segments scales the BPP bitrates
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PerTitleConfig {
+    pub enabled: bool,
+    /// Number of segments sampled across the video
+    pub samples: u32,
+    /// Length of each sampled segment in seconds
+    pub sample_duration: u32,
+    /// x264 CRF of the probe encode; its bitrate is compared to the BPP model
+    pub crf: u32,
+}
+
+impl Default for PerTitleConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            samples: 6,
+            sample_duration: 4,
+            crf: 23,
+        }
+    }
+}
+
+/// OCR of bitmap subtitles (PGS/VobSub) into companion text tracks with tesseract
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct OcrConfig {
+    pub enabled: bool,
+    /// tesseract binary
+    pub tesseract_path: String,
+    /// tesseract language used when the track language has no installed traineddata
+    pub default_language: String,
+}
+
+impl Default for OcrConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            tesseract_path: "tesseract".to_string(),
+            default_language: "eng".to_string(),
+        }
+    }
+}
+
+/// Seek-bar previews: one frame per interval, tiled into as many sprite sheets as needed
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TrickplayConfig {
+    /// Seconds between preview frames
+    pub interval: f64,
+    /// Tiles per sheet row
+    pub columns: u32,
+    /// Tile rows per sheet
+    pub rows: u32,
+    /// Tile width in pixels; the height follows the display aspect ratio
+    pub width: u32,
+}
+
+impl Default for TrickplayConfig {
+    fn default() -> Self {
+        Self {
+            interval: 5.0,
+            columns: 10,
+            rows: 10,
+            width: 160,
+        }
+    }
+}
+
+/// Muted hover loop: short clips from across the video joined into `preview.mp4` and `preview.webp`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HoverPreviewConfig {
+    pub enabled: bool,
+    /// Number of clips sampled across the video
+    pub clips: u32,
+    /// Length of each clip in seconds
+    pub clip_duration: f64,
+    /// Output width in pixels; the height follows the aspect ratio
+    pub width: u32,
+    pub fps: u32,
+}
+
+impl Default for HoverPreviewConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            clips: 5,
+            clip_duration: 1.5,
+            width: 320,
+            fps: 12,
+        }
+    }
+}
+
+/// Chapters from scene changes for sources without container chapters
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AutoChaptersConfig {
+    pub enabled: bool,
+    /// Minimum `scene` score (0-1) of a shot change
+    pub scene_threshold: f64,
+    /// Shortest chapter in seconds; shorter shots are merged into their neighbours
+    pub min_chapter_duration: f64,
+    pub max_chapters: u32,
+}
+
+impl Default for AutoChaptersConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            scene_threshold: 0.4,
+            min_chapter_duration: 60.0,
+            max_chapters: 12,
+        }
+    }
+}
+
+/// Intro/credits detection: audio fingerprints of the start and end of every upload are
+/// matched against other episodes sharing a series tag
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct IntroDetectionConfig {
+    pub enabled: bool,
+    /// Tags starting with this prefix group episodes of one series
+    pub series_tag_prefix: String,
+    /// Seconds from the start searched for the intro
+    pub intro_window: f64,
+    /// Seconds before the end searched for the credits
+    pub credits_window: f64,
+    /// Shortest and longest shared audio accepted as intro or credits, in seconds
+    pub min_duration: f64,
+    pub max_duration: f64,
+    /// Differing bits (of 32) at which two fingerprint items still match
+    pub max_bit_errors: u32,
+    /// Other episodes, closest by upload date, each episode is compared with
+    pub compare_episodes: usize,
+}
+
+impl Default for IntroDetectionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            series_tag_prefix: "series:".to_string(),
+            intro_window: 600.0,
+            credits_window: 360.0,
+            min_duration: 15.0,
+            max_duration: 150.0,
+            max_bit_errors: 6,
+            compare_episodes: 4,
+        }
+    }
+}
+
+impl VideoConfig {
+    /// Resolve a profile by name, falling back to `default_profile`, then the built-in defaults
+    pub fn profile(&self, name: Option<&str>) -> Result<EncodingProfile> {
+        match name.or(self.default_profile.as_deref()) {
+            Some(name) => self
+                .profiles
+                .get(name)
+                .cloned()
+                .with_context(|| format!("Unknown encoding profile: {}", name)),
+            None => Ok(EncodingProfile::default()),
+        }
+    }
+
+    /// Codec families to encode, H.264 first. Unknown names are skipped with a warning.
+    pub fn codec_families(&self) -> Vec<VideoCodec> {
+        let mut codecs = vec![VideoCodec::H264];
+        for name in &self.extra_codecs {
+            match VideoCodec::from_config(name) {
+                Some(codec) if !codecs.contains(&codec) => codecs.push(codec),
+                Some(_) => {}
+                None => warn!("Ignoring unknown codec in video.extra_codecs: {}", name),
+            }
+        }
+        codecs
+    }
+}
+
+/// Remote worker side: where to claim jobs. R2 credentials and `video.encoder`
+/// come from the worker's own config; every other video setting from the job.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct WorkerConfig {
+    /// Base URL of the main server, e.g. `http://10.0.0.2:3000`
+    pub server_url: String,
+    /// Must match the server's `server.worker_token`
+    pub token: String,
+    /// Seconds between claim attempts while the queue is empty
+    pub poll_interval: u64,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        Self {
+            server_url: "http://localhost:3000".to_string(),
+            token: String::new(),
+            poll_interval: 5,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -52,6 +450,10 @@ impl Config {
             .context("Failed to read config file")?;
         let config: Config =
             serde_yaml::from_str(&content).context("Failed to parse config file")?;
+        config
+            .video
+            .profile(None)
+            .context("Invalid video.default_profile")?;
         Ok(config)
     }
 }
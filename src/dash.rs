@@ -0,0 +1,434 @@
+use crate::hls::{SUBTITLE_WEBVTT_FILE, audio_group_ids, clearkey_pssh, video_codec_string};
+use crate::types::{AudioRendition, HlsSegmentType, SubtitleStreamInfo, VideoVariant};
+use anyhow::{Context, Result};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use std::path::Path;
+use tokio::fs;
+
+/// Escape a value for an XML attribute or text node
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// `frameRate` value: an integer, or a fraction since the attribute takes no decimals
+fn frame_rate(fps: f64) -> String {
+    if (fps - fps.round()).abs() < 0.001 {
+        format!("{}", fps.round() as u32)
+    } else {
+        format!("{}/1000", (fps * 1000.0).round() as u32)
+    }
+}
+
+/// Segment durations (seconds) listed in an HLS media playlist
+fn playlist_segment_durations(playlist: &str) -> Vec<f64> {
+    playlist
+        .lines()
+        .filter_map(|line| line.strip_prefix("#EXTINF:"))
+        .filter_map(|rest| rest.split(',').next()?.trim().parse().ok())
+        .collect()
+}
+
+/// `SegmentTemplate` for an HLS fMP4 rendition directory, timed from its media playlist
+async fn segment_template(out_dir: &Path, dir: &str) -> Result<String> {
+    let playlist = fs::read_to_string(out_dir.join(dir).join("index.m3u8"))
+        .await
+        .with_context(|| format!("failed to read media playlist of {}", dir))?;
+    let durations = playlist_segment_durations(&playlist);
+    if durations.is_empty() {
+        anyhow::bail!("media playlist of {} lists no segments", dir);
+    }
+
+    // Millisecond timeline; repeated durations collapse into one S element
+    let mut timeline = String::new();
+    let mut start = 0u64;
+    let mut elapsed = 0.0;
+    let mut run: Option<(u64, u64, u32)> = None;
+    for duration in durations {
+        elapsed += duration;
+        let end = (elapsed * 1000.0).round() as u64;
+        let d = end - start;
+        run = match run {
+            Some((t, run_d, repeat)) if run_d == d => Some((t, run_d, repeat + 1)),
+            Some((t, run_d, repeat)) => {
+                timeline.push_str(&timeline_entry(t, run_d, repeat));
+                Some((start, d, 0))
+            }
+            None => Some((start, d, 0)),
+        };
+        start = end;
+    }
+    if let Some((t, d, repeat)) = run {
+        timeline.push_str(&timeline_entry(t, d, repeat));
+    }
+
+    Ok(format!(
+        "        <SegmentTemplate timescale=\"1000\" initialization=\"{dir}/init.mp4\" media=\"{dir}/segment_$Number%03d$.m4s\" startNumber=\"0\">\n          <SegmentTimeline>\n{timeline}          </SegmentTimeline>\n        </SegmentTemplate>\n",
+        dir = dir,
+        timeline = timeline
+    ))
+}
+
+/// ContentProtection descriptors for CENC segments licensed through ClearKey
+fn content_protection(kid: &[u8; 16]) -> String {
+    let hex = hex::encode(kid);
+    let default_kid = format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    );
+    format!(
+        "      <ContentProtection schemeIdUri=\"urn:mpeg:dash:mp4protection:2011\" value=\"cenc\" cenc:default_KID=\"{}\"/>\n      <ContentProtection schemeIdUri=\"urn:uuid:e2719d58-a985-b3c9-781a-b030af78d30e\" value=\"ClearKey1.0\">\n        <cenc:pssh>{}</cenc:pssh>\n      </ContentProtection>\n",
+        default_kid,
+        STANDARD.encode(clearkey_pssh(kid))
+    )
+}
+
+fn timeline_entry(t: u64, d: u64, repeat: u32) -> String {
+    if repeat > 0 {
+        format!(
+            "            <S t=\"{}\" d=\"{}\" r=\"{}\"/>\n",
+            t, d, repeat
+        )
+    } else {
+        format!("            <S t=\"{}\" d=\"{}\"/>\n", t, d)
+    }
+}
+
+/// `Representation` of a CMAF video rendition encoded into `out_dir`
+async fn video_representation(out_dir: &Path, variant: &VideoVariant) -> Result<String> {
+    Ok(format!(
+        "      <Representation id=\"{}\" codecs=\"{}\" bandwidth=\"{}\" width=\"{}\" height=\"{}\" frameRate=\"{}\" sar=\"1:1\">\n{}      </Representation>\n",
+        variant.dir(),
+        video_codec_string(variant),
+        variant.bandwidth(),
+        variant.width,
+        variant.height,
+        frame_rate(variant.fps),
+        segment_template(out_dir, &variant.dir()).await?
+    ))
+}
+
+/// Write `manifest.mpd` next to the HLS master, referencing the same CMAF segments.
+///
+/// Video renditions form one adaptation set per codec family, each source audio
+/// track one adaptation set with its stereo and multichannel representations, and
+/// every WebVTT subtitle rendition a sidecar text adaptation set. `cenc_kid` marks
+/// the media adaptation sets as CENC protected with a ClearKey license.
+pub async fn write_dash_manifest(
+    out_dir: &Path,
+    variants: &[VideoVariant],
+    audio: &[AudioRendition],
+    subtitles: &[SubtitleStreamInfo],
+    duration: u32,
+    cenc_kid: Option<&[u8; 16]>,
+) -> Result<()> {
+    let mut adaptation_sets = String::new();
+    let mut set_id = 0u32;
+    let protection = cenc_kid.map(content_protection).unwrap_or_default();
+
+    // Video: one adaptation set per codec family (codec + HDR)
+    let mut families: Vec<String> = Vec::new();
+    for variant in variants {
+        if variant.segment_type == HlsSegmentType::Fmp4 && !families.contains(&variant.codec_id()) {
+            families.push(variant.codec_id());
+        }
+    }
+    for family in &families {
+        let members: Vec<&VideoVariant> = variants
+            .iter()
+            .filter(|v| v.segment_type == HlsSegmentType::Fmp4 && &v.codec_id() == family)
+            .collect();
+        let max_width = members.iter().map(|v| v.width).max().unwrap_or(0);
+        let max_height = members.iter().map(|v| v.height).max().unwrap_or(0);
+
+        adaptation_sets.push_str(&format!(
+            "    <AdaptationSet id=\"{}\" contentType=\"video\" mimeType=\"video/mp4\" segmentAlignment=\"true\" startWithSAP=\"1\" maxWidth=\"{}\" maxHeight=\"{}\">\n",
+            set_id, max_width, max_height
+        ));
+        adaptation_sets.push_str(&protection);
+        for variant in members {
+            adaptation_sets.push_str(&video_representation(out_dir, variant).await?);
+        }
+        adaptation_sets.push_str("    </AdaptationSet>\n");
+        set_id += 1;
+    }
+
+    if families.is_empty() {
+        anyhow::bail!("no CMAF video renditions to reference");
+    }
+
+    // Audio: one adaptation set per source track, one representation per channel layout
+    let mut tracks: Vec<usize> = Vec::new();
+    for rendition in audio {
+        if !tracks.contains(&rendition.track) {
+            tracks.push(rendition.track);
+        }
+    }
+    let (stereo_group, surround_group) = audio_group_ids(HlsSegmentType::Fmp4);
+    for track in tracks {
+        // Stereo-only tracks are listed in both groups with the same playlist
+        let mut dirs: Vec<&str> = Vec::new();
+        let representations: Vec<&AudioRendition> = audio
+            .iter()
+            .filter(|a| a.track == track)
+            .filter(|a| a.group_id == stereo_group || a.group_id == surround_group)
+            .filter(|a| {
+                let new = !dirs.contains(&a.dir.as_str());
+                dirs.push(&a.dir);
+                new
+            })
+            .collect();
+        let Some(first) = representations.first() else {
+            continue;
+        };
+
+        adaptation_sets.push_str(&format!(
+            "    <AdaptationSet id=\"{}\" contentType=\"audio\" mimeType=\"audio/mp4\" lang=\"{}\" segmentAlignment=\"true\" startWithSAP=\"1\">\n",
+            set_id,
+            xml_escape(&first.language)
+        ));
+        adaptation_sets.push_str(&format!(
+            "      <Label>{}</Label>\n",
+            xml_escape(&first.name)
+        ));
+        adaptation_sets.push_str(&protection);
+        if first.is_default {
+            adaptation_sets
+                .push_str("      <Role schemeIdUri=\"urn:mpeg:dash:role:2011\" value=\"main\"/>\n");
+        }
+        for rendition in representations {
+            adaptation_sets.push_str(&format!(
+                "      <Representation id=\"{}\" codecs=\"{}\" bandwidth=\"{}\">\n        <AudioChannelConfiguration schemeIdUri=\"urn:mpeg:dash:23003:3:audio_channel_configuration:2011\" value=\"{}\"/>\n",
+                rendition.dir,
+                rendition.codec.codec_string(),
+                rendition.bitrate * 1000,
+                rendition.channels
+            ));
+            adaptation_sets.push_str(&segment_template(out_dir, &rendition.dir).await?);
+            adaptation_sets.push_str("      </Representation>\n");
+        }
+        adaptation_sets.push_str("    </AdaptationSet>\n");
+        set_id += 1;
+    }
+
+    // Text: the whole WebVTT of each fMP4 subtitle rendition; tracks that failed
+    // conversion have none
+    for (idx, sub) in subtitles.iter().enumerate() {
+        let path = format!("subs_{}_fmp4/{}", idx, SUBTITLE_WEBVTT_FILE);
+        if !fs::try_exists(out_dir.join(&path)).await.unwrap_or(false) {
+            continue;
+        }
+
+        let language = sub.language.as_deref().unwrap_or("und");
+        let role = if sub.is_forced {
+            "forced-subtitle"
+        } else {
+            "subtitle"
+        };
+        adaptation_sets.push_str(&format!(
+            "    <AdaptationSet id=\"{}\" contentType=\"text\" mimeType=\"text/vtt\" lang=\"{}\">\n",
+            set_id,
+            xml_escape(language)
+        ));
+        if let Some(title) = &sub.title {
+            adaptation_sets.push_str(&format!("      <Label>{}</Label>\n", xml_escape(title)));
+        }
+        adaptation_sets.push_str(&format!(
+            "      <Role schemeIdUri=\"urn:mpeg:dash:role:2011\" value=\"{}\"/>\n      <Representation id=\"subtitle_{}\" bandwidth=\"256\">\n        <BaseURL>{}</BaseURL>\n      </Representation>\n    </AdaptationSet>\n",
+            role, idx, path
+        ));
+        set_id += 1;
+    }
+
+    let manifest = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<MPD xmlns=\"urn:mpeg:dash:schema:mpd:2011\" xmlns:cenc=\"urn:mpeg:cenc:2013\" profiles=\"urn:mpeg:dash:profile:isoff-live:2011\" type=\"static\" mediaPresentationDuration=\"PT{}S\" minBufferTime=\"PT4S\">\n  <Period id=\"0\" start=\"PT0S\">\n{}  </Period>\n</MPD>\n",
+        duration, adaptation_sets
+    );
+
+    fs::write(out_dir.join("manifest.mpd"), manifest)
+        .await
+        .context("failed to write DASH manifest")?;
+
+    Ok(())
+}
+
+/// Manifest without the representations of the rendition directories `dirs`
+pub fn remove_dash_representations(manifest: &str, dirs: &[String]) -> String {
+    let mut out = String::with_capacity(manifest.len());
+    let mut skipping = false;
+    for line in manifest.lines() {
+        if !skipping
+            && dirs.iter().any(|dir| {
+                line.starts_with(&format!(
+                    "      <Representation id=\"{}\" ",
+                    xml_escape(dir)
+                ))
+            })
+        {
+            skipping = true;
+        }
+        if !skipping {
+            out.push_str(line);
+            out.push('\n');
+        }
+        if skipping && line == "      </Representation>" {
+            skipping = false;
+        }
+    }
+    out
+}
+
+/// Manifest with `variant`, encoded into `out_dir`, added to the adaptation set
+/// holding `template_dir`. The set's `maxWidth`/`maxHeight` grow to fit it.
+pub async fn add_dash_representation(
+    manifest: &str,
+    out_dir: &Path,
+    template_dir: &str,
+    variant: &VideoVariant,
+) -> Result<String> {
+    let lines: Vec<&str> = manifest.lines().collect();
+    let template = format!("      <Representation id=\"{}\" ", xml_escape(template_dir));
+    let position = lines
+        .iter()
+        .position(|line| line.starts_with(&template))
+        .with_context(|| format!("{} is not in the DASH manifest", template_dir))?;
+    let set_start = lines[..position]
+        .iter()
+        .rposition(|line| line.starts_with("    <AdaptationSet "))
+        .context("representation outside an adaptation set")?;
+    let set_end = position
+        + lines[position..]
+            .iter()
+            .position(|line| *line == "    </AdaptationSet>")
+            .context("unterminated adaptation set")?;
+
+    let representation = video_representation(out_dir, variant).await?;
+    let mut out = String::with_capacity(manifest.len() + representation.len());
+    for (idx, line) in lines.iter().enumerate() {
+        if idx == set_end {
+            out.push_str(&representation);
+        }
+        if idx == set_start {
+            let line = grow_attribute(line, "maxWidth", variant.width);
+            out.push_str(&grow_attribute(&line, "maxHeight", variant.height));
+        } else {
+            out.push_str(line);
+        }
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+/// Raise a numeric XML attribute on `line` to at least `value`
+fn grow_attribute(line: &str, name: &str, value: u32) -> String {
+    let needle = format!(" {}=\"", name);
+    let Some(start) = line.find(&needle).map(|i| i + needle.len()) else {
+        return line.to_string();
+    };
+    let Some(len) = line[start..].find('"') else {
+        return line.to_string();
+    };
+    match line[start..start + len].parse::<u32>() {
+        Ok(current) if current < value => {
+            format!("{}{}{}", &line[..start], value, &line[start + len..])
+        }
+        _ => line.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    const PLAYLIST: &str = "#EXTM3U\n#EXT-X-VERSION:7\n#EXT-X-TARGETDURATION:4\n#EXT-X-MAP:URI=\"init.mp4\"\n#EXTINF:4.000000,\nsegment_000.m4s\n#EXTINF:4.000000,\nsegment_001.m4s\n#EXTINF:2.500000,\nsegment_002.m4s\n#EXT-X-ENDLIST\n";
+
+    /// CMAF H.264 variants with their media playlists written into a fresh directory
+    async fn ladder(labels: &[(&str, u32, u32)]) -> (std::path::PathBuf, Vec<VideoVariant>) {
+        let out_dir = std::env::temp_dir().join(format!("akane-dash-test-{}", Uuid::new_v4()));
+        let mut variants = Vec::new();
+        for (label, width, height) in labels {
+            let variant = VideoVariant::new(label, *width, *height, 30.0).with_cmaf(true);
+            fs::create_dir_all(out_dir.join(variant.dir()))
+                .await
+                .unwrap();
+            fs::write(out_dir.join(variant.dir()).join("index.m3u8"), PLAYLIST)
+                .await
+                .unwrap();
+            variants.push(variant);
+        }
+        (out_dir, variants)
+    }
+
+    #[tokio::test]
+    async fn test_remove_and_add_dash_representation() {
+        let (out_dir, variants) = ladder(&[("720p", 1280, 720), ("1080p", 1920, 1080)]).await;
+        write_dash_manifest(&out_dir, &variants, &[], &[], 11, None)
+            .await
+            .unwrap();
+        let manifest = fs::read_to_string(out_dir.join("manifest.mpd"))
+            .await
+            .unwrap();
+
+        let removed = remove_dash_representations(&manifest, &["1080p".to_string()]);
+        assert!(!removed.contains("<Representation id=\"1080p\""));
+        assert!(removed.contains("<Representation id=\"720p\""));
+        assert_eq!(
+            removed.matches("<Representation ").count(),
+            removed.matches("</Representation>").count()
+        );
+
+        // Re-adding the tier restores the original manifest
+        let added = add_dash_representation(&removed, &out_dir, "720p", &variants[1])
+            .await
+            .unwrap();
+        let _ = fs::remove_dir_all(&out_dir).await;
+        assert_eq!(added, manifest);
+    }
+
+    #[tokio::test]
+    async fn test_add_dash_representation_grows_the_adaptation_set() {
+        let (out_dir, variants) = ladder(&[("720p", 1280, 720), ("1080p", 1920, 1080)]).await;
+        write_dash_manifest(&out_dir, &variants[..1], &[], &[], 11, None)
+            .await
+            .unwrap();
+        let manifest = fs::read_to_string(out_dir.join("manifest.mpd"))
+            .await
+            .unwrap();
+        assert!(manifest.contains("maxWidth=\"1280\" maxHeight=\"720\""));
+
+        let added = add_dash_representation(&manifest, &out_dir, "720p", &variants[1])
+            .await
+            .unwrap();
+        let missing = add_dash_representation(&manifest, &out_dir, "480p", &variants[1]).await;
+        let _ = fs::remove_dir_all(&out_dir).await;
+
+        assert!(added.contains("maxWidth=\"1920\" maxHeight=\"1080\""));
+        let representation = added.find("<Representation id=\"1080p\"").unwrap();
+        let set_end = added.find("    </AdaptationSet>").unwrap();
+        assert!(representation < set_end);
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn test_grow_attribute() {
+        let line = "    <AdaptationSet id=\"0\" maxWidth=\"1280\" maxHeight=\"720\">";
+        assert_eq!(
+            grow_attribute(line, "maxWidth", 1920),
+            "    <AdaptationSet id=\"0\" maxWidth=\"1920\" maxHeight=\"720\">"
+        );
+        // Never shrinks, and leaves lines without the attribute alone
+        assert_eq!(grow_attribute(line, "maxHeight", 480), line);
+        assert_eq!(grow_attribute(line, "maxFrameRate", 60), line);
+        // Matches the whole attribute name only
+        assert_eq!(grow_attribute(line, "Width", 1920), line);
+    }
+}
@@ -1,6 +1,9 @@
-use crate::types::{Attachment, AudioTrack, Chapter, SubtitleTrack, VideoDto, VideoQuery};
+use crate::types::{
+    Attachment, AudioFingerprint, AudioTrack, Chapter, Marker, MarkerKind, SubtitleTrack, VideoDto,
+    VideoKey, VideoQuery, VideoRendition, VideoVariant,
+};
 use anyhow::{Context, Result};
-use sqlx::{Sqlite, SqlitePool, migrate::MigrateDatabase};
+use sqlx::{Sqlite, SqliteConnection, SqliteExecutor, SqlitePool, migrate::MigrateDatabase};
 use std::collections::HashMap;
 use tracing::info;
 
@@ -27,23 +30,27 @@ pub async fn initialize_database(database_url: &str) -> Result<SqlitePool> {
     Ok(db_pool)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn save_video(
-    db_pool: &SqlitePool,
+    db: impl SqliteExecutor<'_>,
     video_id: &str,
     video_name: &str,
     tags: &[String],
     available_resolutions: &[String],
     duration: u32,
     thumbnail_key: &str,
-    sprites_key: &str,
+    thumbnails_vtt_key: Option<&str>,
+    preview_keys: Option<(&str, &str)>,
     entrypoint: &str,
+    source_key: Option<&str>,
+    profile: Option<&str>,
 ) -> Result<()> {
     let tags_json = serde_json::to_string(tags)?;
     let resolutions_json = serde_json::to_string(available_resolutions)?;
 
     sqlx
          ::query(
-             "INSERT INTO videos (id, name, tags, available_resolutions, duration, thumbnail_key, sprites_key, entrypoint) VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
+             "INSERT INTO videos (id, name, tags, available_resolutions, duration, thumbnail_key, thumbnails_vtt_key, preview_mp4_key, preview_webp_key, entrypoint, source_key, profile) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
          )
          .bind(video_id)
          .bind(video_name)
@@ -51,9 +58,13 @@ pub async fn save_video(
          .bind(&resolutions_json)
          .bind(duration as i64)
          .bind(thumbnail_key)
-         .bind(sprites_key)
+         .bind(thumbnails_vtt_key)
+         .bind(preview_keys.map(|(mp4, _)| mp4))
+         .bind(preview_keys.map(|(_, webp)| webp))
          .bind(entrypoint)
-         .execute(db_pool).await?;
+         .bind(source_key)
+         .bind(profile)
+         .execute(db).await?;
 
     info!(
         "Video saved to database: id={}, name={}",
@@ -63,6 +74,137 @@ pub async fn save_video(
     Ok(())
 }
 
+/// Point an existing video at a freshly encoded revision and drop the metadata of
+/// the previous one; run inside the transaction that saves the new metadata.
+/// Manual markers and the archived source are kept.
+#[allow(clippy::too_many_arguments)]
+pub async fn replace_video_content(
+    conn: &mut SqliteConnection,
+    video_id: &str,
+    revision: i64,
+    available_resolutions: &[String],
+    duration: u32,
+    thumbnail_key: &str,
+    thumbnails_vtt_key: Option<&str>,
+    preview_keys: Option<(&str, &str)>,
+    entrypoint: &str,
+    profile: Option<&str>,
+) -> Result<()> {
+    let resolutions_json = serde_json::to_string(available_resolutions)?;
+
+    let rows_affected = sqlx::query(
+        "UPDATE videos SET revision = ?, available_resolutions = ?, duration = ?, thumbnail_key = ?, \
+         thumbnails_vtt_key = ?, preview_mp4_key = ?, preview_webp_key = ?, entrypoint = ?, profile = ? WHERE id = ?",
+    )
+    .bind(revision)
+    .bind(&resolutions_json)
+    .bind(duration as i64)
+    .bind(thumbnail_key)
+    .bind(thumbnails_vtt_key)
+    .bind(preview_keys.map(|(mp4, _)| mp4))
+    .bind(preview_keys.map(|(_, webp)| webp))
+    .bind(entrypoint)
+    .bind(profile)
+    .bind(video_id)
+    .execute(&mut *conn)
+    .await?
+    .rows_affected();
+
+    if rows_affected == 0 {
+        anyhow::bail!("Video not found");
+    }
+
+    for table in [
+        "video_renditions",
+        "audio_tracks",
+        "subtitles",
+        "attachments",
+        "chapters",
+        "video_keys",
+    ] {
+        sqlx::query(&format!("DELETE FROM {} WHERE video_id = ?", table))
+            .bind(video_id)
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    info!(
+        "Video content replaced: id={}, revision={}",
+        video_id, revision
+    );
+
+    Ok(())
+}
+
+/// What reprocessing and rendition edits need to know about an existing video
+pub struct StoredVideo {
+    pub name: String,
+    pub tags: Vec<String>,
+    pub available_resolutions: Vec<String>,
+    pub revision: i64,
+    pub thumbnail_key: String,
+    pub source_key: Option<String>,
+    pub profile: Option<String>,
+}
+
+#[derive(sqlx::FromRow)]
+struct StoredVideoRow {
+    name: String,
+    tags: String,
+    available_resolutions: String,
+    revision: i64,
+    thumbnail_key: String,
+    source_key: Option<String>,
+    profile: Option<String>,
+}
+
+pub async fn get_stored_video(db_pool: &SqlitePool, video_id: &str) -> Result<Option<StoredVideo>> {
+    let row: Option<StoredVideoRow> = sqlx::query_as(
+        "SELECT name, tags, available_resolutions, revision, thumbnail_key, source_key, profile FROM videos WHERE id = ?",
+    )
+    .bind(video_id)
+    .fetch_optional(db_pool)
+    .await?;
+
+    row.map(|row| {
+        Ok(StoredVideo {
+            name: row.name,
+            tags: serde_json::from_str(&row.tags)
+                .context("Failed to parse tags JSON from database")?,
+            available_resolutions: serde_json::from_str(&row.available_resolutions)
+                .context("Failed to parse resolutions JSON from database")?,
+            revision: row.revision,
+            thumbnail_key: row.thumbnail_key,
+            source_key: row.source_key,
+            profile: row.profile,
+        })
+    })
+    .transpose()
+}
+
+pub async fn update_available_resolutions(
+    db: impl SqliteExecutor<'_>,
+    video_id: &str,
+    available_resolutions: &[String],
+) -> Result<()> {
+    let resolutions_json = serde_json::to_string(available_resolutions)?;
+    sqlx::query("UPDATE videos SET available_resolutions = ? WHERE id = ?")
+        .bind(&resolutions_json)
+        .bind(video_id)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Storage revision a video is served from, None for unknown videos
+pub async fn get_video_revision(db_pool: &SqlitePool, video_id: &str) -> Result<Option<i64>> {
+    let revision = sqlx::query_scalar::<_, i64>("SELECT revision FROM videos WHERE id = ?")
+        .bind(video_id)
+        .fetch_optional(db_pool)
+        .await?;
+    Ok(revision)
+}
+
 #[allow(dead_code)]
 #[derive(sqlx::FromRow)]
 struct VideoRow {
@@ -72,7 +214,9 @@ struct VideoRow {
     available_resolutions: String,
     duration: i64,
     thumbnail_key: String,
-    sprites_key: Option<String>,
+    thumbnails_vtt_key: Option<String>,
+    preview_mp4_key: Option<String>,
+    preview_webp_key: Option<String>,
     entrypoint: String,
     created_at: String,
 }
@@ -143,7 +287,7 @@ pub async fn list_videos(
     let rows: Vec<VideoRow> = match (name.as_ref(), tag) {
          (None, None) => {
              sqlx::query_as::<_, VideoRow>(
-                 "SELECT id, name, tags, available_resolutions, duration, thumbnail_key, sprites_key, entrypoint, created_at \
+                 "SELECT id, name, tags, available_resolutions, duration, thumbnail_key, thumbnails_vtt_key, preview_mp4_key, preview_webp_key, entrypoint, created_at \
                   FROM videos \
                   ORDER BY datetime(created_at) DESC \
                   LIMIT ? OFFSET ?",
@@ -157,7 +301,7 @@ pub async fn list_videos(
              let safe_name = name.replace("\"", "");
              let pattern = format!("name:\"{}\"*", safe_name);
              sqlx::query_as::<_, VideoRow>(
-                 "SELECT v.id, v.name, v.tags, v.available_resolutions, v.duration, v.thumbnail_key, v.sprites_key, v.entrypoint, v.created_at \
+                 "SELECT v.id, v.name, v.tags, v.available_resolutions, v.duration, v.thumbnail_key, v.thumbnails_vtt_key, v.preview_mp4_key, v.preview_webp_key, v.entrypoint, v.created_at \
                   FROM videos v \
                   JOIN videos_fts f ON v.id = f.id \
                   WHERE f.videos_fts MATCH ? \
@@ -174,7 +318,7 @@ pub async fn list_videos(
              let safe_tag = tag.replace("\"", "");
              let pattern = format!("tags:\"{}\"", safe_tag);
              sqlx::query_as::<_, VideoRow>(
-                 "SELECT v.id, v.name, v.tags, v.available_resolutions, v.duration, v.thumbnail_key, v.sprites_key, v.entrypoint, v.created_at \
+                 "SELECT v.id, v.name, v.tags, v.available_resolutions, v.duration, v.thumbnail_key, v.thumbnails_vtt_key, v.preview_mp4_key, v.preview_webp_key, v.entrypoint, v.created_at \
                   FROM videos v \
                   JOIN videos_fts f ON v.id = f.id \
                   WHERE f.videos_fts MATCH ? \
@@ -192,7 +336,7 @@ pub async fn list_videos(
              let safe_tag = tag.replace("\"", "");
              let pattern = format!("name:\"{}\"* AND tags:\"{}\"", safe_name, safe_tag);
              sqlx::query_as::<_, VideoRow>(
-                 "SELECT v.id, v.name, v.tags, v.available_resolutions, v.duration, v.thumbnail_key, v.sprites_key, v.entrypoint, v.created_at \
+                 "SELECT v.id, v.name, v.tags, v.available_resolutions, v.duration, v.thumbnail_key, v.thumbnails_vtt_key, v.preview_mp4_key, v.preview_webp_key, v.entrypoint, v.created_at \
                   FROM videos v \
                   JOIN videos_fts f ON v.id = f.id \
                   WHERE f.videos_fts MATCH ? \
@@ -216,12 +360,18 @@ pub async fn list_videos(
 
         let base = public_base_url.trim_end_matches('/');
         let thumbnail_url = format!("{}/{}", base, row.thumbnail_key);
-        let sprites_key = row
-            .sprites_key
+        let thumbnails_vtt_url = row
+            .thumbnails_vtt_key
             .as_deref()
-            .map(|key| key.to_string())
-            .unwrap_or_else(|| row.thumbnail_key.clone());
-        let sprites_url = format!("{}/{}", base, sprites_key);
+            .map(|key| format!("{}/{}", base, key));
+        let preview_mp4_url = row
+            .preview_mp4_key
+            .as_deref()
+            .map(|key| format!("{}/{}", base, key));
+        let preview_webp_url = row
+            .preview_webp_key
+            .as_deref()
+            .map(|key| format!("{}/{}", base, key));
         // Return player URL instead of direct HLS URL
         let player_url = format!("/player/{}", row.id);
 
@@ -234,7 +384,9 @@ pub async fn list_videos(
             available_resolutions: resolutions,
             duration: row.duration as u32,
             thumbnail_url,
-            sprites_url: Some(sprites_url),
+            thumbnails_vtt_url,
+            preview_mp4_url,
+            preview_webp_url,
             player_url,
             view_count,
             created_at: row.created_at,
@@ -252,6 +404,8 @@ pub struct VideoSummary {
     pub view_count: i64,
     pub created_at: String,
     pub thumbnail_key: String,
+    pub preview_mp4_key: Option<String>,
+    pub preview_webp_key: Option<String>,
 }
 
 pub async fn update_video(
@@ -279,6 +433,39 @@ pub async fn update_video(
     Ok(())
 }
 
+pub async fn get_thumbnail_key(db_pool: &SqlitePool, video_id: &str) -> Result<Option<String>> {
+    let key = sqlx::query_scalar::<_, String>("SELECT thumbnail_key FROM videos WHERE id = ?")
+        .bind(video_id)
+        .fetch_optional(db_pool)
+        .await?;
+
+    Ok(key)
+}
+
+pub async fn update_thumbnail_key(
+    db_pool: &SqlitePool,
+    video_id: &str,
+    thumbnail_key: &str,
+) -> Result<()> {
+    let rows_affected = sqlx::query("UPDATE videos SET thumbnail_key = ? WHERE id = ?")
+        .bind(thumbnail_key)
+        .bind(video_id)
+        .execute(db_pool)
+        .await?
+        .rows_affected();
+
+    if rows_affected == 0 {
+        anyhow::bail!("Video not found");
+    }
+
+    info!(
+        "Thumbnail updated in database: id={}, key={}",
+        video_id, thumbnail_key
+    );
+
+    Ok(())
+}
+
 pub async fn delete_videos(db_pool: &SqlitePool, video_ids: &[String]) -> Result<u64> {
     if video_ids.is_empty() {
         return Ok(0);
@@ -335,14 +522,14 @@ pub async fn get_all_videos_summary(
 ) -> Result<Vec<VideoSummary>> {
     let query = if let Some(l) = limit {
         format!(
-            "SELECT id, name, created_at, thumbnail_key \
+            "SELECT id, name, created_at, thumbnail_key, preview_mp4_key, preview_webp_key \
          FROM videos \
          ORDER BY datetime(created_at) DESC \
          LIMIT {}",
             l
         )
     } else {
-        "SELECT id, name, created_at, thumbnail_key \
+        "SELECT id, name, created_at, thumbnail_key, preview_mp4_key, preview_webp_key \
          FROM videos \
          ORDER BY datetime(created_at) DESC"
             .to_string()
@@ -379,10 +566,12 @@ struct SubtitleRow {
     storage_key: String,
     is_default: i32,
     is_forced: i32,
+    source_track_index: Option<i32>,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn save_subtitle(
-    db_pool: &SqlitePool,
+    db: impl SqliteExecutor<'_>,
     video_id: &str,
     track_index: i32,
     language: Option<&str>,
@@ -392,10 +581,11 @@ pub async fn save_subtitle(
     idx_storage_key: Option<&str>,
     is_default: bool,
     is_forced: bool,
+    source_track_index: Option<i32>,
 ) -> Result<i64> {
     let result = sqlx::query(
-        "INSERT INTO subtitles (video_id, track_index, language, title, codec, storage_key, idx_storage_key, is_default, is_forced) 
-         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)"
+        "INSERT INTO subtitles (video_id, track_index, language, title, codec, storage_key, idx_storage_key, is_default, is_forced, source_track_index) 
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
     )
     .bind(video_id)
     .bind(track_index)
@@ -406,7 +596,8 @@ pub async fn save_subtitle(
     .bind(idx_storage_key)
     .bind(is_default as i32)
     .bind(is_forced as i32)
-    .execute(db_pool)
+    .bind(source_track_index)
+    .execute(db)
     .await?;
 
     info!(
@@ -422,7 +613,7 @@ pub async fn get_subtitles_for_video(
     video_id: &str,
 ) -> Result<Vec<SubtitleTrack>> {
     let rows: Vec<SubtitleRow> = sqlx::query_as(
-        "SELECT id, video_id, track_index, language, title, codec, storage_key, is_default, is_forced 
+        "SELECT id, video_id, track_index, language, title, codec, storage_key, is_default, is_forced, source_track_index 
          FROM subtitles WHERE video_id = ? ORDER BY track_index"
     )
     .bind(video_id)
@@ -442,6 +633,7 @@ pub async fn get_subtitles_for_video(
             idx_storage_key: None, // Not stored in DB yet
             is_default: r.is_default != 0,
             is_forced: r.is_forced != 0,
+            source_track_index: r.source_track_index,
         })
         .collect())
 }
@@ -452,7 +644,7 @@ pub async fn get_subtitle_by_track(
     track_index: i32,
 ) -> Result<Option<SubtitleTrack>> {
     let row: Option<SubtitleRow> = sqlx::query_as(
-        "SELECT id, video_id, track_index, language, title, codec, storage_key, is_default, is_forced 
+        "SELECT id, video_id, track_index, language, title, codec, storage_key, is_default, is_forced, source_track_index 
          FROM subtitles WHERE video_id = ? AND track_index = ?"
     )
     .bind(video_id)
@@ -471,6 +663,7 @@ pub async fn get_subtitle_by_track(
         idx_storage_key: None, // Not stored in DB yet
         is_default: r.is_default != 0,
         is_forced: r.is_forced != 0,
+        source_track_index: r.source_track_index,
     }))
 }
 
@@ -486,7 +679,7 @@ struct AttachmentRow {
 }
 
 pub async fn save_attachment(
-    db_pool: &SqlitePool,
+    db: impl SqliteExecutor<'_>,
     video_id: &str,
     filename: &str,
     mimetype: &str,
@@ -499,7 +692,7 @@ pub async fn save_attachment(
     .bind(filename)
     .bind(mimetype)
     .bind(storage_key)
-    .execute(db_pool)
+    .execute(db)
     .await?;
 
     info!(
@@ -569,11 +762,12 @@ struct AudioTrackRow {
     sample_rate: Option<i32>,
     bit_rate: Option<i64>,
     is_default: i32,
+    integrated_loudness: Option<f64>,
 }
 
-#[allow(dead_code)]
+#[allow(clippy::too_many_arguments)]
 pub async fn save_audio_track(
-    db_pool: &SqlitePool,
+    db: impl SqliteExecutor<'_>,
     video_id: &str,
     track_index: i32,
     language: Option<&str>,
@@ -583,10 +777,11 @@ pub async fn save_audio_track(
     sample_rate: Option<i32>,
     bit_rate: Option<i64>,
     is_default: bool,
+    integrated_loudness: Option<f64>,
 ) -> Result<i64> {
     let result = sqlx::query(
-        "INSERT INTO audio_tracks (video_id, track_index, language, title, codec, channels, sample_rate, bit_rate, is_default) 
-         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)"
+        "INSERT INTO audio_tracks (video_id, track_index, language, title, codec, channels, sample_rate, bit_rate, is_default, integrated_loudness) 
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
     )
     .bind(video_id)
     .bind(track_index)
@@ -597,7 +792,8 @@ pub async fn save_audio_track(
     .bind(sample_rate)
     .bind(bit_rate)
     .bind(is_default as i32)
-    .execute(db_pool)
+    .bind(integrated_loudness)
+    .execute(db)
     .await?;
 
     info!(
@@ -613,7 +809,7 @@ pub async fn get_audio_tracks_for_video(
     video_id: &str,
 ) -> Result<Vec<AudioTrack>> {
     let rows: Vec<AudioTrackRow> = sqlx::query_as(
-        "SELECT id, video_id, track_index, language, title, codec, channels, sample_rate, bit_rate, is_default 
+        "SELECT id, video_id, track_index, language, title, codec, channels, sample_rate, bit_rate, is_default, integrated_loudness 
          FROM audio_tracks WHERE video_id = ? ORDER BY track_index ASC"
     )
     .bind(video_id)
@@ -633,6 +829,7 @@ pub async fn get_audio_tracks_for_video(
             sample_rate: r.sample_rate,
             bit_rate: r.bit_rate,
             is_default: r.is_default != 0,
+            integrated_loudness: r.integrated_loudness,
         })
         .collect())
 }
@@ -647,25 +844,28 @@ struct ChapterRow {
     start_time: f64,
     end_time: f64,
     title: String,
+    is_auto: bool,
 }
 
 pub async fn save_chapter(
-    db_pool: &SqlitePool,
+    db: impl SqliteExecutor<'_>,
     video_id: &str,
     chapter_index: i32,
     start_time: f64,
     end_time: f64,
     title: &str,
+    is_auto: bool,
 ) -> Result<i64> {
     let result = sqlx::query(
-        "INSERT INTO chapters (video_id, chapter_index, start_time, end_time, title) VALUES (?, ?, ?, ?, ?)",
+        "INSERT INTO chapters (video_id, chapter_index, start_time, end_time, title, is_auto) VALUES (?, ?, ?, ?, ?, ?)",
     )
     .bind(video_id)
     .bind(chapter_index)
     .bind(start_time)
     .bind(end_time)
     .bind(title)
-    .execute(db_pool)
+    .bind(is_auto)
+    .execute(db)
     .await?;
 
     info!(
@@ -678,7 +878,7 @@ pub async fn save_chapter(
 
 pub async fn get_chapters_for_video(db_pool: &SqlitePool, video_id: &str) -> Result<Vec<Chapter>> {
     let rows: Vec<ChapterRow> = sqlx::query_as(
-        "SELECT id, video_id, chapter_index, start_time, end_time, title 
+        "SELECT id, video_id, chapter_index, start_time, end_time, title, is_auto 
          FROM chapters WHERE video_id = ? ORDER BY chapter_index",
     )
     .bind(video_id)
@@ -694,6 +894,279 @@ pub async fn get_chapters_for_video(db_pool: &SqlitePool, video_id: &str) -> Res
             start_time: r.start_time,
             end_time: r.end_time,
             title: r.title,
+            is_auto: r.is_auto,
+        })
+        .collect())
+}
+
+// Rendition CRUD operations
+
+#[derive(sqlx::FromRow)]
+struct RenditionRow {
+    id: i64,
+    video_id: String,
+    codec: String,
+    label: String,
+    width: i64,
+    height: i64,
+    fps: f64,
+    bitrate: i64,
+    model_bitrate: i64,
+    complexity: Option<f64>,
+}
+
+pub async fn save_rendition(
+    db: impl SqliteExecutor<'_>,
+    video_id: &str,
+    variant: &VideoVariant,
+    complexity: Option<f64>,
+) -> Result<i64> {
+    let result = sqlx::query(
+        "INSERT OR REPLACE INTO video_renditions (video_id, codec, label, width, height, fps, bitrate, model_bitrate, complexity) 
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(video_id)
+    .bind(variant.codec_id())
+    .bind(&variant.label)
+    .bind(variant.width as i64)
+    .bind(variant.height as i64)
+    .bind(variant.fps)
+    .bind(variant.bitrate as i64)
+    .bind(variant.model_bitrate() as i64)
+    .bind(complexity)
+    .execute(db)
+    .await?;
+
+    info!(
+        "Rendition saved to database: video_id={}, codec={}, label={}, bitrate={}k",
+        video_id,
+        variant.codec_id(),
+        variant.label,
+        variant.bitrate
+    );
+
+    Ok(result.last_insert_rowid())
+}
+
+/// Forget every rendition of a resolution tier, returning how many were recorded
+pub async fn delete_renditions(
+    db: impl SqliteExecutor<'_>,
+    video_id: &str,
+    label: &str,
+) -> Result<u64> {
+    let result = sqlx::query("DELETE FROM video_renditions WHERE video_id = ? AND label = ?")
+        .bind(video_id)
+        .bind(label)
+        .execute(db)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+pub async fn get_renditions_for_video(
+    db_pool: &SqlitePool,
+    video_id: &str,
+) -> Result<Vec<VideoRendition>> {
+    let rows: Vec<RenditionRow> = sqlx::query_as(
+        "SELECT id, video_id, codec, label, width, height, fps, bitrate, model_bitrate, complexity 
+         FROM video_renditions WHERE video_id = ? ORDER BY codec, height ASC",
+    )
+    .bind(video_id)
+    .fetch_all(db_pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| VideoRendition {
+            id: r.id,
+            video_id: r.video_id,
+            codec: r.codec,
+            label: r.label,
+            width: r.width as u32,
+            height: r.height as u32,
+            fps: r.fps,
+            bitrate: r.bitrate as u32,
+            model_bitrate: r.model_bitrate as u32,
+            complexity: r.complexity,
         })
         .collect())
 }
+
+// Intro/credits markers and the fingerprints they are detected from
+
+pub async fn save_fingerprint(
+    db_pool: &SqlitePool,
+    video_id: &str,
+    region: MarkerKind,
+    start_time: f64,
+    items: &[u32],
+) -> Result<()> {
+    let data: Vec<u8> = items.iter().flat_map(|item| item.to_le_bytes()).collect();
+    sqlx::query(
+        "INSERT OR REPLACE INTO audio_fingerprints (video_id, region, start_time, data) VALUES (?, ?, ?, ?)",
+    )
+    .bind(video_id)
+    .bind(region.as_str())
+    .bind(start_time)
+    .bind(data)
+    .execute(db_pool)
+    .await?;
+
+    info!(
+        "Fingerprint saved to database: video_id={}, region={}, items={}",
+        video_id,
+        region.as_str(),
+        items.len()
+    );
+
+    Ok(())
+}
+
+/// Fingerprints of one region for every video carrying `tag`, oldest upload first
+pub async fn get_series_fingerprints(
+    db_pool: &SqlitePool,
+    tag: &str,
+    region: MarkerKind,
+) -> Result<Vec<AudioFingerprint>> {
+    let rows: Vec<(String, f64, Vec<u8>)> = sqlx::query_as(
+        "SELECT f.video_id, f.start_time, f.data \
+         FROM audio_fingerprints f \
+         JOIN videos v ON v.id = f.video_id \
+         WHERE f.region = ? AND EXISTS (SELECT 1 FROM json_each(v.tags) WHERE json_each.value = ?) \
+         ORDER BY datetime(v.created_at), v.id",
+    )
+    .bind(region.as_str())
+    .bind(tag)
+    .fetch_all(db_pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(video_id, start_time, data)| AudioFingerprint {
+            video_id,
+            start_time,
+            items: data
+                .chunks_exact(4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+        })
+        .collect())
+}
+
+/// Store a detected marker, or drop the stale one when `range` is None.
+/// Markers set by an admin are never touched.
+pub async fn save_auto_marker(
+    db_pool: &SqlitePool,
+    video_id: &str,
+    kind: MarkerKind,
+    range: Option<(f64, f64)>,
+) -> Result<()> {
+    match range {
+        Some((start_time, end_time)) => {
+            sqlx::query(
+                "INSERT INTO markers (video_id, kind, start_time, end_time, is_auto) VALUES (?, ?, ?, ?, 1) \
+                 ON CONFLICT(video_id, kind) DO UPDATE SET start_time = excluded.start_time, end_time = excluded.end_time \
+                 WHERE markers.is_auto = 1",
+            )
+            .bind(video_id)
+            .bind(kind.as_str())
+            .bind(start_time)
+            .bind(end_time)
+            .execute(db_pool)
+            .await?;
+        }
+        None => {
+            sqlx::query("DELETE FROM markers WHERE video_id = ? AND kind = ? AND is_auto = 1")
+                .bind(video_id)
+                .bind(kind.as_str())
+                .execute(db_pool)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Replace every marker of a video with manual ones
+pub async fn set_markers(db_pool: &SqlitePool, video_id: &str, markers: &[Marker]) -> Result<()> {
+    let mut tx = db_pool.begin().await?;
+    sqlx::query("DELETE FROM markers WHERE video_id = ?")
+        .bind(video_id)
+        .execute(&mut *tx)
+        .await?;
+    for marker in markers {
+        sqlx::query(
+            "INSERT INTO markers (video_id, kind, start_time, end_time, is_auto) VALUES (?, ?, ?, ?, 0)",
+        )
+        .bind(video_id)
+        .bind(marker.kind.as_str())
+        .bind(marker.start_time)
+        .bind(marker.end_time)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+
+    info!(
+        "Markers replaced: video_id={}, count={}",
+        video_id,
+        markers.len()
+    );
+
+    Ok(())
+}
+
+pub async fn get_markers_for_video(db_pool: &SqlitePool, video_id: &str) -> Result<Vec<Marker>> {
+    let rows: Vec<(String, f64, f64, bool)> = sqlx::query_as(
+        "SELECT kind, start_time, end_time, is_auto FROM markers WHERE video_id = ? ORDER BY start_time",
+    )
+    .bind(video_id)
+    .fetch_all(db_pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|(kind, start_time, end_time, is_auto)| {
+            Some(Marker {
+                kind: MarkerKind::from_db(&kind)?,
+                start_time,
+                end_time,
+                is_auto,
+            })
+        })
+        .collect())
+}
+
+// HLS encryption keys
+
+pub async fn save_video_key(
+    db: impl SqliteExecutor<'_>,
+    video_id: &str,
+    method: &str,
+    kid: Option<&[u8]>,
+    key: &[u8],
+) -> Result<()> {
+    sqlx::query(
+        "INSERT OR REPLACE INTO video_keys (video_id, method, kid, key) VALUES (?, ?, ?, ?)",
+    )
+    .bind(video_id)
+    .bind(method)
+    .bind(kid)
+    .bind(key)
+    .execute(db)
+    .await?;
+
+    info!(
+        "Encryption key saved to database: video_id={}, method={}",
+        video_id, method
+    );
+    Ok(())
+}
+
+pub async fn get_video_key(db_pool: &SqlitePool, video_id: &str) -> Result<Option<VideoKey>> {
+    let row: Option<(String, Option<Vec<u8>>, Vec<u8>)> =
+        sqlx::query_as("SELECT method, kid, key FROM video_keys WHERE video_id = ?")
+            .bind(video_id)
+            .fetch_optional(db_pool)
+            .await?;
+
+    Ok(row.map(|(method, kid, key)| VideoKey { method, kid, key }))
+}
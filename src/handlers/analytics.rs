@@ -114,6 +114,8 @@ pub struct AnalyticsVideoDto {
     pub view_count: i64,
     pub created_at: String,
     pub thumbnail_url: String,
+    pub preview_mp4_url: Option<String>,
+    pub preview_webp_url: Option<String>,
 }
 
 pub async fn get_analytics_videos(
@@ -144,6 +146,8 @@ pub async fn get_analytics_videos(
             view_count: v.view_count,
             created_at: v.created_at,
             thumbnail_url: format!("{}/{}", base, v.thumbnail_key),
+            preview_mp4_url: v.preview_mp4_key.map(|key| format!("{}/{}", base, key)),
+            preview_webp_url: v.preview_webp_key.map(|key| format!("{}/{}", base, key)),
         })
         .collect();
 
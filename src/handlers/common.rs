@@ -236,8 +236,7 @@ fn minify_pass_one(code: &str) -> String {
                 let curr_is_ident_start = is_ident_start(c);
 
                 // Space needed between identifiers
-                let needs_separator = (last_is_ident && curr_is_ident)
-                    || (last_is_ident && c == '/') // "return /regex/"
+                let needs_separator = (last_is_ident && (curr_is_ident || c == '/')) // "return /regex/"
                     || (last == '/' && curr_is_ident_start) // division followed by identifier
                     || (last == ')' && curr_is_ident_start) // ") function" or ") if"
                     || (last == ']' && curr_is_ident_start); // "] in" patterns
@@ -1035,18 +1034,18 @@ fn rename_variables(code: &str) -> String {
                     j += 1;
                 }
 
-                if valid && j < tokens.len() {
-                    if let JsToken::Operator(op) = &tokens[j] {
-                        if op == "=>" {
-                            for name in param_candidates {
-                                record_declared_name(
-                                    &name,
-                                    &mut used_names,
-                                    &mut declared_set,
-                                    &mut declared_order,
-                                );
-                            }
-                        }
+                if valid
+                    && j < tokens.len()
+                    && let JsToken::Operator(op) = &tokens[j]
+                    && op == "=>"
+                {
+                    for name in param_candidates {
+                        record_declared_name(
+                            &name,
+                            &mut used_names,
+                            &mut declared_set,
+                            &mut declared_order,
+                        );
                     }
                 }
             }
@@ -1,11 +1,12 @@
 use crate::database::{
     get_attachment_by_filename, get_attachments_for_video, get_audio_tracks_for_video,
-    get_chapters_for_video, get_subtitle_by_track, get_subtitles_for_video,
+    get_chapters_for_video, get_markers_for_video, get_subtitle_by_track, get_subtitles_for_video,
+    get_video_key,
 };
 use crate::handlers::common::{internal_err, verify_token};
 use crate::types::{
-    AppState, AttachmentListResponse, AudioTrackListResponse, ChapterListResponse,
-    SubtitleListResponse,
+    AppState, AttachmentListResponse, AudioTrackListResponse, ChapterListResponse, ClearKeyJwk,
+    ClearKeyLicense, ClearKeyLicenseRequest, MarkerListResponse, SubtitleListResponse,
 };
 
 use axum::{
@@ -15,6 +16,7 @@ use axum::{
     http::{HeaderMap, StatusCode, header},
     response::{IntoResponse, Response},
 };
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
 use futures::StreamExt;
 use std::net::SocketAddr;
 use tracing::error;
@@ -24,6 +26,64 @@ pub struct TokenQuery {
     pub token: Option<String>,
 }
 
+/// Check the playback token of a request for `video_id`, taken from the query or the
+/// `token` cookie and bound to the client IP and User-Agent. `resource` names what
+/// was asked for in the denial log.
+fn authorize_playback(
+    state: &AppState,
+    headers: &HeaderMap,
+    addr: SocketAddr,
+    video_id: &str,
+    token: Option<&str>,
+    resource: &str,
+) -> Result<(), (StatusCode, String)> {
+    let cookie_token = headers
+        .get(header::COOKIE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|cookies| {
+            cookies
+                .split(';')
+                .find_map(|cookie| cookie.trim().strip_prefix("token="))
+        });
+    let token = token
+        .filter(|t| !t.is_empty())
+        .or(cookie_token)
+        .unwrap_or("");
+
+    // Client IP from X-Forwarded-For, falling back to the peer address
+    let ip = headers
+        .get("x-forwarded-for")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|xff| xff.split(',').next().map(|s| s.trim().to_string()))
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| addr.ip().to_string());
+
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+
+    if !verify_token(
+        video_id,
+        token,
+        &state.config.server.secret_key,
+        &ip,
+        user_agent,
+    ) {
+        error!(
+            video_id = %video_id,
+            ip = %ip,
+            "{} denied: invalid or expired token",
+            resource
+        );
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Access denied: Invalid or expired token".to_string(),
+        ));
+    }
+    Ok(())
+}
+
 pub async fn get_video_subtitles(
     State(state): State<AppState>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
@@ -466,6 +526,29 @@ pub async fn get_video_chapters(
     Ok(Json(ChapterListResponse { chapters }))
 }
 
+pub async fn get_video_markers(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(video_id): Path<String>,
+    Query(query): Query<TokenQuery>,
+) -> Result<Json<MarkerListResponse>, (StatusCode, String)> {
+    authorize_playback(
+        &state,
+        &headers,
+        addr,
+        &video_id,
+        query.token.as_deref(),
+        "Marker list access",
+    )?;
+
+    let markers = get_markers_for_video(&state.db_pool, &video_id)
+        .await
+        .map_err(internal_err)?;
+
+    Ok(Json(MarkerListResponse { markers }))
+}
+
 pub async fn get_jassub_worker(
     Path(filename): Path<String>,
 ) -> Result<Response, (StatusCode, String)> {
@@ -549,3 +632,96 @@ pub async fn get_libbitsub_worker(
     )
         .into_response())
 }
+
+/// Release the AES-128 key of an encrypted video to a holder of a valid playback token
+pub async fn get_video_key_file(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(video_id): Path<String>,
+    Query(query): Query<TokenQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    authorize_playback(
+        &state,
+        &headers,
+        addr,
+        &video_id,
+        query.token.as_deref(),
+        "Key access",
+    )?;
+
+    // CENC keys are only released through the ClearKey license route
+    let key = get_video_key(&state.db_pool, &video_id)
+        .await
+        .map_err(internal_err)?
+        .filter(|k| k.method == "aes-128")
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Key not found".to_string()))?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/octet-stream"),
+            (header::CACHE_CONTROL, "private, no-store"),
+            (header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"),
+        ],
+        key.key,
+    )
+        .into_response())
+}
+
+/// ClearKey license server: answers an EME license request with the video's CENC key
+pub async fn get_clearkey_license(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(video_id): Path<String>,
+    Query(query): Query<TokenQuery>,
+    Json(request): Json<ClearKeyLicenseRequest>,
+) -> Result<Response, (StatusCode, String)> {
+    authorize_playback(
+        &state,
+        &headers,
+        addr,
+        &video_id,
+        query.token.as_deref(),
+        "License request",
+    )?;
+
+    let video_key = get_video_key(&state.db_pool, &video_id)
+        .await
+        .map_err(internal_err)?
+        .filter(|k| k.method == "cenc")
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Key not found".to_string()))?;
+    let kid = URL_SAFE_NO_PAD.encode(video_key.kid.unwrap_or_default());
+
+    // Only answer for the key ID this video was packaged with
+    let keys = request
+        .kids
+        .iter()
+        .filter(|requested| requested.trim_end_matches('=') == kid)
+        .map(|_| ClearKeyJwk {
+            kty: "oct",
+            kid: kid.clone(),
+            k: URL_SAFE_NO_PAD.encode(&video_key.key),
+        })
+        .take(1)
+        .collect::<Vec<_>>();
+    if keys.is_empty() {
+        return Err((StatusCode::NOT_FOUND, "Key not found".to_string()));
+    }
+
+    let license = ClearKeyLicense {
+        keys,
+        session_type: request
+            .session_type
+            .unwrap_or_else(|| "temporary".to_string()),
+    };
+
+    Ok((
+        [
+            (header::CACHE_CONTROL, "private, no-store"),
+            (header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"),
+        ],
+        Json(license),
+    )
+        .into_response())
+}
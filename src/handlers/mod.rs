@@ -4,6 +4,7 @@ pub mod content;
 pub mod player;
 pub mod upload;
 pub mod video;
+pub mod worker;
 
 // Re-export specific handlers if needed by main.rs
 pub use analytics::{
@@ -13,8 +14,9 @@ pub use analytics::{
 #[allow(unused)]
 pub use common::{internal_err, minify_js};
 pub use content::{
-    get_attachment_file, get_jassub_worker, get_libbitsub_worker, get_subtitle_file,
-    get_video_attachments, get_video_audio_tracks, get_video_chapters, get_video_subtitles,
+    get_attachment_file, get_clearkey_license, get_jassub_worker, get_libbitsub_worker,
+    get_subtitle_file, get_video_attachments, get_video_audio_tracks, get_video_chapters,
+    get_video_key_file, get_video_markers, get_video_subtitles,
 };
 pub use player::{get_hls_file, get_player};
 
@@ -22,6 +24,11 @@ pub use player::{get_hls_file, get_player};
 pub use upload::{
     CancelQueueResponse, CleanupResponse, ClearFailedResponse, RemoveQueueResponse, cancel_queue,
     cleanup_uploads, clear_all_failed, finalize_chunked_upload, get_progress, list_queues,
-    remove_failed_queue, upload_chunk, upload_video,
+    remove_failed_queue, reprocess_video, upload_chunk, upload_video,
 };
-pub use video::{delete_videos, list_videos, update_video};
+pub use video::{
+    add_video_rendition, delete_videos, detect_markers, get_thumbnail_candidates,
+    get_video_renditions, list_videos, remove_video_rendition, update_thumbnail, update_video,
+    update_video_markers,
+};
+pub use worker::{claim_job, complete_job, fail_job, get_job_source, report_job_progress};
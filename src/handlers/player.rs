@@ -1,11 +1,16 @@
-use crate::database::{get_attachments_for_video, get_chapters_for_video, get_subtitles_for_video};
+use crate::database::{
+    get_attachments_for_video, get_chapters_for_video, get_markers_for_video,
+    get_renditions_for_video, get_subtitles_for_video, get_thumbnail_key, get_video_key,
+    get_video_revision,
+};
 use crate::handlers::common::{generate_token, internal_err, minify_js, verify_token};
+use crate::storage::storage_prefix;
 use crate::types::AppState;
 
 use axum::{
     body::Body,
     extract::{ConnectInfo, Path, Query, State},
-    http::{HeaderMap, StatusCode, header},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
     response::{Html, IntoResponse, Response},
 };
 use futures::StreamExt;
@@ -47,6 +52,21 @@ pub async fn get_player(
     let chapters = get_chapters_for_video(&state.db_pool, &id)
         .await
         .unwrap_or_default();
+    let markers = get_markers_for_video(&state.db_pool, &id)
+        .await
+        .unwrap_or_default();
+
+    let has_clearkey = get_video_key(&state.db_pool, &id)
+        .await
+        .ok()
+        .flatten()
+        .is_some_and(|k| k.method == "cenc");
+
+    let has_burned = get_renditions_for_video(&state.db_pool, &id)
+        .await
+        .unwrap_or_default()
+        .iter()
+        .any(|r| r.codec.ends_with("-burned"));
 
     let has_subtitles = !subtitles.is_empty();
     let has_fonts = !attachments.is_empty();
@@ -118,6 +138,49 @@ pub async fn get_player(
         "const chapters = [];".to_string()
     };
 
+    // Intro/recap/credits ranges shown as skip buttons
+    let markers_js = format!(
+        "const skipMarkers = [{}];",
+        markers
+            .iter()
+            .filter(|m| m.end_time > m.start_time)
+            .map(|m| format!(
+                "{{ skipKind: '{}', skipFrom: {}, skipTo: {} }}",
+                m.kind.as_str(),
+                m.start_time,
+                m.end_time
+            ))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    // CENC videos get their ClearKey license from the token-gated license route
+    let drm_js = if has_clearkey {
+        format!(
+            "const drmServers = {{ 'org.w3.clearkey': '/api/videos/{}/license' }};",
+            id
+        )
+    } else {
+        "const drmServers = {};".to_string()
+    };
+
+    // Uploads with burned-in subtitles have a second master playlist for that ladder
+    let burned_js = if has_burned {
+        format!("const burnedPlaylist = '/hls/{}/burned.m3u8';", id)
+    } else {
+        "const burnedPlaylist = null;".to_string()
+    };
+
+    // The poster is wherever the video row points, a picked or uploaded one included
+    let thumbnail_js = match get_thumbnail_key(&state.db_pool, &id).await.ok().flatten() {
+        Some(key) => format!(
+            "const thumbnailUrl = '{}/{}';",
+            state.config.r2.public_base_url.trim_end_matches('/'),
+            key
+        ),
+        None => "const thumbnailUrl = null;".to_string(),
+    };
+
     let js_code = format!(
         r#"
         const videoId = '{video_id}';
@@ -125,13 +188,14 @@ pub async fn get_player(
         {subtitle_js}
         {fonts_js}
         {chapters_js}
-        const thumbnailUrl = '/hls/{video_id}/thumbnail.jpg';
-        const spriteUrl = '/hls/{video_id}/sprites.jpg';
-        const spriteColumns = 10;
-        const spriteRows = 10;
-        const spriteWidth = 160;
-        const spriteHeight = 90;
-        let spriteInterval = 10;
+        {markers_js}
+        {drm_js}
+        {burned_js}
+        const mainPlaylist = '/hls/{video_id}/index.m3u8';
+        let currentPlaylist = mainPlaylist;
+        {thumbnail_js}
+        const thumbnailsVttUrl = '/hls/{video_id}/thumbnails.vtt';
+        let thumbnailCues = [];
         
         let player = null;
         let video = null;
@@ -164,6 +228,39 @@ pub async fn get_player(
             return ['dvd_subtitle','dvdsub'].includes(codec?.toLowerCase());
         }}
 
+        function parseVttTime(value) {{
+            return value.trim().split(':').reduce((acc, part) => acc * 60 + parseFloat(part), 0);
+        }}
+
+        // Trickplay tiles: each cue of thumbnails.vtt points at a sheet region via #xywh=
+        async function loadThumbnails() {{
+            try {{
+                const res = await fetch(withToken(thumbnailsVttUrl));
+                if (!res.ok) return;
+                const base = new URL(thumbnailsVttUrl, window.location.origin);
+                const lines = (await res.text()).split('\n').map(l => l.trim());
+                const cues = [];
+                for (let i = 0; i + 1 < lines.length; i++) {{
+                    const timing = lines[i].split(' --> ');
+                    const hash = lines[i + 1].indexOf('#xywh=');
+                    if (timing.length !== 2 || hash < 0) continue;
+                    const box = lines[i + 1].slice(hash + 6).split(',').map(Number);
+                    cues.push({{
+                        cueStart: parseVttTime(timing[0]),
+                        cueEnd: parseVttTime(timing[1]),
+                        sheet: new URL(lines[i + 1].slice(0, hash), base).toString(),
+                        tileX: box[0],
+                        tileY: box[1],
+                        tileW: box[2],
+                        tileH: box[3]
+                    }});
+                }}
+                thumbnailCues = cues;
+            }} catch (e) {{
+                console.warn('Failed to load thumbnails:', e);
+            }}
+        }}
+
         // Format time as HH:MM:SS or MM:SS
         function formatTime(seconds) {{
             if (!isFinite(seconds)) return '0:00';
@@ -199,6 +296,8 @@ pub async fn get_player(
             const previewImage = document.getElementById('previewImage');
             const previewTime = document.getElementById('previewTime');
 
+            loadThumbnails();
+
             if (thumbnailUrl && video && container) {{
                 video.poster = thumbnailUrl;
                 container.style.backgroundImage = `url(${{thumbnailUrl}})`;
@@ -221,30 +320,21 @@ pub async fn get_player(
                 if (video) video.poster = '';
             }};
 
-            const updateSpriteInterval = () => {{
-                if (!video || !isFinite(video.duration) || video.duration <= 0) return;
-                const frames = spriteColumns * spriteRows;
-                spriteInterval = Math.max(video.duration / frames, 0.1);
-            }};
-
             const hidePreview = () => {{
                 if (preview) preview.classList.remove('show');
             }};
 
             const showPreview = (clientX) => {{
-                if (!spriteUrl || !preview || !previewImage || !previewTime || !progress || !video || !isFinite(video.duration)) return;
+                if (!thumbnailCues.length || !preview || !previewImage || !previewTime || !progress || !video || !isFinite(video.duration)) return;
                 const rect = progress.getBoundingClientRect();
                 const pct = Math.min(1, Math.max(0, (clientX - rect.left) / rect.width));
                 const time = pct * (video.duration || 0);
-                const frames = spriteColumns * spriteRows;
-                const interval = spriteInterval > 0 ? spriteInterval : Math.max((video.duration || 0) / frames, 0.1);
-                const idx = Math.min(frames - 1, Math.floor(time / interval));
-                const col = idx % spriteColumns;
-                const row = Math.floor(idx / spriteColumns);
-
-                previewImage.style.backgroundImage = 'url(' + spriteUrl + ')';
-                previewImage.style.backgroundSize = (spriteColumns * spriteWidth) + 'px ' + (spriteRows * spriteHeight) + 'px';
-                previewImage.style.backgroundPosition = '-' + (col * spriteWidth) + 'px -' + (row * spriteHeight) + 'px';
+                const cue = thumbnailCues.find(c => time >= c.cueStart && time < c.cueEnd) || thumbnailCues[thumbnailCues.length - 1];
+
+                previewImage.style.width = cue.tileW + 'px';
+                previewImage.style.height = cue.tileH + 'px';
+                previewImage.style.backgroundImage = 'url(' + cue.sheet + ')';
+                previewImage.style.backgroundPosition = '-' + cue.tileX + 'px -' + cue.tileY + 'px';
                 previewTime.textContent = formatTime(time);
 
                 const minX = 60;
@@ -298,6 +388,9 @@ pub async fn get_player(
                     bufferingGoal: 30,
                     rebufferingGoal: 2,
                     bufferBehind: 30
+                }},
+                drm: {{
+                    servers: drmServers
                 }}
             }});
 
@@ -311,7 +404,7 @@ pub async fn get_player(
 
             // Load HLS stream
             try {{
-                await player.load(withToken('/hls/{video_id}/index.m3u8'));
+                await player.load(withToken(mainPlaylist));
                 setLoading(false);
                 updateBufferedBar();
             }} catch (e) {{
@@ -334,10 +427,7 @@ pub async fn get_player(
                 }}
             }};
 
-            video.onloadedmetadata = () => {{
-                updateOrientation();
-                updateSpriteInterval();
-            }};
+            video.onloadedmetadata = updateOrientation;
             window.addEventListener('resize', updateOrientation);
             document.onfullscreenchange = () => {{
                 updateOrientation();
@@ -433,11 +523,11 @@ pub async fn get_player(
                     currentTimeEl.textContent = formatTime(video.currentTime);
                 }}
                 updateBufferedBar();
+                updateSkipButton();
             }};
             video.ondurationchange = () => {{
                 durationEl.textContent = formatTime(video.duration);
                 updateBufferedBar();
-                updateSpriteInterval();
             }};
             video.onprogress = updateBufferedBar;
             progress.onmousemove = (e) => showPreview(e.clientX);
@@ -516,6 +606,9 @@ pub async fn get_player(
                     speedMenu.classList.toggle('show');
                 }};
             }}
+            const skipBtn = document.getElementById('skipBtn');
+            if (skipBtn) skipBtn.onclick = skipActive;
+
             const pipBtn = document.getElementById('pipBtn');
             if (pipBtn) {{
                 pipBtn.onclick = () => togglePiP();
@@ -624,6 +717,16 @@ pub async fn get_player(
             }}
         }}
 
+        // Reload at the current position from another master playlist
+        async function switchPlaylist(url) {{
+            if (url === currentPlaylist) return;
+            const time = video.currentTime;
+            const paused = video.paused;
+            currentPlaylist = url;
+            await player.load(withToken(url), time);
+            if (!paused) video.play();
+        }}
+
         function buildQualityMenu() {{
             const menu = document.getElementById('qualityMenu');
             const tracks = player.getVariantTracks();
@@ -633,21 +736,36 @@ pub async fn get_player(
             heights.forEach(h => {{
                 menu.innerHTML += '<div class="menu-item" data-value="' + h + '">' + h + 'p</div>';
             }});
+            if (burnedPlaylist) {{
+                menu.innerHTML += '<div class="menu-item" data-value="burned">With burned subtitles</div>';
+            }}
 
             menu.querySelectorAll('.menu-item').forEach(item => {{
-                item.onclick = (e) => {{
+                item.onclick = async (e) => {{
                     e.stopPropagation();
+                    menu.querySelectorAll('.menu-item').forEach(i => i.classList.remove('active'));
+                    item.classList.add('active');
+                    menu.classList.remove('show');
+
+                    // Burned-in ladder replaces the soft subtitle renderer
+                    if (item.dataset.value === 'burned') {{
+                        destroySubtitleRenderer();
+                        currentSubtitle = null;
+                        document.querySelectorAll('#subtitleMenu .menu-item').forEach((i, idx) => i.classList.toggle('active', idx === 0));
+                        player.configure({{ abr: {{ enabled: true }} }});
+                        await switchPlaylist(burnedPlaylist);
+                        return;
+                    }}
+
+                    await switchPlaylist(mainPlaylist);
                     const val = parseInt(item.dataset.value);
                     if (val === -1) {{
                         player.configure({{ abr: {{ enabled: true }} }});
                     }} else {{
                         player.configure({{ abr: {{ enabled: false }} }});
-                        const track = tracks.find(t => t.height === val);
+                        const track = player.getVariantTracks().find(t => t.height === val);
                         if (track) player.selectVariantTrack(track, true);
                     }}
-                    menu.querySelectorAll('.menu-item').forEach(i => i.classList.remove('active'));
-                    item.classList.add('active');
-                    menu.classList.remove('show');
                 }};
             }});
             menu.querySelector('.menu-item').classList.add('active');
@@ -826,6 +944,30 @@ pub async fn get_player(
             }}
         }}
         
+        // Skip button while inside an intro, recap or credits range
+        const skipLabels = {{ intro: 'Skip intro', recap: 'Skip recap', credits: 'Skip credits' }};
+        let activeSkip = null;
+        function updateSkipButton() {{
+            const skipBtn = document.getElementById('skipBtn');
+            if (!skipBtn || skipMarkers.length === 0) return;
+            const now = video.currentTime;
+            const found = skipMarkers.find(m => now >= m.skipFrom && now < m.skipTo - 1) || null;
+            if (found === activeSkip) return;
+            activeSkip = found;
+            if (found) {{
+                skipBtn.textContent = skipLabels[found.skipKind] || 'Skip';
+                skipBtn.classList.add('show');
+            }} else {{
+                skipBtn.classList.remove('show');
+            }}
+        }}
+        function skipActive(e) {{
+            e.stopPropagation();
+            if (!activeSkip) return;
+            video.currentTime = Math.min(activeSkip.skipTo, video.duration || activeSkip.skipTo);
+            updateSkipButton();
+        }}
+
         // Chapter markers on progress bar
         function buildChapterMarkers() {{
             const progress = document.getElementById('progress');
@@ -866,6 +1008,9 @@ pub async fn get_player(
         subtitle_js = subtitle_js,
         fonts_js = fonts_js,
         chapters_js = chapters_js,
+        markers_js = markers_js,
+        drm_js = drm_js,
+        burned_js = burned_js,
     );
 
     // Minify JS
@@ -963,6 +1108,11 @@ pub async fn get_player(
         /* Chapter markers */
         .chapter-marker {{ position: absolute; top: 0; width: 3px; height: 100%; background: rgba(255,255,255,0.5); transform: translateX(-50%); z-index: 2; cursor: pointer; }}
         .chapter-marker:hover {{ background: #fff; }}
+
+        /* Skip intro/credits */
+        #skipBtn {{ position: absolute; right: 24px; bottom: 110px; padding: 10px 18px; background: rgba(24,24,24,0.88); color: #fff; border: 1px solid rgba(255,255,255,0.35); border-radius: 8px; font-size: 15px; cursor: pointer; display: none; z-index: 4; }}
+        #skipBtn.show {{ display: block; }}
+        #skipBtn:hover {{ background: #fff; color: #000; }}
     </style>
 </head>
 <body>
@@ -971,6 +1121,7 @@ pub async fn get_player(
         <div id="loading">
             <div class="spinner"></div>
         </div>
+        <button id="skipBtn"></button>
         <div id="controls">
             <div id="progress">
                 <div id="preview" class="preview">
@@ -1044,10 +1195,19 @@ pub async fn get_hls_file(
     Query(query): Query<HlsTokenQuery>,
     Path((id, file)): Path<(String, String)>,
 ) -> Result<Response, (StatusCode, String)> {
-    let key = format!("{}/{}", id, file);
+    // The archived original is never served to players
+    if file.starts_with("source/") {
+        return Err((StatusCode::NOT_FOUND, "File not found".to_string()));
+    }
 
-    // Verify token for HLS files (.m3u8, .ts)
-    if file.ends_with(".m3u8") || file.ends_with(".ts") {
+    // Verify token for HLS/DASH files (.m3u8, .mpd, .ts, CMAF .m4s/.mp4, WebVTT segments)
+    if file.ends_with(".m3u8")
+        || file.ends_with(".mpd")
+        || file.ends_with(".ts")
+        || file.ends_with(".vtt")
+        || file.ends_with(".m4s")
+        || file.ends_with(".mp4")
+    {
         // Extract token from query or Cookie header
         let mut token = query.token.unwrap_or_default();
         if token.is_empty() {
@@ -1093,16 +1253,31 @@ pub async fn get_hls_file(
         }
     }
 
+    // Playlists reference files relative to the current revision's prefix
+    let revision = get_video_revision(&state.db_pool, &id)
+        .await
+        .map_err(internal_err)?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Video not found".to_string()))?;
+    let key = format!("{}{}", storage_prefix(&id, revision), file);
+
+    // Byte-range requests (I-frame playlists reference parts of segments) go to the bucket as-is
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .map(|s| s.to_string());
+
     // Fetch content from S3
     let content = state
         .s3
         .get_object()
         .bucket(&state.config.r2.bucket)
         .key(&key)
+        .set_range(range.clone())
         .send()
         .await
         .map_err(|e| internal_err(anyhow::anyhow!(e)))?;
 
+    let content_range = content.content_range.clone();
     let reader = content.body.into_async_read();
     let stream = tokio_util::io::ReaderStream::new(reader);
     let body_stream = stream.map(|result| result.map_err(std::io::Error::other));
@@ -1111,13 +1286,34 @@ pub async fn get_hls_file(
     // Determine Content-Type
     let content_type = if file.ends_with(".m3u8") {
         "application/vnd.apple.mpegurl"
+    } else if file.ends_with(".mpd") {
+        "application/dash+xml"
     } else if file.ends_with(".ts") {
         "video/mp2t"
+    } else if file.ends_with(".m4s") {
+        "video/iso.segment"
+    } else if file.ends_with(".mp4") {
+        "video/mp4"
+    } else if file.ends_with(".vtt") {
+        "text/vtt"
     } else if file.ends_with(".jpg") || file.ends_with(".jpeg") {
         "image/jpeg"
     } else {
         "application/octet-stream"
     };
 
-    Ok(([(header::CONTENT_TYPE, content_type)], body).into_response())
+    let mut response = ([(header::CONTENT_TYPE, content_type)], body).into_response();
+    response
+        .headers_mut()
+        .insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    if range.is_some()
+        && let Some(content_range) = content_range.and_then(|r| HeaderValue::from_str(&r).ok())
+    {
+        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+        response
+            .headers_mut()
+            .insert(header::CONTENT_RANGE, content_range);
+    }
+
+    Ok(response)
 }
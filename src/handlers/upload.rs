@@ -1,14 +1,26 @@
-use crate::database::{save_attachment, save_chapter, save_subtitle, save_video};
+use crate::dash::write_dash_manifest;
+use crate::database::{
+    get_stored_video, replace_video_content, save_attachment, save_audio_track, save_chapter,
+    save_rendition, save_subtitle, save_video, save_video_key,
+};
 use crate::handlers::common::{internal_err, now_millis};
-use crate::storage::upload_hls_to_r2;
+use crate::handlers::worker::run_remote_job;
+use crate::markers::fingerprint_episode;
+use crate::storage::{
+    delete_stale_revisions, download_from_r2, storage_prefix, upload_hls_to_r2,
+    upload_large_file_to_r2,
+};
 use crate::types::{
-    AppState, ChunkUploadResponse, ChunkedUpload, FinalizeUploadRequest, ProgressMap,
-    ProgressResponse, ProgressUpdate, QueueItem, QueueListResponse, UploadAccepted, UploadResponse,
+    AppState, AttachmentInfo, AudioStreamInfo, ChunkUploadResponse, ChunkedUpload, ContentKey,
+    EncodeSpec, EncodedSource, FinalizeUploadRequest, ProgressMap, ProgressResponse,
+    ProgressUpdate, QueueItem, QueueListResponse, SegmentEncryption, SubtitleStreamInfo,
+    UploadAccepted, UploadOptions, UploadResponse, VideoMetadata,
 };
 use crate::video::{
-    encode_to_hls, extract_all_attachments, extract_subtitle, extract_vobsub_subtitle,
-    get_attachments, get_audio_streams, get_chapters, get_subtitle_extension, get_subtitle_streams,
-    get_variants_for_height, get_video_duration, get_video_height, is_vobsub_subtitle,
+    build_variant_ladder, encode_to_hls, extract_all_attachments, extract_subtitle,
+    extract_vobsub_subtitle, generate_scene_chapters, get_attachments, get_audio_streams,
+    get_chapters, get_subtitle_extension, get_subtitle_streams, get_video_metadata,
+    is_vobsub_subtitle, probe_complexity, write_hls_key_info,
 };
 
 use axum::{
@@ -21,6 +33,7 @@ use futures::stream::Stream;
 use std::collections::HashMap;
 use std::path::PathBuf;
 use std::time::Duration;
+use tokio::sync::Semaphore;
 use tokio::{fs, io::AsyncReadExt, io::AsyncWriteExt};
 use tracing::{error, info, warn};
 use uuid::Uuid;
@@ -56,14 +69,732 @@ async fn cleanup_stale_uploads(state: &AppState) {
     }
 }
 
-async fn update_progress(progress_map: &ProgressMap, upload_id: &str, mut update: ProgressUpdate) {
+pub(crate) async fn update_progress(
+    progress_map: &ProgressMap,
+    upload_id: &str,
+    mut update: ProgressUpdate,
+) {
     let mut map = progress_map.write().await;
     if let Some(existing) = map.get(upload_id) {
         update.created_at = existing.created_at;
+        if update.video_name.is_none() {
+            update.video_name = existing.video_name.clone();
+        }
     }
     map.insert(upload_id.to_string(), update);
 }
 
+/// Drop a finished job's progress entry once clients had time to see the outcome
+pub(crate) async fn expire_progress(progress_map: &ProgressMap, upload_id: &str) {
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    let mut map = progress_map.write().await;
+    if let Some(entry) = map.get(upload_id)
+        && (entry.status == "completed" || entry.status == "failed")
+    {
+        map.remove(upload_id);
+    }
+}
+
+/// Reject uploads naming an encoding profile that isn't configured
+fn check_profile(state: &AppState, options: &UploadOptions) -> Result<(), (StatusCode, String)> {
+    state
+        .config
+        .video
+        .profile(options.profile.as_deref())
+        .map(|_| ())
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+/// Whether a reprocess or rendition change of `video_id` is still running;
+/// they rewrite the same files, so only one may run at a time
+pub(crate) fn video_job_running(
+    progress_map: &HashMap<String, ProgressUpdate>,
+    video_id: &str,
+) -> bool {
+    [
+        format!("reprocess-{}", video_id),
+        format!("rendition-{}", video_id),
+    ]
+    .iter()
+    .any(|id| {
+        progress_map
+            .get(id)
+            .is_some_and(|p| p.status == "processing")
+    })
+}
+
+/// An existing video re-encoded from its archived source
+struct Reprocess {
+    video_id: String,
+    /// Revision the new files are written under
+    revision: i64,
+    /// Thumbnail an admin picked, kept instead of the newly generated one
+    custom_thumbnail: Option<String>,
+}
+
+/// Run the encode/upload pipeline for a received source file in the background
+fn spawn_processing(
+    state: AppState,
+    upload_id: String,
+    video_path: PathBuf,
+    video_name: String,
+    tags: Vec<String>,
+    options: UploadOptions,
+    reprocess: Option<Reprocess>,
+) {
+    tokio::spawn(async move {
+        let result = process_video(
+            &state,
+            &upload_id,
+            &video_path,
+            &video_name,
+            &tags,
+            &options,
+            reprocess.as_ref(),
+        )
+        .await;
+
+        match result {
+            Ok(response) => {
+                let completion_progress = ProgressUpdate {
+                    stage: "Completed".to_string(),
+                    current_chunk: 1,
+                    total_chunks: 1,
+                    percentage: 100,
+                    details: Some("Upload and processing complete".to_string()),
+                    status: "completed".to_string(),
+                    result: Some(response),
+                    error: None,
+                    video_name: Some(video_name.clone()),
+                    created_at: now_millis(),
+                    variant_percentage: None,
+                    encode_speed: None,
+                    eta_seconds: None,
+                };
+                update_progress(&state.progress, &upload_id, completion_progress).await;
+            }
+            Err(e) => {
+                error!("Background processing failed: {:?}", e);
+                let error_progress = ProgressUpdate {
+                    stage: "Failed".to_string(),
+                    current_chunk: 0,
+                    total_chunks: 1,
+                    percentage: 0,
+                    details: Some(format!("Processing failed: {}", e)),
+                    status: "failed".to_string(),
+                    result: None,
+                    error: Some(e.to_string()),
+                    video_name: Some(video_name.clone()),
+                    created_at: now_millis(),
+                    variant_percentage: None,
+                    encode_speed: None,
+                    eta_seconds: None,
+                };
+                update_progress(&state.progress, &upload_id, error_progress).await;
+            }
+        }
+
+        expire_progress(&state.progress, &upload_id).await;
+    });
+}
+
+/// ffprobe results of a source, probed once where the encode runs
+pub(crate) struct SourceProbe {
+    pub metadata: VideoMetadata,
+    pub audio_streams: Vec<AudioStreamInfo>,
+    pub subtitle_streams: Vec<SubtitleStreamInfo>,
+    pub attachment_streams: Vec<AttachmentInfo>,
+}
+
+impl SourceProbe {
+    pub(crate) async fn probe(video_path: &PathBuf) -> anyhow::Result<Self> {
+        Ok(Self {
+            metadata: get_video_metadata(video_path).await?,
+            audio_streams: get_audio_streams(video_path).await.unwrap_or_default(),
+            subtitle_streams: get_subtitle_streams(video_path).await.unwrap_or_default(),
+            attachment_streams: get_attachments(video_path).await.unwrap_or_default(),
+        })
+    }
+}
+
+/// Encode stage shared by the server and remote workers: the per-title probe, chapter
+/// detection, HLS renditions, extracted subtitles and fonts and the DASH manifest,
+/// all written to `hls_dir`. `encode_slots` is the FFmpeg semaphore the caller holds
+/// a permit of.
+pub(crate) async fn encode_source(
+    progress: &ProgressMap,
+    encode_slots: &Semaphore,
+    upload_id: &str,
+    video_path: &PathBuf,
+    hls_dir: &PathBuf,
+    source: &SourceProbe,
+    spec: &EncodeSpec,
+) -> anyhow::Result<EncodedSource> {
+    let video_config = &spec.video_config;
+    let metadata = &source.metadata;
+    let subtitle_streams = &source.subtitle_streams;
+
+    // Optional per-title pass: scale the BPP ladder by the measured complexity
+    let mut variants = spec.variants.clone();
+    let per_title = &video_config.per_title;
+    let complexity = if per_title.enabled {
+        let analysis_progress = ProgressUpdate {
+            stage: "Analyzing complexity".to_string(),
+            current_chunk: 0,
+            total_chunks: 1,
+            percentage: 0,
+            details: Some(format!(
+                "Probing {} samples at CRF {}",
+                per_title.samples, per_title.crf
+            )),
+            status: "processing".to_string(),
+            result: None,
+            error: None,
+            video_name: None,
+            created_at: now_millis(),
+            variant_percentage: None,
+            encode_speed: None,
+            eta_seconds: None,
+        };
+        update_progress(progress, upload_id, analysis_progress).await;
+
+        match probe_complexity(video_path, metadata, &variants, per_title).await {
+            Ok(factor) => {
+                for variant in &mut variants {
+                    variant.scale_bitrate(factor);
+                }
+                Some(factor)
+            }
+            Err(e) => {
+                warn!("Complexity probe failed, using default ladder: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let encoding_progress = ProgressUpdate {
+        stage: "FFmpeg processing".to_string(),
+        current_chunk: 0,
+        total_chunks: variants.len() as u32,
+        percentage: 0,
+        details: Some("Starting encoding...".to_string()),
+        status: "processing".to_string(),
+        result: None,
+        error: None,
+        video_name: None,
+        created_at: now_millis(),
+        variant_percentage: None,
+        encode_speed: None,
+        eta_seconds: None,
+    };
+    update_progress(progress, upload_id, encoding_progress).await;
+
+    // Chapters from the container, falling back to scene detection
+    let mut chapters = get_chapters(video_path).await.unwrap_or_default();
+    let auto_chapters = &video_config.auto_chapters;
+    let chapters_are_auto = chapters.is_empty() && auto_chapters.enabled;
+    if chapters_are_auto {
+        match generate_scene_chapters(video_path, metadata.duration as f64, auto_chapters).await {
+            Ok(scene_chapters) => {
+                info!(
+                    "Generated {} chapters from scene changes",
+                    scene_chapters.len()
+                );
+                chapters = scene_chapters;
+            }
+            Err(e) => warn!("Automatic chapter generation failed: {}", e),
+        }
+    }
+
+    // The AES-128 key file is kept outside the HLS dir so it never reaches the bucket
+    let key_dir = std::env::temp_dir().join(format!("hls-key-{}", &spec.video_id));
+    let encryption = match &spec.content_key {
+        Some(ContentKey::Cenc { kid, key }) => Some(SegmentEncryption::Cenc {
+            kid: *kid,
+            key: *key,
+        }),
+        Some(ContentKey::Aes128 { key }) => {
+            let key_uri = format!("/api/videos/{}/key", spec.video_id);
+            let key_info = write_hls_key_info(&key_dir, &key_uri, key).await?;
+            Some(SegmentEncryption::Aes128 { key_info })
+        }
+        None => None,
+    };
+
+    let hls_output = encode_to_hls(
+        video_path,
+        hls_dir,
+        progress,
+        upload_id,
+        video_config,
+        metadata,
+        &variants,
+        &source.audio_streams,
+        subtitle_streams,
+        encryption.as_ref(),
+        spec.burn_subtitles,
+        &spec.profile,
+        encode_slots,
+    )
+    .await;
+    let _ = fs::remove_dir_all(&key_dir).await;
+    let hls_output = hls_output?;
+
+    // Create directories for subtitles and fonts
+    let subtitles_dir = hls_dir.join("subtitles");
+    let fonts_dir = hls_dir.join("fonts");
+
+    if !subtitle_streams.is_empty() {
+        fs::create_dir_all(&subtitles_dir).await?;
+    }
+    if !source.attachment_streams.is_empty() {
+        fs::create_dir_all(&fonts_dir).await?;
+        // Extract all font attachments
+        extract_all_attachments(video_path, &fonts_dir).await?;
+    }
+
+    // Extract each subtitle stream
+    for (idx, sub) in subtitle_streams.iter().enumerate() {
+        if is_vobsub_subtitle(&sub.codec_name) {
+            // VobSub needs special handling to generate both .sub and .idx files
+            if let Err(e) =
+                extract_vobsub_subtitle(video_path, idx as i32, &subtitles_dir, idx).await
+            {
+                error!(
+                    "Failed to extract VobSub subtitle stream {} (track {}): {}",
+                    sub.stream_index, idx, e
+                );
+            }
+        } else {
+            let ext = get_subtitle_extension(&sub.codec_name);
+            let sub_filename = format!("track_{}.{}", idx, ext);
+            let sub_path = subtitles_dir.join(&sub_filename);
+
+            // Use enumerate index (idx) as relative subtitle stream index
+            if let Err(e) =
+                extract_subtitle(video_path, idx as i32, &sub_path, &sub.codec_name).await
+            {
+                error!(
+                    "Failed to extract subtitle stream {} (track {}): {}",
+                    sub.stream_index, idx, e
+                );
+            }
+        }
+    }
+
+    // DASH manifest over the same CMAF segments, once subtitles are extracted.
+    // Whole-segment AES-128 has no DASH equivalent, so those videos are HLS only.
+    let cenc_kid = match &encryption {
+        Some(SegmentEncryption::Cenc { kid, .. }) => Some(*kid),
+        _ => None,
+    };
+    if video_config.dash && matches!(encryption, Some(SegmentEncryption::Aes128 { .. })) {
+        warn!("Skipping DASH manifest: segments are AES-128 encrypted");
+    } else if video_config.dash
+        && let Err(e) = write_dash_manifest(
+            hls_dir,
+            &hls_output.variants,
+            &hls_output.audio,
+            subtitle_streams,
+            source.metadata.duration,
+            cenc_kid.as_ref(),
+        )
+        .await
+    {
+        warn!("Failed to write DASH manifest: {}", e);
+    }
+
+    Ok(EncodedSource {
+        output: hls_output,
+        complexity,
+        chapters,
+        chapters_are_auto,
+    })
+}
+
+/// Encode a source file to HLS, upload it to R2 and record its metadata.
+/// With `reprocess` the existing video gets a new revision under the same ID.
+async fn process_video(
+    state: &AppState,
+    upload_id: &str,
+    video_path: &PathBuf,
+    video_name: &str,
+    tags: &[String],
+    options: &UploadOptions,
+    reprocess: Option<&Reprocess>,
+) -> anyhow::Result<UploadResponse> {
+    let output_id = reprocess
+        .map(|r| r.video_id.clone())
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let revision = reprocess.map(|r| r.revision).unwrap_or(0);
+    let prefix = storage_prefix(&output_id, revision);
+    let hls_dir = std::env::temp_dir().join(format!("hls-{}", &output_id));
+    fs::create_dir_all(&hls_dir)
+        .await
+        .map_err(|e| anyhow::anyhow!(e))?;
+
+    // Bound FFmpeg/ffprobe concurrency globally so large batch uploads
+    // don't spawn hundreds of FFmpeg processes and stall the pipeline.
+    let ffmpeg_permit = state.ffmpeg_semaphore.acquire().await.unwrap();
+
+    // Run sequentially to keep the concurrency limit meaningful.
+    let profile_name = options
+        .profile
+        .as_deref()
+        .or(state.config.video.default_profile.as_deref());
+    let profile = state.config.video.profile(profile_name)?;
+
+    let source = SourceProbe::probe(video_path).await?;
+    let metadata = &source.metadata;
+    let video_duration = metadata.duration;
+    let variants = build_variant_ladder(metadata, &profile.tiers);
+    let available_resolutions: Vec<String> = variants.iter().map(|v| v.label.clone()).collect();
+
+    // Per-video content key. CENC takes precedence over AES-128.
+    let content_key = if state.config.video.clearkey_drm {
+        Some(ContentKey::Cenc {
+            kid: rand::random(),
+            key: rand::random(),
+        })
+    } else if state.config.video.encryption {
+        Some(ContentKey::Aes128 {
+            key: rand::random(),
+        })
+    } else {
+        None
+    };
+
+    let spec = EncodeSpec {
+        video_id: output_id.clone(),
+        prefix: prefix.clone(),
+        video_config: state.config.video.clone(),
+        profile,
+        variants,
+        burn_subtitles: options.burn_subtitles,
+        content_key: content_key.clone(),
+    };
+
+    let mut ffmpeg_permit = Some(ffmpeg_permit);
+    let remote = if state.config.server.worker_token.is_some() {
+        // Remote workers bring their own FFmpeg capacity
+        ffmpeg_permit = None;
+        run_remote_job(state, upload_id, video_name, video_path, spec.clone()).await?
+    } else {
+        None
+    };
+
+    let (encoded, playlist_key) = if let Some(remote) = remote {
+        remote
+    } else {
+        // Jobs no worker claimed in time fall back to a local encode
+        let ffmpeg_permit = match ffmpeg_permit {
+            Some(permit) => permit,
+            None => state.ffmpeg_semaphore.acquire().await?,
+        };
+        let encoded = encode_source(
+            &state.progress,
+            &state.ffmpeg_semaphore,
+            upload_id,
+            video_path,
+            &hls_dir,
+            &source,
+            &spec,
+        )
+        .await?;
+
+        // Release FFmpeg permit before network/upload work.
+        drop(ffmpeg_permit);
+
+        let upload_progress = ProgressUpdate {
+            stage: "Upload to R2".to_string(),
+            current_chunk: 0,
+            total_chunks: 1,
+            percentage: 0,
+            details: Some("Uploading segments to storage...".to_string()),
+            status: "processing".to_string(),
+            result: None,
+            error: None,
+            video_name: Some(video_name.to_string()),
+            created_at: now_millis(),
+            variant_percentage: None,
+            encode_speed: None,
+            eta_seconds: None,
+        };
+        update_progress(&state.progress, upload_id, upload_progress).await;
+
+        let playlist_key = upload_hls_to_r2(state, &hls_dir, &prefix, Some(upload_id)).await?;
+        (encoded, playlist_key)
+    };
+    let hls_output = &encoded.output;
+    let audio_streams = &source.audio_streams;
+    let subtitle_streams = &source.subtitle_streams;
+    let attachment_streams = &source.attachment_streams;
+
+    // Keep the original for later re-encodes; reprocessing reuses the archived copy
+    let source_key = if reprocess.is_none() && state.config.video.archive_source {
+        let ext = video_path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("bin");
+        let key = format!("{}/source/original.{}", output_id, ext);
+        match upload_large_file_to_r2(state, video_path, &key).await {
+            Ok(()) => Some(key),
+            Err(e) => {
+                warn!("Failed to archive source of {}: {}", output_id, e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    // A thumbnail picked by an admin survives reprocessing
+    let thumbnail_key = reprocess
+        .and_then(|r| r.custom_thumbnail.clone())
+        .unwrap_or_else(|| format!("{}thumbnail.jpg", prefix));
+    let thumbnails_vtt_key = hls_output
+        .trickplay
+        .then(|| format!("{}thumbnails.vtt", prefix));
+    let preview_keys = hls_output.hover_preview.then(|| {
+        (
+            format!("{}preview.mp4", prefix),
+            format!("{}preview.webp", prefix),
+        )
+    });
+    let preview_keys = preview_keys
+        .as_ref()
+        .map(|(mp4, webp)| (mp4.as_str(), webp.as_str()));
+    let entrypoint = playlist_key.clone();
+
+    // All metadata lands in one transaction, so a reprocessed video switches to
+    // its new revision at once
+    let mut tx = state.db_pool.begin().await?;
+    if reprocess.is_some() {
+        replace_video_content(
+            &mut tx,
+            &output_id,
+            revision,
+            &available_resolutions,
+            video_duration,
+            &thumbnail_key,
+            thumbnails_vtt_key.as_deref(),
+            preview_keys,
+            &entrypoint,
+            profile_name,
+        )
+        .await?;
+    } else {
+        save_video(
+            &mut *tx,
+            &output_id,
+            video_name,
+            tags,
+            &available_resolutions,
+            video_duration,
+            &thumbnail_key,
+            thumbnails_vtt_key.as_deref(),
+            preview_keys,
+            &entrypoint,
+            source_key.as_deref(),
+            profile_name,
+        )
+        .await?;
+    }
+
+    match &content_key {
+        Some(ContentKey::Aes128 { key }) => {
+            save_video_key(&mut *tx, &output_id, "aes-128", None, key).await?;
+        }
+        Some(ContentKey::Cenc { kid, key }) => {
+            save_video_key(&mut *tx, &output_id, "cenc", Some(kid), key).await?;
+        }
+        None => {}
+    }
+
+    // Record renditions and the inputs behind their bitrates
+    for variant in hls_output
+        .variants
+        .iter()
+        .chain(&hls_output.burned_variants)
+    {
+        if let Err(e) = save_rendition(&mut *tx, &output_id, variant, encoded.complexity).await {
+            error!(
+                "Failed to save rendition metadata for {}: {}",
+                variant.dir(),
+                e
+            );
+        }
+    }
+
+    // Save audio track metadata, with the loudness measured during normalization
+    for (idx, audio) in audio_streams.iter().enumerate() {
+        let integrated_loudness = hls_output.audio_loudness.get(idx).copied().flatten();
+        if let Err(e) = save_audio_track(
+            &mut *tx,
+            &output_id,
+            idx as i32,
+            audio.language.as_deref(),
+            audio.title.as_deref(),
+            &audio.codec_name,
+            audio.channels,
+            audio.sample_rate,
+            audio.bit_rate,
+            audio.is_default,
+            integrated_loudness,
+        )
+        .await
+        {
+            error!(
+                "Failed to save audio track metadata for track {}: {}",
+                idx, e
+            );
+        }
+    }
+
+    // Save subtitle metadata to database
+    for (idx, sub) in subtitle_streams.iter().enumerate() {
+        let ext = match sub.codec_name.as_str() {
+            "ass" | "ssa" => "ass",
+            "subrip" | "srt" => "srt",
+            _ => "ass",
+        };
+        let storage_key = format!("{}subtitles/track_{}.{}", prefix, idx, ext);
+
+        if let Err(e) = save_subtitle(
+            &mut *tx,
+            &output_id,
+            idx as i32,
+            sub.language.as_deref(),
+            sub.title.as_deref(),
+            &sub.codec_name,
+            &storage_key,
+            None, // idx_storage_key for VobSub
+            sub.is_default,
+            sub.is_forced,
+            None,
+        )
+        .await
+        {
+            error!("Failed to save subtitle metadata for track {}: {}", idx, e);
+        }
+    }
+
+    // OCR companions of bitmap tracks, numbered after the source tracks
+    for (n, &idx) in hls_output.ocr_subtitles.iter().enumerate() {
+        let sub = &subtitle_streams[idx];
+        let track_index = (subtitle_streams.len() + n) as i32;
+        let storage_key = format!("{}subtitles/track_{}_ocr.srt", prefix, idx);
+        let title = format!(
+            "{} (OCR)",
+            sub.title
+                .as_deref()
+                .or(sub.language.as_deref())
+                .unwrap_or("Track")
+        );
+
+        if let Err(e) = save_subtitle(
+            &mut *tx,
+            &output_id,
+            track_index,
+            sub.language.as_deref(),
+            Some(&title),
+            "subrip",
+            &storage_key,
+            None,
+            false,
+            sub.is_forced,
+            Some(idx as i32),
+        )
+        .await
+        {
+            error!(
+                "Failed to save OCR subtitle metadata for track {}: {}",
+                idx, e
+            );
+        }
+    }
+
+    // Save attachment metadata to database
+    for att in attachment_streams {
+        let storage_key = format!("{}fonts/{}", prefix, att.filename);
+
+        if let Err(e) = save_attachment(
+            &mut *tx,
+            &output_id,
+            &att.filename,
+            &att.mimetype,
+            &storage_key,
+        )
+        .await
+        {
+            error!(
+                "Failed to save attachment metadata for {}: {}",
+                att.filename, e
+            );
+        }
+    }
+
+    for (idx, chapter) in encoded.chapters.iter().enumerate() {
+        if let Err(e) = save_chapter(
+            &mut *tx,
+            &output_id,
+            idx as i32,
+            chapter.start_time,
+            chapter.end_time,
+            &chapter.title,
+            encoded.chapters_are_auto,
+        )
+        .await
+        {
+            error!("Failed to save chapter metadata for index {}: {}", idx, e);
+        }
+    }
+
+    tx.commit().await?;
+
+    // The new revision is live; drop the files of the previous one
+    if reprocess.is_some() {
+        match delete_stale_revisions(state, &output_id, &prefix, &[thumbnail_key.as_str()]).await {
+            Ok(count) => info!(
+                "Removed {} objects of previous revisions of {}",
+                count, output_id
+            ),
+            Err(e) => warn!(
+                "Failed to clean up previous revisions of {}: {}",
+                output_id, e
+            ),
+        }
+    }
+
+    // Fingerprint the intro/credits windows and match them against the rest of the series
+    let intro_detection = &state.config.video.intro_detection;
+    if intro_detection.enabled
+        && !audio_streams.is_empty()
+        && let Err(e) = fingerprint_episode(
+            &state.db_pool,
+            video_path,
+            &output_id,
+            video_duration as f64,
+            tags,
+            intro_detection,
+        )
+        .await
+    {
+        warn!("Intro/credits fingerprinting failed: {}", e);
+    }
+
+    let _ = fs::remove_file(&video_path).await;
+    let _ = fs::remove_dir_all(&hls_dir).await;
+
+    let player_url = format!("/player/{}", output_id);
+    Ok(UploadResponse {
+        player_url,
+        upload_id: upload_id.to_string(),
+    })
+}
+
 pub async fn upload_video(
     State(state): State<AppState>,
     headers: HeaderMap,
@@ -72,6 +803,7 @@ pub async fn upload_video(
     let mut video_path: Option<PathBuf> = None;
     let mut video_name: Option<String> = None;
     let mut tags: Vec<String> = Vec::new();
+    let mut options = UploadOptions::default();
 
     let upload_id = headers
         .get("X-Upload-ID")
@@ -92,6 +824,8 @@ pub async fn upload_video(
             video_name: None,
             created_at: now_millis(),
             variant_percentage: None,
+            encode_speed: None,
+            eta_seconds: None,
         };
         state
             .progress
@@ -145,6 +879,8 @@ pub async fn upload_video(
                             video_name: None,
                             created_at: now_millis(),
                             variant_percentage: None,
+                            encode_speed: None,
+                            eta_seconds: None,
                         };
                         update_progress(&state.progress, &upload_id, progress_update).await;
                     }
@@ -174,6 +910,29 @@ pub async fn upload_video(
                         .collect();
                 }
             }
+            Some("burn_subtitles") => {
+                let text = field
+                    .text()
+                    .await
+                    .map_err(|e| internal_err(anyhow::anyhow!(e)))?;
+                if !text.trim().is_empty() {
+                    options.burn_subtitles = Some(text.trim().parse().map_err(|_| {
+                        (
+                            StatusCode::BAD_REQUEST,
+                            "Invalid burn_subtitles".to_string(),
+                        )
+                    })?);
+                }
+            }
+            Some("profile") => {
+                let text = field
+                    .text()
+                    .await
+                    .map_err(|e| internal_err(anyhow::anyhow!(e)))?;
+                if !text.trim().is_empty() {
+                    options.profile = Some(text.trim().to_string());
+                }
+            }
             _ => {
                 continue;
             }
@@ -190,6 +949,11 @@ pub async fn upload_video(
     let video_name =
         video_name.ok_or_else(|| (StatusCode::BAD_REQUEST, "missing field 'name'".to_string()))?;
 
+    if let Err(e) = check_profile(&state, &options) {
+        let _ = fs::remove_file(&video_path).await;
+        return Err(e);
+    }
+
     let initial_progress = ProgressUpdate {
         stage: "Queued for processing".to_string(),
         current_chunk: 0,
@@ -202,273 +966,20 @@ pub async fn upload_video(
         video_name: Some(video_name.clone()),
         created_at: now_millis(),
         variant_percentage: None,
+        encode_speed: None,
+        eta_seconds: None,
     };
     update_progress(&state.progress, &upload_id, initial_progress).await;
 
-    let state_clone = state.clone();
-    let upload_id_clone = upload_id.clone();
-    let video_path_clone = video_path.clone();
-    let video_name_clone = video_name.clone();
-    let tags_clone = tags.clone();
-
-    tokio::spawn(async move {
-        let result = async {
-            let output_id = Uuid::new_v4().to_string();
-            let hls_dir = std::env::temp_dir().join(format!("hls-{}", &output_id));
-            fs::create_dir_all(&hls_dir)
-                .await
-                .map_err(|e| anyhow::anyhow!(e))?;
-
-            // Bound FFmpeg/ffprobe concurrency globally so large batch uploads
-            // don't spawn hundreds of FFmpeg processes and stall the pipeline.
-            let ffmpeg_permit = state_clone.ffmpeg_semaphore.acquire().await.unwrap();
-
-            // Run sequentially to keep the concurrency limit meaningful.
-            let video_duration = get_video_duration(&video_path_clone).await?;
-            let original_height = get_video_height(&video_path_clone).await?;
-            let variants = get_variants_for_height(original_height);
-            let available_resolutions: Vec<String> =
-                variants.iter().map(|v| v.label.clone()).collect();
-
-            let encoding_progress = ProgressUpdate {
-                stage: "FFmpeg processing".to_string(),
-                current_chunk: 0,
-                total_chunks: variants.len() as u32,
-                percentage: 0,
-                details: Some("Starting encoding...".to_string()),
-                status: "processing".to_string(),
-                result: None,
-                error: None,
-                video_name: Some(video_name_clone.clone()),
-                created_at: now_millis(),
-                variant_percentage: None,
-            };
-            update_progress(&state_clone.progress, &upload_id_clone, encoding_progress).await;
-
-            // Get audio streams for multi-audio encoding
-            let audio_streams = get_audio_streams(&video_path_clone)
-                .await
-                .unwrap_or_default();
-
-            encode_to_hls(
-                &video_path_clone,
-                &hls_dir,
-                &state_clone.progress,
-                &upload_id_clone,
-                &state_clone.config.video.encoder,
-                video_duration,
-                &audio_streams,
-            )
-            .await?;
-
-            // Extract subtitles and attachments from the source video
-            let subtitle_streams = get_subtitle_streams(&video_path_clone)
-                .await
-                .unwrap_or_default();
-            let attachment_streams = get_attachments(&video_path_clone).await.unwrap_or_default();
-
-            // Create directories for subtitles and fonts
-            let subtitles_dir = hls_dir.join("subtitles");
-            let fonts_dir = hls_dir.join("fonts");
-
-            if !subtitle_streams.is_empty() {
-                fs::create_dir_all(&subtitles_dir).await?;
-            }
-            if !attachment_streams.is_empty() {
-                fs::create_dir_all(&fonts_dir).await?;
-                // Extract all font attachments
-                extract_all_attachments(&video_path_clone, &fonts_dir).await?;
-            }
-
-            // Extract each subtitle stream
-            for (idx, sub) in subtitle_streams.iter().enumerate() {
-                if is_vobsub_subtitle(&sub.codec_name) {
-                    // VobSub needs special handling to generate both .sub and .idx files
-                    if let Err(e) =
-                        extract_vobsub_subtitle(&video_path_clone, idx as i32, &subtitles_dir, idx)
-                            .await
-                    {
-                        error!(
-                            "Failed to extract VobSub subtitle stream {} (track {}): {}",
-                            sub.stream_index, idx, e
-                        );
-                    }
-                } else {
-                    let ext = get_subtitle_extension(&sub.codec_name);
-                    let sub_filename = format!("track_{}.{}", idx, ext);
-                    let sub_path = subtitles_dir.join(&sub_filename);
-
-                    // Use enumerate index (idx) as relative subtitle stream index
-                    if let Err(e) =
-                        extract_subtitle(&video_path_clone, idx as i32, &sub_path, &sub.codec_name)
-                            .await
-                    {
-                        error!(
-                            "Failed to extract subtitle stream {} (track {}): {}",
-                            sub.stream_index, idx, e
-                        );
-                    }
-                }
-            }
-
-            // Release FFmpeg permit before network/upload work.
-            drop(ffmpeg_permit);
-
-            let upload_progress = ProgressUpdate {
-                stage: "Upload to R2".to_string(),
-                current_chunk: 0,
-                total_chunks: 1,
-                percentage: 0,
-                details: Some("Uploading segments to storage...".to_string()),
-                status: "processing".to_string(),
-                result: None,
-                error: None,
-                video_name: Some(video_name_clone.clone()),
-                created_at: now_millis(),
-                variant_percentage: None,
-            };
-            update_progress(&state_clone.progress, &upload_id_clone, upload_progress).await;
-
-            let prefix = format!("{}/", output_id);
-            let playlist_key =
-                upload_hls_to_r2(&state_clone, &hls_dir, &prefix, Some(&upload_id_clone)).await?;
-
-            let thumbnail_key = format!("{}/thumbnail.jpg", output_id);
-            let sprites_key = format!("{}/sprites.jpg", output_id);
-            let entrypoint = playlist_key.clone();
-
-            save_video(
-                &state_clone.db_pool,
-                &output_id,
-                &video_name_clone,
-                &tags_clone,
-                &available_resolutions,
-                video_duration,
-                &thumbnail_key,
-                &sprites_key,
-                &entrypoint,
-            )
-            .await?;
-
-            // Save subtitle metadata to database
-            for (idx, sub) in subtitle_streams.iter().enumerate() {
-                let ext = match sub.codec_name.as_str() {
-                    "ass" | "ssa" => "ass",
-                    "subrip" | "srt" => "srt",
-                    _ => "ass",
-                };
-                let storage_key = format!("{}/subtitles/track_{}.{}", output_id, idx, ext);
-
-                if let Err(e) = save_subtitle(
-                    &state_clone.db_pool,
-                    &output_id,
-                    idx as i32,
-                    sub.language.as_deref(),
-                    sub.title.as_deref(),
-                    &sub.codec_name,
-                    &storage_key,
-                    None, // idx_storage_key for VobSub
-                    sub.is_default,
-                    sub.is_forced,
-                )
-                .await
-                {
-                    error!("Failed to save subtitle metadata for track {}: {}", idx, e);
-                }
-            }
-
-            // Save attachment metadata to database
-            for att in &attachment_streams {
-                let storage_key = format!("{}/fonts/{}", output_id, att.filename);
-
-                if let Err(e) = save_attachment(
-                    &state_clone.db_pool,
-                    &output_id,
-                    &att.filename,
-                    &att.mimetype,
-                    &storage_key,
-                )
-                .await
-                {
-                    error!(
-                        "Failed to save attachment metadata for {}: {}",
-                        att.filename, e
-                    );
-                }
-            }
-
-            // Extract and save chapters from video
-            let chapter_streams = get_chapters(&video_path_clone).await.unwrap_or_default();
-            for (idx, chapter) in chapter_streams.iter().enumerate() {
-                if let Err(e) = save_chapter(
-                    &state_clone.db_pool,
-                    &output_id,
-                    idx as i32,
-                    chapter.start_time,
-                    chapter.end_time,
-                    &chapter.title,
-                )
-                .await
-                {
-                    error!("Failed to save chapter metadata for index {}: {}", idx, e);
-                }
-            }
-
-            let _ = fs::remove_file(&video_path_clone).await;
-            let _ = fs::remove_dir_all(&hls_dir).await;
-
-            let player_url = format!("/player/{}", output_id);
-            Ok::<_, anyhow::Error>(UploadResponse {
-                player_url,
-                upload_id: upload_id_clone.clone(),
-            })
-        }
-        .await;
-
-        match result {
-            Ok(response) => {
-                let completion_progress = ProgressUpdate {
-                    stage: "Completed".to_string(),
-                    current_chunk: 1,
-                    total_chunks: 1,
-                    percentage: 100,
-                    details: Some("Upload and processing complete".to_string()),
-                    status: "completed".to_string(),
-                    result: Some(response),
-                    error: None,
-                    video_name: Some(video_name_clone.clone()),
-                    created_at: now_millis(),
-                    variant_percentage: None,
-                };
-                update_progress(&state_clone.progress, &upload_id_clone, completion_progress).await;
-            }
-            Err(e) => {
-                error!("Background processing failed: {:?}", e);
-                let error_progress = ProgressUpdate {
-                    stage: "Failed".to_string(),
-                    current_chunk: 0,
-                    total_chunks: 1,
-                    percentage: 0,
-                    details: Some(format!("Processing failed: {}", e)),
-                    status: "failed".to_string(),
-                    result: None,
-                    error: Some(e.to_string()),
-                    video_name: Some(video_name_clone.clone()),
-                    created_at: now_millis(),
-                    variant_percentage: None,
-                };
-                update_progress(&state_clone.progress, &upload_id_clone, error_progress).await;
-            }
-        }
-
-        tokio::time::sleep(Duration::from_secs(10)).await;
-        let mut progress_map = state_clone.progress.write().await;
-        if let Some(entry) = progress_map.get(&upload_id_clone)
-            && (entry.status == "completed" || entry.status == "failed")
-        {
-            progress_map.remove(&upload_id_clone);
-        }
-    });
+    spawn_processing(
+        state.clone(),
+        upload_id.clone(),
+        video_path,
+        video_name,
+        tags,
+        options,
+        None,
+    );
 
     Ok(Json(UploadAccepted {
         upload_id,
@@ -622,6 +1133,8 @@ pub async fn upload_chunk(
                 video_name: Some(file_name.replace(&['.'][..], "_")),
                 created_at: now_millis(),
                 variant_percentage: None,
+                encode_speed: None,
+                eta_seconds: None,
             };
             state
                 .progress
@@ -677,6 +1190,8 @@ pub async fn upload_chunk(
         video_name: Some(file_name.replace(&['.'][..], "_")),
         created_at: now_millis(),
         variant_percentage: None,
+        encode_speed: None,
+        eta_seconds: None,
     };
     update_progress(&state.progress, &upload_id, progress).await;
 
@@ -706,6 +1221,7 @@ pub async fn finalize_chunked_upload(
         })?;
 
     info!("Finalizing chunked upload: {}", upload_id);
+    check_profile(&state, &body.options)?;
 
     let chunked_upload = {
         let mut uploads = state.chunked_uploads.write().await;
@@ -736,6 +1252,8 @@ pub async fn finalize_chunked_upload(
         video_name: Some(body.name.clone()),
         created_at: now_millis(),
         variant_percentage: None,
+        encode_speed: None,
+        eta_seconds: None,
     };
     update_progress(&state.progress, &upload_id, progress).await;
     let final_path =
@@ -788,267 +1306,131 @@ pub async fn finalize_chunked_upload(
         video_name: Some(video_name.clone()),
         created_at: now_millis(),
         variant_percentage: None,
+        encode_speed: None,
+        eta_seconds: None,
     };
     update_progress(&state.progress, &upload_id, progress).await;
-    let state_clone = state.clone();
-    let upload_id_clone = upload_id.clone();
-    let video_path_clone = final_path.clone();
-    let video_name_clone = video_name.clone();
-    let tags_clone = tags.clone();
-
-    tokio::spawn(async move {
-        let result = async {
-            let output_id = Uuid::new_v4().to_string();
-            let hls_dir = std::env::temp_dir().join(format!("hls-{}", &output_id));
-            fs::create_dir_all(&hls_dir)
-                .await
-                .map_err(|e| anyhow::anyhow!(e))?;
+    spawn_processing(
+        state.clone(),
+        upload_id.clone(),
+        final_path,
+        video_name,
+        tags,
+        body.options,
+        None,
+    );
 
-            // Bound FFmpeg/ffprobe concurrency globally so large batch uploads
-            // don't spawn hundreds of FFmpeg processes and stall the pipeline.
-            let ffmpeg_permit = state_clone.ffmpeg_semaphore.acquire().await.unwrap();
+    Ok(Json(UploadAccepted {
+        upload_id,
+        message: "Chunked upload finalized, processing started in background".to_string(),
+    }))
+}
 
-            // Run sequentially to keep the concurrency limit meaningful.
-            let video_duration = get_video_duration(&video_path_clone).await?;
-            let original_height = get_video_height(&video_path_clone).await?;
-            let variants = get_variants_for_height(original_height);
-            let available_resolutions: Vec<String> =
-                variants.iter().map(|v| v.label.clone()).collect();
+/// Re-run the whole pipeline from the archived source. The new revision is encoded
+/// next to the current one, which keeps serving until the metadata swap.
+pub async fn reprocess_video(
+    State(state): State<AppState>,
+    Path(video_id): Path<String>,
+    body: Option<Json<UploadOptions>>,
+) -> Result<Json<UploadAccepted>, (StatusCode, String)> {
+    let video = get_stored_video(&state.db_pool, &video_id)
+        .await
+        .map_err(internal_err)?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Video not found".to_string()))?;
+    let source_key = video.source_key.clone().ok_or_else(|| {
+        (
+            StatusCode::CONFLICT,
+            "Video has no archived source".to_string(),
+        )
+    })?;
+    // Keep the profile the video was encoded with unless the request picks another
+    let mut options = body.map(|Json(options)| options).unwrap_or_default();
+    if options.profile.is_none() {
+        options.profile = video.profile.clone();
+    }
+    check_profile(&state, &options)?;
 
-            let encoding_progress = ProgressUpdate {
-                stage: "FFmpeg processing".to_string(),
+    let upload_id = format!("reprocess-{}", video_id);
+    {
+        let mut progress_map = state.progress.write().await;
+        if video_job_running(&progress_map, &video_id) {
+            return Err((
+                StatusCode::CONFLICT,
+                "Video is already being processed".to_string(),
+            ));
+        }
+        progress_map.insert(
+            upload_id.clone(),
+            ProgressUpdate {
+                stage: "Downloading source".to_string(),
                 current_chunk: 0,
-                total_chunks: variants.len() as u32,
+                total_chunks: 1,
                 percentage: 0,
-                details: Some("Starting encoding...".to_string()),
+                details: Some("Fetching the archived source from storage...".to_string()),
                 status: "processing".to_string(),
                 result: None,
                 error: None,
-                video_name: Some(video_name_clone.clone()),
+                video_name: Some(video.name.clone()),
                 created_at: now_millis(),
                 variant_percentage: None,
-            };
-            update_progress(&state_clone.progress, &upload_id_clone, encoding_progress).await;
-
-            // Get audio streams for multi-audio encoding
-            let audio_streams = get_audio_streams(&video_path_clone)
-                .await
-                .unwrap_or_default();
-
-            encode_to_hls(
-                &video_path_clone,
-                &hls_dir,
-                &state_clone.progress,
-                &upload_id_clone,
-                &state_clone.config.video.encoder,
-                video_duration,
-                &audio_streams,
-            )
-            .await?;
-
-            // Extract subtitles and attachments from the source video
-            let subtitle_streams = get_subtitle_streams(&video_path_clone)
-                .await
-                .unwrap_or_default();
-            let attachment_streams = get_attachments(&video_path_clone).await.unwrap_or_default();
-
-            // Create directories for subtitles and fonts
-            let subtitles_dir = hls_dir.join("subtitles");
-            let fonts_dir = hls_dir.join("fonts");
-
-            if !subtitle_streams.is_empty() {
-                fs::create_dir_all(&subtitles_dir).await?;
-            }
-            if !attachment_streams.is_empty() {
-                fs::create_dir_all(&fonts_dir).await?;
-                // Extract all font attachments
-                extract_all_attachments(&video_path_clone, &fonts_dir).await?;
-            }
-
-            // Extract each subtitle stream
-            for (idx, sub) in subtitle_streams.iter().enumerate() {
-                let ext = match sub.codec_name.as_str() {
-                    "ass" | "ssa" => "ass",
-                    "subrip" | "srt" => "srt",
-                    _ => "ass", // Default to ASS
-                };
-                let sub_filename = format!("track_{}.{}", idx, ext);
-                let sub_path = subtitles_dir.join(&sub_filename);
-
-                // Use enumerate index (idx) as relative subtitle stream index
-                if let Err(e) =
-                    extract_subtitle(&video_path_clone, idx as i32, &sub_path, &sub.codec_name)
-                        .await
-                {
-                    error!(
-                        "Failed to extract subtitle stream {} (track {}): {}",
-                        sub.stream_index, idx, e
-                    );
-                }
-            }
+                encode_speed: None,
+                eta_seconds: None,
+            },
+        );
+    }
 
-            // Release FFmpeg permit before network/upload work.
-            drop(ffmpeg_permit);
+    let current_thumbnail = format!("{}thumbnail.jpg", storage_prefix(&video_id, video.revision));
+    let reprocess = Reprocess {
+        video_id: video_id.clone(),
+        revision: video.revision + 1,
+        custom_thumbnail: (video.thumbnail_key != current_thumbnail)
+            .then(|| video.thumbnail_key.clone()),
+    };
 
-            let upload_progress = ProgressUpdate {
-                stage: "Upload to R2".to_string(),
+    let task_state = state.clone();
+    let task_upload_id = upload_id.clone();
+    tokio::spawn(async move {
+        let ext = source_key
+            .rsplit_once('.')
+            .map(|(_, ext)| ext)
+            .unwrap_or("bin");
+        let video_path = std::env::temp_dir().join(format!("{}-source.{}", Uuid::new_v4(), ext));
+        if let Err(e) = download_from_r2(&task_state, &source_key, &video_path).await {
+            error!("Failed to download source of {}: {:?}", video_id, e);
+            let _ = fs::remove_file(&video_path).await;
+            let error_progress = ProgressUpdate {
+                stage: "Failed".to_string(),
                 current_chunk: 0,
                 total_chunks: 1,
                 percentage: 0,
-                details: Some("Uploading segments to storage...".to_string()),
-                status: "processing".to_string(),
+                details: Some(format!("Source download failed: {}", e)),
+                status: "failed".to_string(),
                 result: None,
-                error: None,
-                video_name: Some(video_name_clone.clone()),
+                error: Some(e.to_string()),
+                video_name: Some(video.name.clone()),
                 created_at: now_millis(),
                 variant_percentage: None,
+                encode_speed: None,
+                eta_seconds: None,
             };
-            update_progress(&state_clone.progress, &upload_id_clone, upload_progress).await;
-
-            let prefix = format!("{}/", output_id);
-            let playlist_key =
-                upload_hls_to_r2(&state_clone, &hls_dir, &prefix, Some(&upload_id_clone)).await?;
-
-            let thumbnail_key = format!("{}/thumbnail.jpg", output_id);
-            let sprites_key = format!("{}/sprites.jpg", output_id);
-            let entrypoint = playlist_key.clone();
-
-            save_video(
-                &state_clone.db_pool,
-                &output_id,
-                &video_name_clone,
-                &tags_clone,
-                &available_resolutions,
-                video_duration,
-                &thumbnail_key,
-                &sprites_key,
-                &entrypoint,
-            )
-            .await?;
-
-            // Save subtitle metadata to database
-            for (idx, sub) in subtitle_streams.iter().enumerate() {
-                let ext = match sub.codec_name.as_str() {
-                    "ass" | "ssa" => "ass",
-                    "subrip" | "srt" => "srt",
-                    _ => "ass",
-                };
-                let storage_key = format!("{}/subtitles/track_{}.{}", output_id, idx, ext);
-
-                if let Err(e) = save_subtitle(
-                    &state_clone.db_pool,
-                    &output_id,
-                    idx as i32,
-                    sub.language.as_deref(),
-                    sub.title.as_deref(),
-                    &sub.codec_name,
-                    &storage_key,
-                    None, // idx_storage_key for VobSub
-                    sub.is_default,
-                    sub.is_forced,
-                )
-                .await
-                {
-                    error!("Failed to save subtitle metadata for track {}: {}", idx, e);
-                }
-            }
-
-            // Save attachment metadata to database
-            for att in &attachment_streams {
-                let storage_key = format!("{}/fonts/{}", output_id, att.filename);
-
-                if let Err(e) = save_attachment(
-                    &state_clone.db_pool,
-                    &output_id,
-                    &att.filename,
-                    &att.mimetype,
-                    &storage_key,
-                )
-                .await
-                {
-                    error!(
-                        "Failed to save attachment metadata for {}: {}",
-                        att.filename, e
-                    );
-                }
-            }
-
-            // Extract and save chapters from video
-            let chapter_streams = get_chapters(&video_path_clone).await.unwrap_or_default();
-            for (idx, chapter) in chapter_streams.iter().enumerate() {
-                if let Err(e) = save_chapter(
-                    &state_clone.db_pool,
-                    &output_id,
-                    idx as i32,
-                    chapter.start_time,
-                    chapter.end_time,
-                    &chapter.title,
-                )
-                .await
-                {
-                    error!("Failed to save chapter metadata for index {}: {}", idx, e);
-                }
-            }
-
-            let _ = fs::remove_file(&video_path_clone).await;
-            let _ = fs::remove_dir_all(&hls_dir).await;
-
-            let player_url = format!("/player/{}", output_id);
-            Ok::<_, anyhow::Error>(UploadResponse {
-                player_url,
-                upload_id: upload_id_clone.clone(),
-            })
-        }
-        .await;
-
-        match result {
-            Ok(response) => {
-                let completion_progress = ProgressUpdate {
-                    stage: "Completed".to_string(),
-                    current_chunk: 1,
-                    total_chunks: 1,
-                    percentage: 100,
-                    details: Some("Upload and processing complete".to_string()),
-                    status: "completed".to_string(),
-                    result: Some(response),
-                    error: None,
-                    video_name: Some(video_name_clone.clone()),
-                    created_at: now_millis(),
-                    variant_percentage: None,
-                };
-                update_progress(&state_clone.progress, &upload_id_clone, completion_progress).await;
-            }
-            Err(e) => {
-                error!("Background processing failed: {:?}", e);
-                let error_progress = ProgressUpdate {
-                    stage: "Failed".to_string(),
-                    current_chunk: 0,
-                    total_chunks: 1,
-                    percentage: 0,
-                    details: Some(format!("Processing failed: {}", e)),
-                    status: "failed".to_string(),
-                    result: None,
-                    error: Some(e.to_string()),
-                    video_name: Some(video_name_clone.clone()),
-                    created_at: now_millis(),
-                    variant_percentage: None,
-                };
-                update_progress(&state_clone.progress, &upload_id_clone, error_progress).await;
-            }
+            update_progress(&task_state.progress, &task_upload_id, error_progress).await;
+            return;
         }
 
-        tokio::time::sleep(Duration::from_secs(10)).await;
-        let mut progress_map = state_clone.progress.write().await;
-        if let Some(entry) = progress_map.get(&upload_id_clone)
-            && (entry.status == "completed" || entry.status == "failed")
-        {
-            progress_map.remove(&upload_id_clone);
-        }
+        spawn_processing(
+            task_state,
+            task_upload_id,
+            video_path,
+            video.name,
+            video.tags,
+            options,
+            Some(reprocess),
+        );
     });
 
     Ok(Json(UploadAccepted {
         upload_id,
-        message: "Chunked upload finalized, processing started in background".to_string(),
+        message: "Reprocessing started in background".to_string(),
     }))
 }
 
@@ -1068,6 +1450,8 @@ pub async fn list_queues(State(state): State<AppState>) -> Json<QueueListRespons
             video_name: p.video_name.clone(),
             created_at: p.created_at,
             variant_percentage: p.variant_percentage,
+            encode_speed: p.encode_speed,
+            eta_seconds: p.eta_seconds,
         })
         .collect();
 
@@ -1181,6 +1565,7 @@ pub async fn cancel_queue(
             "Initializing upload",
             "Queued for processing",
             "Receiving chunks",
+            "Waiting for a worker",
         ];
         let is_cancellable = progress.status == "initializing"
             || (progress.status == "processing"
@@ -1209,11 +1594,25 @@ pub async fn cancel_queue(
             video_name: progress.video_name.clone(),
             created_at: progress.created_at,
             variant_percentage: None,
+            encode_speed: None,
+            eta_seconds: None,
         };
         progress_map.insert(upload_id.clone(), cancelled_progress);
 
         // Also clean up any chunked upload data if it exists
         drop(progress_map); // Release the lock before acquiring another
+
+        // Dropping an unclaimed remote job fails the upload waiting on it
+        let mut remote_jobs = state.remote_jobs.write().await;
+        if remote_jobs
+            .get(&upload_id)
+            .is_some_and(|job| job.lease.is_none())
+        {
+            remote_jobs.remove(&upload_id);
+            info!("Removed queued remote job {}", upload_id);
+        }
+        drop(remote_jobs);
+
         let mut chunked_uploads = state.chunked_uploads.write().await;
         if let Some(chunked) = chunked_uploads.remove(&upload_id) {
             // Clean up temp directory
@@ -1270,6 +1669,9 @@ pub async fn get_progress(
                     status: p.status.clone(),
                     result: p.result.clone(),
                     error: p.error.clone(),
+                    variant_percentage: p.variant_percentage,
+                    encode_speed: p.encode_speed,
+                    eta_seconds: p.eta_seconds,
                 })
                 .unwrap_or_default();
 
@@ -1362,15 +1764,15 @@ pub async fn cleanup_uploads(
     // Also clean up temp directories on disk that don't have corresponding entries
     if let Ok(mut entries) = tokio::fs::read_dir(std::env::temp_dir()).await {
         while let Ok(Some(entry)) = entries.next_entry().await {
-            if let Some(name) = entry.file_name().to_str() {
-                if name.starts_with("chunked-") {
-                    let upload_id = name.trim_start_matches("chunked-");
-                    let uploads = state.chunked_uploads.read().await;
-                    if !uploads.contains_key(upload_id) {
-                        let _ = fs::remove_dir_all(entry.path()).await;
-                        cleaned_uploads += 1;
-                        info!("Cleaned up orphaned temp directory: {}", name);
-                    }
+            if let Some(name) = entry.file_name().to_str()
+                && name.starts_with("chunked-")
+            {
+                let upload_id = name.trim_start_matches("chunked-");
+                let uploads = state.chunked_uploads.read().await;
+                if !uploads.contains_key(upload_id) {
+                    let _ = fs::remove_dir_all(entry.path()).await;
+                    cleaned_uploads += 1;
+                    info!("Cleaned up orphaned temp directory: {}", name);
                 }
             }
         }
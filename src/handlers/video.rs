@@ -1,18 +1,31 @@
 use crate::clickhouse;
 use crate::database::{
-    count_videos, delete_videos as db_delete_videos, get_video_ids_with_prefix,
-    list_videos as db_list_videos, update_video as db_update_video,
+    count_videos, delete_videos as db_delete_videos, get_markers_for_video,
+    get_renditions_for_video, get_stored_video, get_thumbnail_key, get_video_ids_with_prefix,
+    get_video_revision, list_videos as db_list_videos, set_markers, update_thumbnail_key,
+    update_video as db_update_video,
 };
-use crate::handlers::common::internal_err;
-use crate::types::{AppState, VideoListResponse, VideoQuery};
+use crate::handlers::common::{internal_err, now_millis};
+use crate::handlers::upload::{expire_progress, update_progress, video_job_running};
+use crate::markers::detect_series_markers;
+use crate::renditions::{add_rendition_tier, remove_rendition_tier, tier_height};
+use crate::storage::storage_prefix;
+use crate::types::{
+    AddRenditionRequest, AppState, DetectMarkersRequest, DetectMarkersResponse, MarkerListResponse,
+    ProgressUpdate, RemoveRenditionResponse, RenditionListResponse, SetMarkersRequest,
+    ThumbnailCandidate, ThumbnailListResponse, UpdateThumbnailResponse, UploadAccepted,
+    VideoListResponse, VideoQuery,
+};
+use crate::video::tier_label;
 
+use aws_sdk_s3::primitives::ByteStream;
 use axum::{
     Json,
-    extract::{Path, Query, State},
+    extract::{Multipart, Path, Query, State},
     http::StatusCode,
 };
 use std::collections::HashMap;
-use tracing::info;
+use tracing::{error, info, warn};
 
 #[derive(serde::Deserialize)]
 pub struct UpdateVideoRequest {
@@ -181,3 +194,417 @@ pub async fn update_video(
 
     Ok(StatusCode::OK)
 }
+
+/// List the encoded renditions of a video with the inputs behind their bitrates
+pub async fn get_video_renditions(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<RenditionListResponse>, (StatusCode, String)> {
+    let items = get_renditions_for_video(&state.db_pool, &id)
+        .await
+        .map_err(internal_err)?;
+
+    Ok(Json(RenditionListResponse { items }))
+}
+
+/// Encode one more ladder tier for an existing video in the background
+pub async fn add_video_rendition(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(body): Json<AddRenditionRequest>,
+) -> Result<Json<UploadAccepted>, (StatusCode, String)> {
+    let video = get_stored_video(&state.db_pool, &id)
+        .await
+        .map_err(internal_err)?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Video not found".to_string()))?;
+    let label = body.label.trim().to_string();
+    let height = tier_height(&label);
+    if height == 0 || tier_label(height) != label {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Invalid rendition label: {}", label),
+        ));
+    }
+    if video.available_resolutions.contains(&label) {
+        return Err((StatusCode::CONFLICT, format!("Video already has {}", label)));
+    }
+
+    let upload_id = format!("rendition-{}", id);
+    {
+        let mut progress_map = state.progress.write().await;
+        if video_job_running(&progress_map, &id) {
+            return Err((
+                StatusCode::CONFLICT,
+                "Video is already being processed".to_string(),
+            ));
+        }
+        progress_map.insert(
+            upload_id.clone(),
+            ProgressUpdate {
+                stage: "Preparing source".to_string(),
+                current_chunk: 0,
+                total_chunks: 1,
+                percentage: 0,
+                details: Some(format!("Adding {}", label)),
+                status: "processing".to_string(),
+                result: None,
+                error: None,
+                video_name: Some(video.name.clone()),
+                created_at: now_millis(),
+                variant_percentage: None,
+                encode_speed: None,
+                eta_seconds: None,
+            },
+        );
+    }
+
+    let task_state = state.clone();
+    let task_upload_id = upload_id.clone();
+    tokio::spawn(async move {
+        let result = add_rendition_tier(&task_state, &task_upload_id, &id, &video, &label).await;
+        let (stage, details, status, error) = match result {
+            Ok(_) => (
+                "Completed",
+                format!("Rendition {} added", label),
+                "completed",
+                None,
+            ),
+            Err(e) => {
+                error!("Adding {} to {} failed: {:?}", label, id, e);
+                (
+                    "Failed",
+                    format!("Adding {} failed: {}", label, e),
+                    "failed",
+                    Some(e.to_string()),
+                )
+            }
+        };
+        let done = status == "completed";
+        update_progress(
+            &task_state.progress,
+            &task_upload_id,
+            ProgressUpdate {
+                stage: stage.to_string(),
+                current_chunk: done as u32,
+                total_chunks: 1,
+                percentage: if done { 100 } else { 0 },
+                details: Some(details),
+                status: status.to_string(),
+                result: None,
+                error,
+                video_name: Some(video.name.clone()),
+                created_at: now_millis(),
+                variant_percentage: None,
+                encode_speed: None,
+                eta_seconds: None,
+            },
+        )
+        .await;
+        expire_progress(&task_state.progress, &task_upload_id).await;
+    });
+
+    Ok(Json(UploadAccepted {
+        upload_id,
+        message: "Rendition encoding started in background".to_string(),
+    }))
+}
+
+/// Drop every rendition of a ladder tier from an existing video
+pub async fn remove_video_rendition(
+    State(state): State<AppState>,
+    Path((id, label)): Path<(String, String)>,
+) -> Result<Json<RemoveRenditionResponse>, (StatusCode, String)> {
+    let video = get_stored_video(&state.db_pool, &id)
+        .await
+        .map_err(internal_err)?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Video not found".to_string()))?;
+    if !video.available_resolutions.contains(&label) {
+        return Err((StatusCode::NOT_FOUND, format!("Video has no {}", label)));
+    }
+    if video.available_resolutions.len() <= 1 {
+        return Err((
+            StatusCode::CONFLICT,
+            "Cannot remove the only rendition".to_string(),
+        ));
+    }
+    if video_job_running(&*state.progress.read().await, &id) {
+        return Err((
+            StatusCode::CONFLICT,
+            "Video is already being processed".to_string(),
+        ));
+    }
+
+    let available_resolutions = remove_rendition_tier(&state, &id, &video, &label)
+        .await
+        .map_err(internal_err)?;
+    info!("Removed {} from video {}", label, id);
+
+    Ok(Json(RemoveRenditionResponse {
+        label,
+        available_resolutions,
+    }))
+}
+
+/// Extension and MIME type of an uploaded poster, detected from its magic bytes
+fn image_type(bytes: &[u8]) -> Option<(&'static str, &'static str)> {
+    if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some(("jpg", "image/jpeg"))
+    } else if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(("png", "image/png"))
+    } else if bytes.len() >= 12 && bytes.starts_with(b"RIFF") && &bytes[8..12] == b"WEBP" {
+        Some(("webp", "image/webp"))
+    } else {
+        None
+    }
+}
+
+/// List the thumbnail candidates picked while encoding, next to the current thumbnail
+pub async fn get_thumbnail_candidates(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<ThumbnailListResponse>, (StatusCode, String)> {
+    let thumbnail_key = get_thumbnail_key(&state.db_pool, &id)
+        .await
+        .map_err(internal_err)?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Video not found".to_string()))?;
+
+    let revision = get_video_revision(&state.db_pool, &id)
+        .await
+        .map_err(internal_err)?
+        .unwrap_or_default();
+    let prefix = format!("{}thumbnails/", storage_prefix(&id, revision));
+    let list_resp = state
+        .s3
+        .list_objects_v2()
+        .bucket(&state.config.r2.bucket)
+        .prefix(&prefix)
+        .send()
+        .await
+        .map_err(|e| internal_err(anyhow::anyhow!(e)))?;
+
+    let base = state.config.r2.public_base_url.trim_end_matches('/');
+    let mut candidates: Vec<ThumbnailCandidate> = list_resp
+        .contents
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|obj| {
+            let key = obj.key?;
+            let index = key
+                .strip_prefix(&prefix)?
+                .strip_prefix("candidate_")?
+                .strip_suffix(".jpg")?
+                .parse()
+                .ok()?;
+            Some(ThumbnailCandidate {
+                index,
+                url: format!("{}/{}", base, key),
+            })
+        })
+        .collect();
+    candidates.sort_by_key(|c| c.index);
+
+    Ok(Json(ThumbnailListResponse {
+        thumbnail_url: format!("{}/{}", base, thumbnail_key),
+        candidates,
+    }))
+}
+
+/// Replace the thumbnail with a candidate (`candidate` field) or an uploaded image (`file`).
+/// The new object gets a fresh key so cached copies of the old one are never served,
+/// then the old object is deleted.
+pub async fn update_thumbnail(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    mut multipart: Multipart,
+) -> Result<Json<UpdateThumbnailResponse>, (StatusCode, String)> {
+    let old_key = get_thumbnail_key(&state.db_pool, &id)
+        .await
+        .map_err(internal_err)?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Video not found".to_string()))?;
+
+    let mut candidate: Option<u32> = None;
+    let mut image: Option<Vec<u8>> = None;
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| internal_err(anyhow::anyhow!(e)))?
+    {
+        let field_name = field.name().map(|s| s.to_string());
+
+        match field_name.as_deref() {
+            Some("candidate") => {
+                let text = field
+                    .text()
+                    .await
+                    .map_err(|e| internal_err(anyhow::anyhow!(e)))?;
+                candidate = Some(
+                    text.trim()
+                        .parse()
+                        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid candidate".to_string()))?,
+                );
+            }
+            Some("file") => {
+                image = Some(
+                    field
+                        .bytes()
+                        .await
+                        .map_err(|e| internal_err(anyhow::anyhow!(e)))?
+                        .to_vec(),
+                );
+            }
+            _ => continue,
+        }
+    }
+
+    let bucket = &state.config.r2.bucket;
+    let suffix = hex::encode(rand::random::<[u8; 4]>());
+    let new_key = match (candidate, image) {
+        (Some(index), None) => {
+            let revision = get_video_revision(&state.db_pool, &id)
+                .await
+                .map_err(internal_err)?
+                .unwrap_or_default();
+            let source = format!(
+                "{}thumbnails/candidate_{}.jpg",
+                storage_prefix(&id, revision),
+                index
+            );
+            state
+                .s3
+                .head_object()
+                .bucket(bucket)
+                .key(&source)
+                .send()
+                .await
+                .map_err(|e| {
+                    if e.as_service_error().is_some_and(|e| e.is_not_found()) {
+                        (
+                            StatusCode::NOT_FOUND,
+                            format!("Thumbnail candidate {} not found", index),
+                        )
+                    } else {
+                        internal_err(anyhow::anyhow!(e))
+                    }
+                })?;
+
+            let key = format!("{}/thumbnail_{}.jpg", id, suffix);
+            state
+                .s3
+                .copy_object()
+                .bucket(bucket)
+                .copy_source(format!("{}/{}", bucket, source))
+                .key(&key)
+                .send()
+                .await
+                .map_err(|e| internal_err(anyhow::anyhow!(e)))?;
+            key
+        }
+        (None, Some(bytes)) => {
+            let (extension, content_type) = image_type(&bytes).ok_or_else(|| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    "Unsupported image, expected JPEG, PNG or WebP".to_string(),
+                )
+            })?;
+
+            let key = format!("{}/thumbnail_{}.{}", id, suffix, extension);
+            state
+                .s3
+                .put_object()
+                .bucket(bucket)
+                .key(&key)
+                .content_type(content_type)
+                .body(ByteStream::from(bytes))
+                .send()
+                .await
+                .map_err(|e| internal_err(anyhow::anyhow!(e)))?;
+            key
+        }
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Send either a 'candidate' index or an image 'file'".to_string(),
+            ));
+        }
+    };
+
+    update_thumbnail_key(&state.db_pool, &id, &new_key)
+        .await
+        .map_err(internal_err)?;
+
+    if let Err(e) = state
+        .s3
+        .delete_object()
+        .bucket(bucket)
+        .key(&old_key)
+        .send()
+        .await
+    {
+        warn!("Failed to delete old thumbnail {}: {}", old_key, e);
+    }
+    info!("Thumbnail of {} replaced: {} -> {}", id, old_key, new_key);
+
+    let base = state.config.r2.public_base_url.trim_end_matches('/');
+    Ok(Json(UpdateThumbnailResponse {
+        thumbnail_url: format!("{}/{}", base, new_key),
+    }))
+}
+
+/// Replace a video's markers with manual ones; detection never overrides these
+pub async fn update_video_markers(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(body): Json<SetMarkersRequest>,
+) -> Result<Json<MarkerListResponse>, (StatusCode, String)> {
+    let existing = get_video_ids_with_prefix(&state.db_pool, std::slice::from_ref(&id))
+        .await
+        .map_err(internal_err)?;
+    if existing.is_empty() {
+        return Err((StatusCode::NOT_FOUND, "Video not found".to_string()));
+    }
+
+    let mut kinds = Vec::new();
+    for marker in &body.markers {
+        if marker.start_time < 0.0 || marker.end_time <= marker.start_time {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Invalid {} range", marker.kind.as_str()),
+            ));
+        }
+        if kinds.contains(&marker.kind) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Duplicate {} marker", marker.kind.as_str()),
+            ));
+        }
+        kinds.push(marker.kind);
+    }
+
+    set_markers(&state.db_pool, &id, &body.markers)
+        .await
+        .map_err(internal_err)?;
+    let markers = get_markers_for_video(&state.db_pool, &id)
+        .await
+        .map_err(internal_err)?;
+
+    Ok(Json(MarkerListResponse { markers }))
+}
+
+/// Re-run intro/credits detection over every episode carrying a series tag
+pub async fn detect_markers(
+    State(state): State<AppState>,
+    Json(body): Json<DetectMarkersRequest>,
+) -> Result<Json<DetectMarkersResponse>, (StatusCode, String)> {
+    let config = &state.config.video.intro_detection;
+    if !body.tag.starts_with(&config.series_tag_prefix) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Series tags start with '{}'", config.series_tag_prefix),
+        ));
+    }
+
+    let response = detect_series_markers(&state.db_pool, &body.tag, config)
+        .await
+        .map_err(internal_err)?;
+    Ok(Json(response))
+}
@@ -0,0 +1,234 @@
+use crate::handlers::common::{internal_err, now_millis};
+use crate::handlers::upload::update_progress;
+use crate::types::{
+    AppState, ClaimedJob, EncodeSpec, EncodedSource, ProgressUpdate, RemoteJob, RemoteJobFailure,
+    RemoteJobResult,
+};
+
+use axum::{
+    Json,
+    body::Body,
+    extract::{Path, State},
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use std::time::Duration;
+use tokio::fs;
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// A claim lapses when its worker hasn't reported for this long; the job is handed out again
+const WORKER_LEASE_MS: u64 = 2 * 60 * 1000;
+
+/// Queue an encode for the remote workers and wait until one of them has uploaded it.
+/// Returns the encode result and the key of the uploaded master playlist, or None when
+/// no worker held the job for `server.worker_claim_timeout`; it is then off the queue.
+pub(crate) async fn run_remote_job(
+    state: &AppState,
+    upload_id: &str,
+    video_name: &str,
+    video_path: &std::path::Path,
+    spec: EncodeSpec,
+) -> anyhow::Result<Option<(EncodedSource, String)>> {
+    let (done, mut result) = oneshot::channel();
+    state.remote_jobs.write().await.insert(
+        upload_id.to_string(),
+        RemoteJob {
+            spec,
+            source: video_path.to_path_buf(),
+            created_at: now_millis(),
+            lease: None,
+            done,
+        },
+    );
+
+    let waiting_progress = ProgressUpdate {
+        stage: "Waiting for a worker".to_string(),
+        current_chunk: 0,
+        total_chunks: 1,
+        percentage: 0,
+        details: Some("Queued for a remote encoding worker...".to_string()),
+        status: "processing".to_string(),
+        result: None,
+        error: None,
+        video_name: Some(video_name.to_string()),
+        created_at: now_millis(),
+        variant_percentage: None,
+        encode_speed: None,
+        eta_seconds: None,
+    };
+    update_progress(&state.progress, upload_id, waiting_progress).await;
+
+    let claim_timeout = state.config.server.worker_claim_timeout;
+    let outcome = loop {
+        if claim_timeout == 0 {
+            break (&mut result).await;
+        }
+        tokio::select! {
+            outcome = &mut result => break outcome,
+            _ = tokio::time::sleep(Duration::from_secs(claim_timeout)) => {}
+        }
+
+        // Give up on the workers once nobody has held the job for a whole window
+        let now = now_millis();
+        let mut jobs = state.remote_jobs.write().await;
+        let unclaimed = jobs.get(upload_id).is_some_and(|job| {
+            job.lease
+                .as_ref()
+                .is_none_or(|(_, seen)| now.saturating_sub(*seen) > WORKER_LEASE_MS)
+        });
+        if unclaimed {
+            jobs.remove(upload_id);
+            warn!(
+                "No worker claimed {} within {}s, encoding it here",
+                upload_id, claim_timeout
+            );
+            return Ok(None);
+        }
+    };
+
+    match outcome {
+        Ok(Ok(result)) => Ok(Some((result.encoded, result.entrypoint))),
+        Ok(Err(error)) => anyhow::bail!("Remote encode failed: {}", error),
+        Err(_) => anyhow::bail!("Remote job was cancelled"),
+    }
+}
+
+/// Upload ID of the job a worker holds the claim `job_id` of, refreshing the claim
+async fn leased_job(state: &AppState, job_id: &str) -> Result<String, (StatusCode, String)> {
+    let mut jobs = state.remote_jobs.write().await;
+    jobs.iter_mut()
+        .find_map(|(upload_id, job)| match &mut job.lease {
+            Some((lease, seen)) if lease == job_id => {
+                *seen = now_millis();
+                Some(upload_id.clone())
+            }
+            _ => None,
+        })
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Job not found".to_string()))
+}
+
+/// Hand the oldest unclaimed (or abandoned) job to a worker; 204 when the queue is empty
+pub async fn claim_job(State(state): State<AppState>) -> Response {
+    let now = now_millis();
+    let mut jobs = state.remote_jobs.write().await;
+    let next = jobs
+        .iter_mut()
+        .filter(|(_, job)| {
+            job.lease
+                .as_ref()
+                .is_none_or(|(_, seen)| now.saturating_sub(*seen) > WORKER_LEASE_MS)
+        })
+        .min_by_key(|(_, job)| job.created_at);
+    let Some((upload_id, job)) = next else {
+        return StatusCode::NO_CONTENT.into_response();
+    };
+
+    if job.lease.is_some() {
+        warn!(
+            "Worker of {} stopped reporting, handing the job out again",
+            upload_id
+        );
+    }
+    let job_id = Uuid::new_v4().to_string();
+    job.lease = Some((job_id.clone(), now));
+    info!("Remote job {} claimed as {}", upload_id, job_id);
+
+    Json(ClaimedJob {
+        job_id,
+        source_extension: job
+            .source
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("bin")
+            .to_string(),
+        spec: job.spec.clone(),
+    })
+    .into_response()
+}
+
+/// Stream the source file of a claimed job
+pub async fn get_job_source(
+    State(state): State<AppState>,
+    Path(job_id): Path<String>,
+) -> Result<Response, (StatusCode, String)> {
+    let upload_id = leased_job(&state, &job_id).await?;
+    let source = state
+        .remote_jobs
+        .read()
+        .await
+        .get(&upload_id)
+        .map(|job| job.source.clone())
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Job not found".to_string()))?;
+
+    let file = fs::File::open(&source)
+        .await
+        .map_err(|e| internal_err(anyhow::anyhow!(e)))?;
+    let content_length = file
+        .metadata()
+        .await
+        .map_err(|e| internal_err(anyhow::anyhow!(e)))?
+        .len();
+    let body = Body::from_stream(tokio_util::io::ReaderStream::new(file));
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::CONTENT_LENGTH, content_length)
+        .body(body)
+        .unwrap())
+}
+
+/// Mirror a worker's progress into the server's progress map; doubles as its heartbeat
+pub async fn report_job_progress(
+    State(state): State<AppState>,
+    Path(job_id): Path<String>,
+    Json(mut update): Json<ProgressUpdate>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let upload_id = leased_job(&state, &job_id).await?;
+    update.video_name = state
+        .progress
+        .read()
+        .await
+        .get(&upload_id)
+        .and_then(|p| p.video_name.clone());
+    update_progress(&state.progress, &upload_id, update).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Take a claimed job off the queue, the caller resolves its waiting upload
+async fn finish_job(state: &AppState, job_id: &str) -> Result<RemoteJob, (StatusCode, String)> {
+    let upload_id = leased_job(state, job_id).await?;
+    state
+        .remote_jobs
+        .write()
+        .await
+        .remove(&upload_id)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Job not found".to_string()))
+}
+
+pub async fn complete_job(
+    State(state): State<AppState>,
+    Path(job_id): Path<String>,
+    Json(result): Json<RemoteJobResult>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let job = finish_job(&state, &job_id).await?;
+    info!("Remote job {} of {} completed", job_id, job.spec.video_id);
+    let _ = job.done.send(Ok(result));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn fail_job(
+    State(state): State<AppState>,
+    Path(job_id): Path<String>,
+    Json(failure): Json<RemoteJobFailure>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let job = finish_job(&state, &job_id).await?;
+    warn!(
+        "Remote job {} of {} failed: {}",
+        job_id, job.spec.video_id, failure.error
+    );
+    let _ = job.done.send(Err(failure.error));
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,864 @@
+use crate::types::{
+    AudioRendition, HlsSegmentType, IFrameEntry, SubtitleRendition, VideoCodec, VideoVariant,
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+
+/// W3C ClearKey DRM system ID (e2719d58-a985-b3c9-781a-b030af78d30e)
+const CLEARKEY_SYSTEM_ID: [u8; 16] = [
+    0xe2, 0x71, 0x9d, 0x58, 0xa9, 0x85, 0xb3, 0xc9, 0x78, 0x1a, 0xb0, 0x30, 0xaf, 0x78, 0xd3, 0x0e,
+];
+
+/// Length of WebVTT subtitle segments in seconds
+pub const SUBTITLE_SEGMENT_SECONDS: u32 = 30;
+
+/// Unsegmented WebVTT kept in each fMP4 subtitle rendition directory for DASH
+pub const SUBTITLE_WEBVTT_FILE: &str = "subtitles.vtt";
+
+/// Subtitle GROUP-ID for a segment type. WebVTT cue times map to a different
+/// media timeline for MPEG-TS and fMP4, so each gets its own segments.
+pub fn subtitle_group_id(segment_type: HlsSegmentType) -> &'static str {
+    match segment_type {
+        HlsSegmentType::MpegTs => "subs",
+        HlsSegmentType::Fmp4 => "subs-fmp4",
+    }
+}
+
+/// 90 kHz timestamp of media time zero, for `X-TIMESTAMP-MAP`.
+/// FFmpeg's MPEG-TS muxer starts at 1.4s (twice the default 0.7s muxdelay).
+pub fn subtitle_mpegts_offset(segment_type: HlsSegmentType) -> u64 {
+    match segment_type {
+        HlsSegmentType::MpegTs => 126_000,
+        HlsSegmentType::Fmp4 => 0,
+    }
+}
+
+/// Audio GROUP-IDs for a segment type: the stereo group and the multichannel group
+pub fn audio_group_ids(segment_type: HlsSegmentType) -> (&'static str, &'static str) {
+    match segment_type {
+        HlsSegmentType::MpegTs => ("audio", "audio-surround"),
+        HlsSegmentType::Fmp4 => ("audio-fmp4", "audio-surround-fmp4"),
+    }
+}
+
+/// H.264 level_idc needed for a frame size and rate (Main profile limits, Table A-1)
+pub fn h264_level_idc(width: u32, height: u32, fps: f64) -> u32 {
+    let frame_mbs = width.div_ceil(16) as u64 * height.div_ceil(16) as u64;
+    let mbs_per_sec = (frame_mbs as f64 * fps).ceil() as u64;
+
+    // (level_idc, max frame size in MBs, max MBs per second)
+    const LEVELS: &[(u32, u64, u64)] = &[
+        (30, 1_620, 40_500),
+        (31, 3_600, 108_000),
+        (32, 5_120, 216_000),
+        (40, 8_192, 245_760),
+        (42, 8_704, 522_240),
+        (50, 22_080, 589_824),
+        (51, 36_864, 983_040),
+        (52, 36_864, 2_073_600),
+    ];
+
+    LEVELS
+        .iter()
+        .find(|(_, max_fs, max_mbps)| frame_mbs <= *max_fs && mbs_per_sec <= *max_mbps)
+        .map(|(idc, _, _)| *idc)
+        .unwrap_or(52)
+}
+
+/// HEVC general_level_idc (level * 30) needed for a frame size and rate
+fn hevc_level_idc(width: u32, height: u32, fps: f64) -> u32 {
+    let luma_ps = width as u64 * height as u64;
+    let luma_sr = (luma_ps as f64 * fps).ceil() as u64;
+
+    const LEVELS: &[(u32, u64, u64)] = &[
+        (90, 552_960, 16_588_800),
+        (93, 983_040, 33_177_600),
+        (120, 2_228_224, 66_846_720),
+        (123, 2_228_224, 133_693_440),
+        (150, 8_912_896, 267_386_880),
+        (153, 8_912_896, 534_773_760),
+        (156, 8_912_896, 1_069_547_520),
+        (180, 35_651_584, 1_069_547_520),
+    ];
+
+    LEVELS
+        .iter()
+        .find(|(_, max_ps, max_sr)| luma_ps <= *max_ps && luma_sr <= *max_sr)
+        .map(|(idc, _, _)| *idc)
+        .unwrap_or(180)
+}
+
+/// AV1 seq_level_idx needed for a frame size and rate (Annex A.3)
+fn av1_seq_level_idx(width: u32, height: u32, fps: f64) -> u32 {
+    let pic_size = width as u64 * height as u64;
+    let sample_rate = (pic_size as f64 * fps).ceil() as u64;
+
+    const LEVELS: &[(u32, u64, u64)] = &[
+        (0, 147_456, 4_423_680),
+        (1, 278_784, 8_363_520),
+        (4, 665_856, 19_975_680),
+        (5, 1_065_024, 31_950_720),
+        (8, 2_359_296, 70_778_880),
+        (9, 2_359_296, 141_557_760),
+        (12, 8_912_896, 267_386_880),
+        (13, 8_912_896, 534_773_760),
+        (14, 8_912_896, 1_069_547_520),
+        (16, 35_651_584, 1_069_547_520),
+    ];
+
+    LEVELS
+        .iter()
+        .find(|(_, max_ps, max_sr)| pic_size <= *max_ps && sample_rate <= *max_sr)
+        .map(|(idx, _, _)| *idx)
+        .unwrap_or(16)
+}
+
+/// RFC 6381 codec string for an encoded video variant (8-bit Main, or HEVC Main10 for HDR)
+pub fn video_codec_string(variant: &VideoVariant) -> String {
+    let (width, height, fps) = (variant.width, variant.height, variant.fps);
+    match variant.codec {
+        VideoCodec::H264 => format!("avc1.4d40{:02x}", h264_level_idc(width, height, fps)),
+        // Main10 is general_profile_idc 2 with compatibility flag 2 set
+        VideoCodec::Hevc if variant.hdr.is_some() => {
+            format!("hvc1.2.4.L{}.B0", hevc_level_idc(width, height, fps))
+        }
+        VideoCodec::Hevc => format!("hvc1.1.6.L{}.B0", hevc_level_idc(width, height, fps)),
+        VideoCodec::Av1 => format!("av01.0.{:02}M.08", av1_seq_level_idx(width, height, fps)),
+    }
+}
+
+/// Build the master playlist for the encoded ladder.
+///
+/// H.264/MPEG-TS variants reference the `audio` groups, CMAF variants reference
+/// the `audio-fmp4` groups when fMP4 audio renditions exist. When a multichannel
+/// group exists every variant is listed twice, once per audio group. Every
+/// variant carries `CODECS` so players can skip what they cannot decode, and
+/// the WebVTT `SUBTITLES` group matching its segment type.
+pub fn build_master_playlist(
+    variants: &[VideoVariant],
+    audio: &[AudioRendition],
+    subtitles: &[SubtitleRendition],
+) -> String {
+    let uses_fmp4 = variants
+        .iter()
+        .any(|v| v.segment_type == HlsSegmentType::Fmp4);
+    let version = if uses_fmp4 { 7 } else { 3 };
+    // VIDEO-RANGE is only needed to tell HDR and SDR variants apart
+    let has_hdr = variants.iter().any(|v| v.hdr.is_some());
+    let mut master_content = format!("#EXTM3U\n#EXT-X-VERSION:{}\n\n", version);
+
+    // Add audio tracks as EXT-X-MEDIA entries
+    if !audio.is_empty() {
+        for rendition in audio {
+            let default = if rendition.is_default { "YES" } else { "NO" };
+            master_content.push_str(&format!(
+                "#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"{}\",LANGUAGE=\"{}\",NAME=\"{}\",DEFAULT={},AUTOSELECT={},CHANNELS=\"{}\",URI=\"{}/index.m3u8\"\n",
+                rendition.group_id,
+                rendition.language,
+                rendition.name,
+                default,
+                default,
+                rendition.channels,
+                rendition.dir
+            ));
+        }
+        master_content.push('\n');
+    }
+
+    // Add text subtitles as EXT-X-MEDIA entries
+    if !subtitles.is_empty() {
+        for rendition in subtitles {
+            let yes_no = |flag: bool| if flag { "YES" } else { "NO" };
+            master_content.push_str(&format!(
+                "#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID=\"{}\",LANGUAGE=\"{}\",NAME=\"{}\",DEFAULT={},AUTOSELECT=YES,FORCED={},URI=\"{}/index.m3u8\"\n",
+                rendition.group_id,
+                rendition.language,
+                rendition.name,
+                yes_no(rendition.is_default),
+                yes_no(rendition.is_forced),
+                rendition.dir
+            ));
+        }
+        master_content.push('\n');
+    }
+
+    // Add video stream variants, one entry per audio group they can play with
+    for variant in variants {
+        let (stereo, surround) = audio_group_ids(variant.segment_type);
+        let groups: Vec<&str> = [stereo, surround]
+            .into_iter()
+            .filter(|group_id| audio.iter().any(|a| a.group_id == *group_id))
+            .collect();
+
+        let subtitle_group = subtitle_group_id(variant.segment_type);
+        let subtitle_attr = if subtitles.iter().any(|s| s.group_id == subtitle_group) {
+            format!(",SUBTITLES=\"{}\"", subtitle_group)
+        } else {
+            String::new()
+        };
+
+        let video_range = if has_hdr {
+            format!(
+                ",VIDEO-RANGE={}",
+                variant.hdr.map(|h| h.video_range()).unwrap_or("SDR")
+            )
+        } else {
+            String::new()
+        };
+
+        let entries: Vec<Option<&str>> = if groups.is_empty() {
+            vec![None]
+        } else {
+            groups.into_iter().map(Some).collect()
+        };
+
+        for group_id in entries {
+            let mut codecs = video_codec_string(variant);
+            let mut bandwidth = variant.bandwidth();
+            let mut audio_group = String::new();
+
+            if let Some(group_id) = group_id {
+                let group: Vec<&AudioRendition> =
+                    audio.iter().filter(|a| a.group_id == group_id).collect();
+                let mut audio_codecs: Vec<&str> = Vec::new();
+                for rendition in &group {
+                    let codec = rendition.codec.codec_string();
+                    if !audio_codecs.contains(&codec) {
+                        audio_codecs.push(codec);
+                    }
+                }
+                for codec in audio_codecs {
+                    codecs.push(',');
+                    codecs.push_str(codec);
+                }
+                bandwidth += group.iter().map(|a| a.bitrate).max().unwrap_or(0) * 1000;
+                audio_group = format!(",AUDIO=\"{}\"", group_id);
+            }
+
+            master_content.push_str(&format!(
+                "#EXT-X-STREAM-INF:BANDWIDTH={},RESOLUTION={}x{},FRAME-RATE={:.3},CODECS=\"{}\"{}{}{}\n",
+                bandwidth,
+                variant.width,
+                variant.height,
+                variant.fps,
+                codecs,
+                video_range,
+                audio_group,
+                subtitle_attr
+            ));
+            master_content.push_str(&format!("{}/index.m3u8\n", variant.dir()));
+        }
+    }
+
+    master_content
+}
+
+/// Version 1 `pssh` box carrying `kid` for the ClearKey system, the EME "cenc" init data
+pub fn clearkey_pssh(kid: &[u8; 16]) -> Vec<u8> {
+    let mut pssh = Vec::with_capacity(52);
+    pssh.extend_from_slice(&52u32.to_be_bytes());
+    pssh.extend_from_slice(b"pssh");
+    pssh.extend_from_slice(&[1, 0, 0, 0]); // version 1, no flags
+    pssh.extend_from_slice(&CLEARKEY_SYSTEM_ID);
+    pssh.extend_from_slice(&1u32.to_be_bytes()); // KID count
+    pssh.extend_from_slice(kid);
+    pssh.extend_from_slice(&0u32.to_be_bytes()); // no system data
+    pssh
+}
+
+/// EXT-X-KEY / EXT-X-SESSION-KEY attributes for CENC segments licensed through ClearKey
+pub fn clearkey_key_attributes(kid: &[u8; 16]) -> String {
+    format!(
+        "METHOD=SAMPLE-AES-CTR,KEYFORMAT=\"urn:uuid:e2719d58-a985-b3c9-781a-b030af78d30e\",KEYFORMATVERSIONS=\"1\",KEYID=0x{},URI=\"data:text/plain;base64,{}\"",
+        hex::encode(kid),
+        STANDARD.encode(clearkey_pssh(kid))
+    )
+}
+
+/// Insert `tag` on its own line before the first line starting with one of `before`
+pub fn insert_tag_before(playlist: &str, before: &[&str], tag: &str) -> String {
+    let mut out = String::with_capacity(playlist.len() + tag.len() + 1);
+    let mut inserted = false;
+    for line in playlist.lines() {
+        if !inserted && before.iter().any(|prefix| line.starts_with(prefix)) {
+            out.push_str(tag);
+            out.push('\n');
+            inserted = true;
+        }
+        out.push_str(line);
+        out.push('\n');
+    }
+    out
+}
+
+/// Tags describing a whole media playlist rather than its segments
+const PLAYLIST_HEADER_TAGS: &[&str] = &[
+    "#EXTM3U",
+    "#EXT-X-VERSION",
+    "#EXT-X-TARGETDURATION",
+    "#EXT-X-MEDIA-SEQUENCE",
+    "#EXT-X-PLAYLIST-TYPE",
+    "#EXT-X-INDEPENDENT-SEGMENTS",
+    "#EXT-X-ENDLIST",
+];
+
+/// Join consecutive VOD media playlists of one rendition into one. The header of the
+/// first playlist is kept with the largest `TARGETDURATION`; `EXT-X-MAP` and
+/// `EXT-X-KEY` are only repeated where they change.
+pub fn join_media_playlists(playlists: &[String]) -> String {
+    let mut header: Vec<&str> = Vec::new();
+    let mut body = String::new();
+    let mut target_duration = 0u32;
+    let mut last_map: Option<&str> = None;
+    let mut last_key: Option<&str> = None;
+
+    for (idx, playlist) in playlists.iter().enumerate() {
+        for line in playlist.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            if let Some(value) = line.strip_prefix("#EXT-X-TARGETDURATION:") {
+                target_duration = target_duration.max(value.trim().parse().unwrap_or(0));
+            }
+            if PLAYLIST_HEADER_TAGS.iter().any(|tag| line.starts_with(tag)) {
+                if idx == 0 && !line.starts_with("#EXT-X-ENDLIST") {
+                    header.push(line);
+                }
+                continue;
+            }
+
+            let last = if line.starts_with("#EXT-X-MAP") {
+                &mut last_map
+            } else if line.starts_with("#EXT-X-KEY") {
+                &mut last_key
+            } else {
+                body.push_str(line);
+                body.push('\n');
+                continue;
+            };
+            if *last != Some(line) {
+                body.push_str(line);
+                body.push('\n');
+                *last = Some(line);
+            }
+        }
+    }
+
+    let mut out = String::with_capacity(body.len() + 256);
+    for line in header {
+        if line.starts_with("#EXT-X-TARGETDURATION") {
+            out.push_str(&format!("#EXT-X-TARGETDURATION:{}\n", target_duration));
+        } else {
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    out.push_str(&body);
+    out.push_str("#EXT-X-ENDLIST\n");
+    out
+}
+
+/// Parse a WebVTT timestamp (`HH:MM:SS.mmm` or `MM:SS.mmm`) into seconds
+fn parse_vtt_timestamp(value: &str) -> Option<f64> {
+    let mut seconds = 0.0;
+    for part in value.trim().split(':') {
+        seconds = seconds * 60.0 + part.parse::<f64>().ok()?;
+    }
+    Some(seconds)
+}
+
+/// Split a WebVTT file into segments of `segment_seconds`.
+///
+/// Each segment repeats the header with an `X-TIMESTAMP-MAP` and carries every
+/// cue overlapping its time range; cue times stay absolute.
+pub fn segment_webvtt(
+    vtt: &str,
+    duration: f64,
+    segment_seconds: u32,
+    mpegts_offset: u64,
+) -> Vec<String> {
+    let vtt = vtt.replace("\r\n", "\n");
+    // Cue blocks are the blank-line separated blocks with a timing line
+    let cues: Vec<(f64, f64, &str)> = vtt
+        .split("\n\n")
+        .filter_map(|block| {
+            let block = block.trim_matches('\n');
+            let timing = block.lines().find(|line| line.contains("-->"))?;
+            let (start, rest) = timing.split_once("-->")?;
+            let end = rest.split_whitespace().next()?;
+            Some((
+                parse_vtt_timestamp(start)?,
+                parse_vtt_timestamp(end)?,
+                block,
+            ))
+        })
+        .collect();
+
+    let count = ((duration / segment_seconds as f64).ceil() as usize).max(1);
+    (0..count)
+        .map(|i| {
+            let start = (i as u32 * segment_seconds) as f64;
+            let end = start + segment_seconds as f64;
+            let mut segment = format!(
+                "WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:{},LOCAL:00:00:00.000\n",
+                mpegts_offset
+            );
+            for (cue_start, cue_end, block) in &cues {
+                if *cue_start < end && *cue_end > start {
+                    segment.push('\n');
+                    segment.push_str(block);
+                    segment.push('\n');
+                }
+            }
+            segment
+        })
+        .collect()
+}
+
+/// Media playlist for segments written by `segment_webvtt`
+pub fn build_subtitle_playlist(
+    segment_count: usize,
+    duration: f64,
+    segment_seconds: u32,
+) -> String {
+    let mut playlist = format!(
+        "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n",
+        segment_seconds
+    );
+    for i in 0..segment_count {
+        let start = (i as u32 * segment_seconds) as f64;
+        let length = (duration - start).clamp(0.001, segment_seconds as f64);
+        playlist.push_str(&format!("#EXTINF:{:.3},\nsegment_{:03}.vtt\n", length, i));
+    }
+    playlist.push_str("#EXT-X-ENDLIST\n");
+    playlist
+}
+
+/// `(duration, uri)` of every segment listed in a media playlist
+pub fn playlist_segments(playlist: &str) -> Vec<(f64, String)> {
+    let mut segments = Vec::new();
+    let mut duration: Option<f64> = None;
+    for line in playlist.lines().map(str::trim) {
+        if let Some(rest) = line.strip_prefix("#EXTINF:") {
+            duration = rest.split(',').next().and_then(|d| d.trim().parse().ok());
+        } else if !line.is_empty()
+            && !line.starts_with('#')
+            && let Some(d) = duration.take()
+        {
+            segments.push((d, line.to_string()));
+        }
+    }
+    segments
+}
+
+/// I-frame-only media playlist addressing keyframes by byte range. Each segment's
+/// program tables are referenced by an `EXT-X-MAP`, so every range decodes on its own.
+pub fn build_iframe_playlist(entries: &[IFrameEntry]) -> String {
+    let target = entries
+        .iter()
+        .map(|e| e.duration.ceil() as u32)
+        .max()
+        .unwrap_or(1)
+        .max(1);
+    let mut playlist = format!(
+        "#EXTM3U\n#EXT-X-VERSION:5\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n#EXT-X-I-FRAMES-ONLY\n",
+        target
+    );
+    let mut map: Option<(&str, u64)> = None;
+    for entry in entries {
+        let entry_map = (entry.uri.as_str(), entry.tables_length);
+        if entry.tables_length > 0 && map != Some(entry_map) {
+            playlist.push_str(&format!(
+                "#EXT-X-MAP:URI=\"{}\",BYTERANGE=\"{}@0\"\n",
+                entry.uri, entry.tables_length
+            ));
+            map = Some(entry_map);
+        }
+        playlist.push_str(&format!(
+            "#EXTINF:{:.6},\n#EXT-X-BYTERANGE:{}@{}\n{}\n",
+            entry.duration, entry.length, entry.offset, entry.uri
+        ));
+    }
+    playlist.push_str("#EXT-X-ENDLIST\n");
+    playlist
+}
+
+/// Master playlist entry advertising the I-frame playlist of a variant
+pub fn iframe_stream_inf(variant: &VideoVariant, bandwidth: u32) -> String {
+    format!(
+        "#EXT-X-I-FRAME-STREAM-INF:BANDWIDTH={},RESOLUTION={}x{},CODECS=\"{}\",URI=\"{}/iframes.m3u8\"\n",
+        bandwidth,
+        variant.width,
+        variant.height,
+        video_codec_string(variant),
+        variant.dir()
+    )
+}
+
+/// Split a tag's attribute list on the commas outside quoted strings.
+/// Values keep their quotes so the list can be joined back unchanged.
+fn split_attributes(list: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in list.chars().chain(std::iter::once(',')) {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.push(c);
+            }
+            ',' if !quoted => {
+                if let Some((name, value)) = current.split_once('=') {
+                    attributes.push((name.to_string(), value.to_string()));
+                }
+                current.clear();
+            }
+            _ => current.push(c),
+        }
+    }
+    attributes
+}
+
+fn join_attributes(attributes: &[(String, String)]) -> String {
+    attributes
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Whether a playlist URI points into one of the rendition directories `dirs`
+fn in_dirs(uri: &str, dirs: &[String]) -> bool {
+    dirs.iter().any(|dir| {
+        uri.strip_prefix(dir.as_str())
+            .is_some_and(|rest| rest.starts_with('/'))
+    })
+}
+
+/// Master playlist without the variants stored in `dirs` and their I-frame playlists
+pub fn remove_master_variants(master: &str, dirs: &[String]) -> String {
+    let mut out = String::with_capacity(master.len());
+    let mut lines = master.lines();
+    while let Some(line) = lines.next() {
+        if line.starts_with("#EXT-X-STREAM-INF:") {
+            let uri = lines.next().unwrap_or_default();
+            if !in_dirs(uri, dirs) {
+                out.push_str(line);
+                out.push('\n');
+                out.push_str(uri);
+                out.push('\n');
+            }
+            continue;
+        }
+        if let Some(list) = line.strip_prefix("#EXT-X-I-FRAME-STREAM-INF:")
+            && split_attributes(list)
+                .iter()
+                .any(|(name, value)| name == "URI" && in_dirs(value.trim_matches('"'), dirs))
+        {
+            continue;
+        }
+        out.push_str(line);
+        out.push('\n');
+    }
+    out
+}
+
+/// Master playlist with `variant` added next to an existing rendition of the same
+/// family. The new entries copy the audio and subtitle groups of `template_dir`'s
+/// entries, with the template's video bandwidth swapped for the variant's.
+/// None when `template_dir` isn't listed.
+pub fn add_master_variant(
+    master: &str,
+    template_dir: &str,
+    template_bandwidth: u32,
+    variant: &VideoVariant,
+) -> Option<String> {
+    let lines: Vec<&str> = master.lines().collect();
+    let template = [template_dir.to_string()];
+    let mut entries = String::new();
+    let mut last_entry = None;
+    for (idx, pair) in lines.windows(2).enumerate() {
+        let Some(list) = pair[0].strip_prefix("#EXT-X-STREAM-INF:") else {
+            continue;
+        };
+        last_entry = Some(idx + 1);
+        if !in_dirs(pair[1], &template) {
+            continue;
+        }
+
+        let mut attributes = split_attributes(list);
+        for (name, value) in attributes.iter_mut() {
+            match name.as_str() {
+                "BANDWIDTH" => {
+                    let audio = value
+                        .parse::<u32>()
+                        .unwrap_or(0)
+                        .saturating_sub(template_bandwidth);
+                    *value = (audio + variant.bandwidth()).to_string();
+                }
+                "RESOLUTION" => *value = format!("{}x{}", variant.width, variant.height),
+                "FRAME-RATE" => *value = format!("{:.3}", variant.fps),
+                "CODECS" => {
+                    // The video codec comes first, audio codecs follow
+                    let codecs = value.trim_matches('"');
+                    let audio = codecs.split_once(',').map(|(_, audio)| audio);
+                    let video = video_codec_string(variant);
+                    *value = match audio {
+                        Some(audio) => format!("\"{},{}\"", video, audio),
+                        None => format!("\"{}\"", video),
+                    };
+                }
+                _ => {}
+            }
+        }
+        entries.push_str(&format!(
+            "#EXT-X-STREAM-INF:{}\n{}/index.m3u8\n",
+            join_attributes(&attributes),
+            variant.dir()
+        ));
+    }
+    if entries.is_empty() {
+        return None;
+    }
+
+    // New entries go after the last variant, ahead of any I-frame playlists
+    let split = last_entry? + 1;
+    let mut out = String::with_capacity(master.len() + entries.len());
+    for line in &lines[..split] {
+        out.push_str(line);
+        out.push('\n');
+    }
+    out.push_str(&entries);
+    for line in &lines[split..] {
+        out.push_str(line);
+        out.push('\n');
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASTER: &str = "#EXTM3U\n\
+#EXT-X-VERSION:7\n\
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"audio\",NAME=\"English\",URI=\"audio_0/index.m3u8\"\n\
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"audio-mc\",NAME=\"English 5.1\",URI=\"audio_0_mc/index.m3u8\"\n\
+#EXT-X-STREAM-INF:BANDWIDTH=2628000,RESOLUTION=1280x720,CODECS=\"avc1.4d401f,mp4a.40.2\",AUDIO=\"audio\"\n\
+720p/index.m3u8\n\
+#EXT-X-STREAM-INF:BANDWIDTH=2884000,RESOLUTION=1280x720,CODECS=\"avc1.4d401f,mp4a.40.2\",AUDIO=\"audio-mc\"\n\
+720p/index.m3u8\n\
+#EXT-X-STREAM-INF:BANDWIDTH=5128000,RESOLUTION=1920x1080,CODECS=\"avc1.4d4028,mp4a.40.2\",AUDIO=\"audio\"\n\
+1080p/index.m3u8\n\
+#EXT-X-STREAM-INF:BANDWIDTH=5384000,RESOLUTION=1920x1080,CODECS=\"avc1.4d4028,mp4a.40.2\",AUDIO=\"audio-mc\"\n\
+1080p/index.m3u8\n\
+#EXT-X-I-FRAME-STREAM-INF:BANDWIDTH=250000,RESOLUTION=1280x720,CODECS=\"avc1.4d401f\",URI=\"720p/iframes.m3u8\"\n\
+#EXT-X-I-FRAME-STREAM-INF:BANDWIDTH=500000,RESOLUTION=1920x1080,CODECS=\"avc1.4d4028\",URI=\"1080p/iframes.m3u8\"\n";
+
+    #[test]
+    fn test_remove_master_variants_drops_every_entry_of_the_tier() {
+        let result = remove_master_variants(MASTER, &["1080p".to_string()]);
+        assert!(!result.contains("1080p"));
+        assert!(!result.contains("RESOLUTION=1920x1080"));
+        assert_eq!(result.matches("720p/index.m3u8").count(), 2);
+        assert!(result.contains("URI=\"720p/iframes.m3u8\""));
+        assert!(result.contains("GROUP-ID=\"audio-mc\""));
+    }
+
+    #[test]
+    fn test_remove_master_variants_matches_whole_directories() {
+        let result = remove_master_variants(MASTER, &["720".to_string()]);
+        assert_eq!(result, MASTER);
+    }
+
+    #[test]
+    fn test_add_master_variant_copies_the_template_groups() {
+        let without = remove_master_variants(MASTER, &["1080p".to_string()]);
+        let variant = VideoVariant::new("1080p", 1920, 1080, 30.0);
+        let template_bandwidth = 2_500_000;
+        let result = add_master_variant(&without, "720p", template_bandwidth, &variant).unwrap();
+
+        let lines: Vec<&str> = result.lines().collect();
+        let added: Vec<usize> = lines
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| **line == "1080p/index.m3u8")
+            .map(|(idx, _)| idx - 1)
+            .collect();
+        assert_eq!(added.len(), 2);
+
+        let codecs = format!("CODECS=\"{},mp4a.40.2\"", video_codec_string(&variant));
+        for (idx, group, audio) in [
+            (added[0], "audio", 128_000),
+            (added[1], "audio-mc", 384_000),
+        ] {
+            let entry = lines[idx];
+            assert!(entry.contains(&format!("BANDWIDTH={},", audio + variant.bandwidth())));
+            assert!(entry.contains("RESOLUTION=1920x1080"));
+            assert!(entry.contains(&codecs));
+            assert!(entry.contains(&format!("AUDIO=\"{}\"", group)));
+        }
+
+        // Added after the last variant, ahead of the I-frame playlists
+        let iframes = lines
+            .iter()
+            .position(|line| line.starts_with("#EXT-X-I-FRAME-STREAM-INF"))
+            .unwrap();
+        assert_eq!(added[1] + 2, iframes);
+    }
+
+    /// Media playlist of one range as FFmpeg writes it for `-start_number first`
+    fn range_playlist(first: u32, durations: &[f64], key: Option<&str>) -> String {
+        let target = durations
+            .iter()
+            .map(|d| d.round() as u32)
+            .max()
+            .unwrap_or(0);
+        let mut playlist = format!(
+            "#EXTM3U\n#EXT-X-VERSION:7\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:{}\n#EXT-X-PLAYLIST-TYPE:VOD\n#EXT-X-MAP:URI=\"init.mp4\"\n",
+            target, first
+        );
+        if let Some(key) = key {
+            playlist.push_str(key);
+            playlist.push('\n');
+        }
+        for (n, duration) in durations.iter().enumerate() {
+            playlist.push_str(&format!(
+                "#EXTINF:{:.6},\nsegment_{:03}.m4s\n",
+                duration,
+                first as usize + n
+            ));
+        }
+        playlist.push_str("#EXT-X-ENDLIST\n");
+        playlist
+    }
+
+    #[test]
+    fn test_join_media_playlists_keeps_one_header_and_map() {
+        let playlists = [
+            range_playlist(0, &[4.0, 4.0], None),
+            range_playlist(2, &[4.0, 5.0], None),
+            range_playlist(4, &[2.5], None),
+        ];
+        let joined = join_media_playlists(&playlists);
+
+        assert!(joined.starts_with(
+            "#EXTM3U\n#EXT-X-VERSION:7\n#EXT-X-TARGETDURATION:5\n#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n#EXT-X-MAP:URI=\"init.mp4\"\n#EXTINF"
+        ));
+        assert_eq!(joined.matches("#EXTM3U").count(), 1);
+        assert_eq!(joined.matches("#EXT-X-MAP").count(), 1);
+        assert_eq!(joined.matches("#EXT-X-MEDIA-SEQUENCE").count(), 1);
+        assert_eq!(joined.matches("#EXT-X-ENDLIST").count(), 1);
+        assert!(joined.ends_with("segment_004.m4s\n#EXT-X-ENDLIST\n"));
+
+        let segments = playlist_segments(&joined);
+        let uris: Vec<&str> = segments.iter().map(|(_, uri)| uri.as_str()).collect();
+        assert_eq!(
+            uris,
+            [
+                "segment_000.m4s",
+                "segment_001.m4s",
+                "segment_002.m4s",
+                "segment_003.m4s",
+                "segment_004.m4s"
+            ]
+        );
+        assert_eq!(segments[4].0, 2.5);
+    }
+
+    #[test]
+    fn test_join_media_playlists_repeats_keys_only_when_they_change() {
+        let key = "#EXT-X-KEY:METHOD=AES-128,URI=\"/api/videos/v/key\"";
+        let rotated = "#EXT-X-KEY:METHOD=AES-128,URI=\"/api/videos/v/key2\"";
+        let playlists = [
+            range_playlist(0, &[4.0], Some(key)),
+            range_playlist(1, &[4.0], Some(key)),
+            range_playlist(2, &[4.0], Some(rotated)),
+        ];
+        let joined = join_media_playlists(&playlists);
+
+        assert_eq!(joined.matches("#EXT-X-KEY").count(), 2);
+        let first_key = joined.find(key).unwrap();
+        let rotated_key = joined.find(rotated).unwrap();
+        assert!(first_key < joined.find("segment_000").unwrap());
+        assert!(joined.find("segment_001").unwrap() < rotated_key);
+        assert!(rotated_key < joined.find("segment_002").unwrap());
+    }
+
+    fn iframe(uri: &str, offset: u64, length: u64, duration: f64) -> IFrameEntry {
+        IFrameEntry {
+            uri: uri.to_string(),
+            tables_length: 564,
+            offset,
+            length,
+            duration,
+        }
+    }
+
+    #[test]
+    fn test_build_iframe_playlist_maps_program_tables() {
+        let entries = [
+            iframe("segment_000.ts", 564, 20_000, 2.0),
+            iframe("segment_000.ts", 90_000, 18_000, 2.0),
+            iframe("segment_001.ts", 564, 21_000, 1.5),
+        ];
+        let playlist = build_iframe_playlist(&entries);
+
+        assert_eq!(
+            playlist,
+            "#EXTM3U\n#EXT-X-VERSION:5\n#EXT-X-TARGETDURATION:2\n#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n#EXT-X-I-FRAMES-ONLY\n\
+#EXT-X-MAP:URI=\"segment_000.ts\",BYTERANGE=\"564@0\"\n\
+#EXTINF:2.000000,\n#EXT-X-BYTERANGE:20000@564\nsegment_000.ts\n\
+#EXTINF:2.000000,\n#EXT-X-BYTERANGE:18000@90000\nsegment_000.ts\n\
+#EXT-X-MAP:URI=\"segment_001.ts\",BYTERANGE=\"564@0\"\n\
+#EXTINF:1.500000,\n#EXT-X-BYTERANGE:21000@564\nsegment_001.ts\n\
+#EXT-X-ENDLIST\n"
+        );
+    }
+
+    #[test]
+    fn test_add_master_variant_without_template() {
+        let variant = VideoVariant::new("1080p", 1920, 1080, 30.0);
+        assert!(add_master_variant(MASTER, "480p", 1_000_000, &variant).is_none());
+    }
+
+    const VTT: &str = "WEBVTT\r\n\r\n1\r\n00:00:05.000 --> 00:00:08.000\r\nFirst\r\n\r\n\
+2\r\n00:00:28.500 --> 00:00:31.250\r\nAcross\r\n\r\n\
+3\r\n00:01:02.000 --> 00:01:04.000\r\nLast\r\n";
+
+    #[test]
+    fn test_segment_webvtt_repeats_cues_spanning_segments() {
+        let segments = segment_webvtt(VTT, 65.0, 30, 0);
+        assert_eq!(segments.len(), 3);
+        assert!(segments[0].contains("First") && segments[0].contains("Across"));
+        assert!(segments[1].contains("Across") && !segments[1].contains("First"));
+        assert!(segments[2].contains("Last") && !segments[2].contains("Across"));
+        assert!(
+            segments[1].contains("2\n00:00:28.500 --> 00:00:31.250\nAcross\n"),
+            "cue times stay absolute: {}",
+            segments[1]
+        );
+    }
+
+    #[test]
+    fn test_segment_webvtt_timestamp_map_per_segment_type() {
+        for (segment_type, offset) in [(HlsSegmentType::MpegTs, 126_000), (HlsSegmentType::Fmp4, 0)]
+        {
+            assert_eq!(subtitle_mpegts_offset(segment_type), offset);
+            let segments = segment_webvtt(VTT, 65.0, 30, subtitle_mpegts_offset(segment_type));
+            let header = format!(
+                "WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:{},LOCAL:00:00:00.000\n",
+                offset
+            );
+            assert!(segments.iter().all(|segment| segment.starts_with(&header)));
+        }
+    }
+
+    #[test]
+    fn test_subtitle_playlist_ends_with_partial_segment() {
+        let segments = segment_webvtt(VTT, 65.0, 30, 0);
+        let playlist = build_subtitle_playlist(segments.len(), 65.0, 30);
+        let durations: Vec<f64> = playlist_segments(&playlist)
+            .into_iter()
+            .map(|(duration, _)| duration)
+            .collect();
+        assert_eq!(durations, vec![30.0, 30.0, 5.0]);
+        assert!(playlist.contains("segment_002.vtt\n#EXT-X-ENDLIST"));
+    }
+}
@@ -1,10 +1,15 @@
 mod clickhouse;
 mod config;
+mod dash;
 mod database;
 mod handlers;
+mod hls;
+mod markers;
+mod renditions;
 mod storage;
 mod types;
 mod video;
+mod worker;
 
 use anyhow::{Context, Result};
 use aws_sdk_s3::{Client as S3Client, config::Region};
@@ -46,6 +51,26 @@ async fn auth_middleware(
     }
 }
 
+/// Job API of remote workers, authenticated with `server.worker_token`
+async fn worker_auth_middleware(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let Some(token) = state.config.server.worker_token.as_deref() else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let auth_header = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|header| header.to_str().ok());
+
+    match auth_header {
+        Some(auth) if auth == format!("Bearer {}", token) => Ok(next.run(req).await),
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
 async fn check_auth() -> Result<(), StatusCode> {
     Ok(())
 }
@@ -85,6 +110,11 @@ async fn main() -> Result<()> {
         .build();
     let s3 = S3Client::from_conf(s3_config);
 
+    // `akane worker` only encodes jobs claimed from the main server
+    if std::env::args().nth(1).as_deref() == Some("worker") {
+        return worker::run(config, s3).await;
+    }
+
     let database_url = "sqlite://videos.db";
     let db_pool = database::initialize_database(database_url).await?;
 
@@ -109,6 +139,7 @@ async fn main() -> Result<()> {
         ffmpeg_semaphore,
         clickhouse: clickhouse_client,
         chunked_uploads: Arc::new(RwLock::new(HashMap::new())),
+        remote_jobs: Arc::new(RwLock::new(HashMap::new())),
     };
 
     let public_routes = Router::new()
@@ -128,10 +159,13 @@ async fn main() -> Result<()> {
             get(handlers::get_attachment_file),
         )
         .route("/videos/{id}/chapters", get(handlers::get_video_chapters))
+        .route("/videos/{id}/markers", get(handlers::get_video_markers))
         .route(
             "/videos/{id}/audio-tracks",
             get(handlers::get_video_audio_tracks),
         )
+        .route("/videos/{id}/key", get(handlers::get_video_key_file))
+        .route("/videos/{id}/license", post(handlers::get_clearkey_license))
         .route("/analytics/realtime", get(handlers::get_realtime_analytics))
         .route("/analytics/history", get(handlers::get_analytics_history))
         .route("/analytics/videos", get(handlers::get_analytics_videos))
@@ -144,6 +178,22 @@ async fn main() -> Result<()> {
         .route("/videos", get(handlers::list_videos))
         .route("/videos", delete(handlers::delete_videos))
         .route("/videos/{id}", put(handlers::update_video))
+        .route(
+            "/videos/{id}/renditions",
+            get(handlers::get_video_renditions).post(handlers::add_video_rendition),
+        )
+        .route(
+            "/videos/{id}/renditions/{label}",
+            delete(handlers::remove_video_rendition),
+        )
+        .route(
+            "/videos/{id}/thumbnails",
+            get(handlers::get_thumbnail_candidates),
+        )
+        .route("/videos/{id}/thumbnail", put(handlers::update_thumbnail))
+        .route("/videos/{id}/markers", put(handlers::update_video_markers))
+        .route("/videos/{id}/reprocess", post(handlers::reprocess_video))
+        .route("/markers/detect", post(handlers::detect_markers))
         .route("/queues", get(handlers::list_queues))
         .route("/queues/failed", delete(handlers::clear_all_failed))
         .route("/queues/{id}", delete(handlers::cancel_queue))
@@ -156,7 +206,30 @@ async fn main() -> Result<()> {
             auth_middleware,
         ));
 
-    let api_routes = Router::new().merge(public_routes).merge(protected_routes);
+    let worker_routes = Router::new()
+        .route("/worker/jobs/claim", post(handlers::claim_job))
+        .route(
+            "/worker/jobs/{job_id}/source",
+            get(handlers::get_job_source),
+        )
+        .route(
+            "/worker/jobs/{job_id}/progress",
+            post(handlers::report_job_progress),
+        )
+        .route(
+            "/worker/jobs/{job_id}/complete",
+            post(handlers::complete_job),
+        )
+        .route("/worker/jobs/{job_id}/fail", post(handlers::fail_job))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            worker_auth_middleware,
+        ));
+
+    let api_routes = Router::new()
+        .merge(public_routes)
+        .merge(protected_routes)
+        .merge(worker_routes);
 
     let app = Router::new()
         .nest("/api", api_routes)
@@ -0,0 +1,270 @@
+use crate::config::IntroDetectionConfig;
+use crate::database::{get_series_fingerprints, save_auto_marker, save_fingerprint};
+use crate::types::{AudioFingerprint, DetectMarkersResponse, MarkerKind};
+use crate::video::fingerprint_audio;
+
+use anyhow::Result;
+use sqlx::SqlitePool;
+use std::path::PathBuf;
+use tracing::{info, warn};
+
+/// Seconds of audio per chromaprint item: 4096-sample frames with 2/3 overlap at 11025 Hz
+const ITEM_SECONDS: f64 = 4096.0 / 3.0 / 11025.0;
+
+/// Non-matching items bridged inside one shared segment (about one second)
+const MAX_GAP_ITEMS: usize = 8;
+
+/// Longest run of matching items between two fingerprints over every alignment,
+/// as `(start in a, start in b, length)`. Items match when they differ in at most
+/// `max_bit_errors` bits; short gaps of noise or dialogue over the music are bridged.
+fn longest_shared_run(a: &[u32], b: &[u32], max_bit_errors: u32) -> Option<(usize, usize, usize)> {
+    let mut best: Option<(usize, usize, usize)> = None;
+    for shift in -(b.len() as isize - 1)..a.len() as isize {
+        let a_start = shift.max(0) as usize;
+        let b_start = (-shift).max(0) as usize;
+        let overlap = (a.len() - a_start).min(b.len() - b_start);
+        if best.is_some_and(|(_, _, len)| overlap <= len) {
+            continue;
+        }
+
+        let mut keep = |first: usize, last: usize| {
+            let len = last - first + 1;
+            if best.is_none_or(|(_, _, best_len)| len > best_len) {
+                best = Some((a_start + first, b_start + first, len));
+            }
+        };
+        let mut run: Option<(usize, usize)> = None;
+        for i in 0..overlap {
+            if (a[a_start + i] ^ b[b_start + i]).count_ones() > max_bit_errors {
+                continue;
+            }
+            run = match run {
+                Some((first, last)) if i - last - 1 <= MAX_GAP_ITEMS => Some((first, i)),
+                Some((first, last)) => {
+                    keep(first, last);
+                    Some((i, i))
+                }
+                None => Some((i, i)),
+            };
+        }
+        if let Some((first, last)) = run {
+            keep(first, last);
+        }
+    }
+    best
+}
+
+/// For every episode, the longest audio it shares with one of its `compare_episodes`
+/// neighbours by upload order, as a time range within that episode
+fn find_shared_ranges(
+    episodes: &[AudioFingerprint],
+    config: &IntroDetectionConfig,
+) -> Vec<(String, Option<(f64, f64)>)> {
+    let min_items = (config.min_duration / ITEM_SECONDS).ceil() as usize;
+    let max_items = (config.max_duration / ITEM_SECONDS).floor() as usize;
+
+    episodes
+        .iter()
+        .enumerate()
+        .map(|(i, episode)| {
+            let mut neighbours: Vec<usize> = (0..episodes.len()).filter(|&j| j != i).collect();
+            neighbours.sort_by_key(|&j| j.abs_diff(i));
+            neighbours.truncate(config.compare_episodes.max(1));
+
+            // Runs longer than max_duration are re-uploads or recaps, not an intro
+            let best = neighbours
+                .into_iter()
+                .filter_map(|j| {
+                    longest_shared_run(&episode.items, &episodes[j].items, config.max_bit_errors)
+                })
+                .filter(|(_, _, len)| (min_items..=max_items).contains(len))
+                .max_by_key(|(_, _, len)| *len);
+
+            let range = best.map(|(start, _, len)| {
+                (
+                    episode.start_time + start as f64 * ITEM_SECONDS,
+                    episode.start_time + (start + len) as f64 * ITEM_SECONDS,
+                )
+            });
+            (episode.video_id.clone(), range)
+        })
+        .collect()
+}
+
+/// Match the stored fingerprints of every episode tagged `tag` and refresh their
+/// detected intro and credits markers
+pub async fn detect_series_markers(
+    db_pool: &SqlitePool,
+    tag: &str,
+    config: &IntroDetectionConfig,
+) -> Result<DetectMarkersResponse> {
+    let mut response = DetectMarkersResponse {
+        episodes: 0,
+        intros: 0,
+        credits: 0,
+    };
+
+    for region in [MarkerKind::Intro, MarkerKind::Credits] {
+        let episodes = get_series_fingerprints(db_pool, tag, region).await?;
+        response.episodes = response.episodes.max(episodes.len());
+        if episodes.len() < 2 {
+            continue;
+        }
+
+        // Brute-force alignment is CPU bound
+        let matcher_config = config.clone();
+        let ranges =
+            tokio::task::spawn_blocking(move || find_shared_ranges(&episodes, &matcher_config))
+                .await?;
+
+        for (video_id, range) in ranges {
+            save_auto_marker(db_pool, &video_id, region, range).await?;
+            if range.is_some() {
+                match region {
+                    MarkerKind::Credits => response.credits += 1,
+                    _ => response.intros += 1,
+                }
+            }
+        }
+    }
+
+    info!(
+        "Marker detection for {}: {} episodes, {} intros, {} credits",
+        tag, response.episodes, response.intros, response.credits
+    );
+    Ok(response)
+}
+
+/// Fingerprint the intro and credits windows of a new upload, then re-run detection
+/// for each series it belongs to
+pub async fn fingerprint_episode(
+    db_pool: &SqlitePool,
+    input: &PathBuf,
+    video_id: &str,
+    duration: f64,
+    tags: &[String],
+    config: &IntroDetectionConfig,
+) -> Result<()> {
+    // Windows never cross the middle of the episode
+    let half = duration / 2.0;
+    let credits_start = (duration - config.credits_window).max(half);
+    let windows = [
+        (MarkerKind::Intro, 0.0, config.intro_window.min(half)),
+        (MarkerKind::Credits, credits_start, duration - credits_start),
+    ];
+    for (region, start, length) in windows {
+        if length < config.min_duration {
+            continue;
+        }
+        let items = fingerprint_audio(input, start, length).await?;
+        save_fingerprint(db_pool, video_id, region, start, &items).await?;
+    }
+
+    for tag in tags
+        .iter()
+        .filter(|tag| tag.starts_with(&config.series_tag_prefix))
+    {
+        if let Err(e) = detect_series_markers(db_pool, tag, config).await {
+            warn!("Marker detection for {} failed: {}", tag, e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pseudo-random fingerprint items; different seeds share no matching items
+    fn items(seed: u32, count: usize) -> Vec<u32> {
+        let mut state = seed.wrapping_mul(2_654_435_761).max(1);
+        (0..count)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state
+            })
+            .collect()
+    }
+
+    fn episode(video_id: &str, items: Vec<u32>) -> AudioFingerprint {
+        AudioFingerprint {
+            video_id: video_id.to_string(),
+            start_time: 0.0,
+            items,
+        }
+    }
+
+    #[test]
+    fn test_longest_shared_run_finds_shifted_match() {
+        let shared = items(1, 50);
+        let a = [items(2, 20), shared.clone(), items(3, 10)].concat();
+        let b = [items(4, 5), shared, items(5, 30)].concat();
+        assert_eq!(longest_shared_run(&a, &b, 6), Some((20, 5, 50)));
+    }
+
+    #[test]
+    fn test_longest_shared_run_bridges_gaps_up_to_max_gap_items() {
+        let shared = items(1, 40);
+        let with_gap = |gap: usize| {
+            let mut b = shared.clone();
+            for item in &mut b[15..15 + gap] {
+                *item = !*item;
+            }
+            b
+        };
+
+        assert_eq!(
+            longest_shared_run(&shared, &with_gap(MAX_GAP_ITEMS), 6),
+            Some((0, 0, 40))
+        );
+        // One more item splits the run; the longer half is after the gap
+        let after = 15 + MAX_GAP_ITEMS + 1;
+        assert_eq!(
+            longest_shared_run(&shared, &with_gap(MAX_GAP_ITEMS + 1), 6),
+            Some((after, after, 40 - after))
+        );
+    }
+
+    #[test]
+    fn test_longest_shared_run_empty_inputs() {
+        let a = items(1, 10);
+        assert_eq!(longest_shared_run(&a, &[], 6), None);
+        assert_eq!(longest_shared_run(&[], &a, 6), None);
+        assert_eq!(longest_shared_run(&[], &[], 6), None);
+        assert!(find_shared_ranges(&[], &IntroDetectionConfig::default()).is_empty());
+    }
+
+    #[test]
+    fn test_find_shared_ranges_filters_by_duration() {
+        let config = IntroDetectionConfig {
+            min_duration: 19.5 * ITEM_SECONDS,
+            max_duration: 60.5 * ITEM_SECONDS,
+            ..IntroDetectionConfig::default()
+        };
+        let pair = |shared: usize| {
+            let common = items(1, shared);
+            [
+                episode("a", [items(2, 10), common.clone(), items(3, 10)].concat()),
+                episode("b", [common, items(4, 30)].concat()),
+            ]
+        };
+
+        let ranges = find_shared_ranges(&pair(40), &config);
+        let (start, end) = ranges[0].1.unwrap();
+        assert!((start - 10.0 * ITEM_SECONDS).abs() < 1e-9);
+        assert!((end - 50.0 * ITEM_SECONDS).abs() < 1e-9);
+        assert_eq!(ranges[1].1.map(|(start, _)| start), Some(0.0));
+
+        // Too short to be an intro, or long enough to be a re-upload
+        for shared in [10, 100] {
+            let ranges = find_shared_ranges(&pair(shared), &config);
+            assert!(
+                ranges.iter().all(|(_, range)| range.is_none()),
+                "{}",
+                shared
+            );
+        }
+    }
+}
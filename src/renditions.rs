@@ -0,0 +1,369 @@
+use crate::config::EncodingProfile;
+use crate::dash::{add_dash_representation, remove_dash_representations};
+use crate::database::{
+    StoredVideo, delete_renditions, get_renditions_for_video, get_video_key, save_rendition,
+    update_available_resolutions,
+};
+use crate::hls::{add_master_variant, iframe_stream_inf, remove_master_variants};
+use crate::storage::{
+    delete_prefix_from_r2, download_from_r2, download_prefix_from_r2, put_object_to_r2,
+    read_text_from_r2, storage_prefix, upload_dir_to_r2,
+};
+use crate::types::{
+    AppState, HdrFormat, HlsSegmentType, SegmentEncryption, VideoCodec, VideoRendition,
+    VideoVariant,
+};
+use crate::video::{
+    IFRAME_PLAYLIST_VARIANTS, build_variant_ladder, encode_variants, get_video_metadata,
+    write_hls_key_info, write_iframe_playlist,
+};
+
+use anyhow::{Context, Result};
+use std::path::Path;
+use tokio::fs;
+use tracing::{info, warn};
+
+/// Split a recorded codec id (`hevc-pq`, `h264-burned`, ...) back into its family
+fn parse_codec_id(codec_id: &str) -> Option<(VideoCodec, Option<HdrFormat>, bool)> {
+    let mut parts = codec_id.split('-');
+    let codec = VideoCodec::from_config(parts.next()?)?;
+    let mut hdr = None;
+    let mut burned = false;
+    for part in parts {
+        match part {
+            "pq" => hdr = Some(HdrFormat::Pq),
+            "hlg" => hdr = Some(HdrFormat::Hlg),
+            "burned" => burned = true,
+            _ => return None,
+        }
+    }
+    Some((codec, hdr, burned))
+}
+
+/// The variant a recorded rendition was encoded as. The segment type isn't
+/// recorded, so H.264 reads as MPEG-TS even when it was packaged as CMAF.
+fn recorded_variant(rendition: &VideoRendition) -> Option<VideoVariant> {
+    let (codec, hdr, burned) = parse_codec_id(&rendition.codec)?;
+    Some(VideoVariant {
+        label: rendition.label.clone(),
+        width: rendition.width,
+        height: rendition.height,
+        fps: rendition.fps,
+        bitrate: rendition.bitrate,
+        codec,
+        hdr,
+        segment_type: codec.segment_type(),
+        burned_subtitles: burned,
+    })
+}
+
+/// Short edge of a ladder label, for keeping `available_resolutions` in ladder order
+pub fn tier_height(label: &str) -> u32 {
+    match label {
+        "4K" => 2160,
+        _ => label.trim_end_matches('p').parse().unwrap_or(0),
+    }
+}
+
+/// Drop every rendition of the tier `label` from a video: the playlists and DASH
+/// manifest stop listing it first, then its metadata and files go.
+/// Returns the remaining `available_resolutions`.
+pub async fn remove_rendition_tier(
+    state: &AppState,
+    video_id: &str,
+    video: &StoredVideo,
+    label: &str,
+) -> Result<Vec<String>> {
+    let renditions = get_renditions_for_video(&state.db_pool, video_id).await?;
+    let mut dirs: Vec<String> = renditions
+        .iter()
+        .filter(|r| r.label == label)
+        .filter_map(recorded_variant)
+        .map(|v| v.dir())
+        .collect();
+    // Uploads from before renditions were recorded only have the H.264 ladder
+    if dirs.is_empty() {
+        dirs.push(VideoCodec::H264.rendition_dir(label));
+    }
+    let prefix = storage_prefix(video_id, video.revision);
+
+    for name in ["index.m3u8", "burned.m3u8"] {
+        let key = format!("{}{}", prefix, name);
+        if let Some(master) = read_text_from_r2(state, &key).await? {
+            let master = remove_master_variants(&master, &dirs);
+            put_object_to_r2(state, &key, master.into_bytes()).await?;
+        }
+    }
+    let manifest_key = format!("{}manifest.mpd", prefix);
+    if let Some(manifest) = read_text_from_r2(state, &manifest_key).await? {
+        let manifest = remove_dash_representations(&manifest, &dirs);
+        put_object_to_r2(state, &manifest_key, manifest.into_bytes()).await?;
+    }
+
+    let available: Vec<String> = video
+        .available_resolutions
+        .iter()
+        .filter(|l| *l != label)
+        .cloned()
+        .collect();
+    let mut tx = state.db_pool.begin().await?;
+    delete_renditions(&mut *tx, video_id, label).await?;
+    update_available_resolutions(&mut *tx, video_id, &available).await?;
+    tx.commit().await?;
+
+    for dir in &dirs {
+        match delete_prefix_from_r2(state, &format!("{}{}/", prefix, dir)).await {
+            Ok(count) => info!("Removed {} objects of {}/{}", count, video_id, dir),
+            Err(e) => warn!("Failed to delete files of {}/{}: {}", video_id, dir, e),
+        }
+    }
+
+    Ok(available)
+}
+
+/// Encode the tier `label` for every SDR and HDR family a video already has and
+/// splice it into the stored playlists and DASH manifest. The archived source is
+/// used when there is one, otherwise the highest H.264 rendition (unencrypted
+/// videos only). The burned-in ladder is left as it is.
+/// Returns the new `available_resolutions`.
+pub async fn add_rendition_tier(
+    state: &AppState,
+    upload_id: &str,
+    video_id: &str,
+    video: &StoredVideo,
+    label: &str,
+) -> Result<Vec<String>> {
+    let work_dir = std::env::temp_dir().join(format!("rendition-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&work_dir).await?;
+    let result = add_rendition_tier_in(state, upload_id, video_id, video, label, &work_dir).await;
+    let _ = fs::remove_dir_all(&work_dir).await;
+    result
+}
+
+async fn add_rendition_tier_in(
+    state: &AppState,
+    upload_id: &str,
+    video_id: &str,
+    video: &StoredVideo,
+    label: &str,
+    work_dir: &Path,
+) -> Result<Vec<String>> {
+    let renditions = get_renditions_for_video(&state.db_pool, video_id).await?;
+    let recorded: Vec<VideoVariant> = renditions
+        .iter()
+        .filter_map(recorded_variant)
+        .filter(|v| !v.burned_subtitles)
+        .collect();
+
+    // The highest rendition of each family is the template for the new entries
+    let mut templates: Vec<&VideoVariant> = Vec::new();
+    for variant in &recorded {
+        match templates
+            .iter_mut()
+            .find(|t| t.codec_id() == variant.codec_id())
+        {
+            Some(template) if template.height < variant.height => *template = variant,
+            Some(_) => {}
+            None => templates.push(variant),
+        }
+    }
+    let h264_template = templates
+        .iter()
+        .find(|t| t.codec == VideoCodec::H264 && t.hdr.is_none())
+        .copied()
+        .context("Video has no recorded H.264 renditions")?;
+    let prefix = storage_prefix(video_id, video.revision);
+    let stored_key = get_video_key(&state.db_pool, video_id).await?;
+
+    let input = match &video.source_key {
+        Some(source_key) => {
+            let ext = source_key
+                .rsplit_once('.')
+                .map(|(_, ext)| ext)
+                .unwrap_or("bin");
+            let path = work_dir.join(format!("source.{}", ext));
+            download_from_r2(state, source_key, &path).await?;
+            path
+        }
+        None if stored_key.is_some() => {
+            anyhow::bail!("Encrypted videos need an archived source to add renditions")
+        }
+        None => {
+            let dir = work_dir.join("input");
+            download_prefix_from_r2(state, &format!("{}{}/", prefix, h264_template.dir()), &dir)
+                .await?;
+            dir.join("index.m3u8")
+        }
+    };
+
+    let _ffmpeg_permit = state.ffmpeg_semaphore.acquire().await?;
+    let metadata = get_video_metadata(&input).await?;
+    // Videos without a stored profile were encoded with the built-in defaults
+    let profile = match &video.profile {
+        Some(name) => state.config.video.profile(Some(name))?,
+        None => EncodingProfile::default(),
+    };
+    let mut tier = build_variant_ladder(&metadata, &[tier_height(label)])
+        .into_iter()
+        .find(|v| v.label == label)
+        .with_context(|| {
+            format!(
+                "{} would upscale the {}x{} source",
+                label, metadata.width, metadata.height
+            )
+        })?;
+    let complexity = renditions.iter().find_map(|r| r.complexity);
+    if let Some(factor) = complexity {
+        tier.scale_bitrate(factor);
+    }
+
+    // H.264 follows the packaging of the existing ladder
+    let h264_playlist = read_text_from_r2(
+        state,
+        &format!("{}{}/index.m3u8", prefix, h264_template.dir()),
+    )
+    .await?
+    .unwrap_or_default();
+    let cmaf = h264_playlist.contains("#EXT-X-MAP");
+
+    let mut variants: Vec<VideoVariant> = Vec::new();
+    for template in &templates {
+        if template.hdr.is_some() && template.hdr != metadata.hdr {
+            warn!(
+                "Skipping {} for {}: the source isn't {}",
+                label,
+                template.codec_id(),
+                template.hdr.map(|h| h.video_range()).unwrap_or("SDR")
+            );
+            continue;
+        }
+        variants.push(
+            tier.with_codec(template.codec)
+                .with_hdr(template.hdr)
+                .with_cmaf(cmaf),
+        );
+    }
+
+    // Reuse the video's content key so the new segments play with the old license
+    let key_dir = work_dir.join("key");
+    let encryption = match stored_key {
+        Some(stored) if stored.method == "cenc" => Some(SegmentEncryption::Cenc {
+            kid: stored
+                .kid
+                .as_deref()
+                .and_then(|kid| kid.try_into().ok())
+                .context("Invalid stored key ID")?,
+            key: stored
+                .key
+                .as_slice()
+                .try_into()
+                .context("Invalid stored key")?,
+        }),
+        Some(stored) => {
+            let key: [u8; 16] = stored
+                .key
+                .as_slice()
+                .try_into()
+                .context("Invalid stored key")?;
+            let key_uri = format!("/api/videos/{}/key", video_id);
+            let key_info = write_hls_key_info(&key_dir, &key_uri, &key).await?;
+            Some(SegmentEncryption::Aes128 { key_info })
+        }
+        None => None,
+    };
+
+    let out_dir = work_dir.join("out");
+    let encoded = encode_variants(
+        &input,
+        &out_dir,
+        &state.progress,
+        upload_id,
+        &state.config.video,
+        &metadata,
+        &variants,
+        encryption.as_ref(),
+        &profile,
+    )
+    .await?;
+    if encoded.is_empty() {
+        anyhow::bail!("No renditions of {} could be encoded", label);
+    }
+
+    let master_key = format!("{}index.m3u8", prefix);
+    let mut master = read_text_from_r2(state, &master_key)
+        .await?
+        .context("Master playlist is missing")?;
+    let manifest_key = format!("{}manifest.mpd", prefix);
+    let mut manifest = read_text_from_r2(state, &manifest_key).await?;
+
+    // A new lowest MPEG-TS tier gets an I-frame playlist like the lowest ones at upload
+    let iframe_count = master
+        .lines()
+        .filter(|line| line.starts_with("#EXT-X-I-FRAME-STREAM-INF:"))
+        .count();
+    let lowest_ts_bitrate = recorded
+        .iter()
+        .filter(|v| v.codec == VideoCodec::H264 && v.hdr.is_none())
+        .map(|v| v.bitrate)
+        .min()
+        .unwrap_or(u32::MAX);
+
+    for variant in &encoded {
+        let template = templates
+            .iter()
+            .find(|t| t.codec_id() == variant.codec_id())
+            .context("No template rendition")?;
+        master = add_master_variant(&master, &template.dir(), template.bandwidth(), variant)
+            .with_context(|| format!("{} is not in the master playlist", template.dir()))?;
+
+        if variant.segment_type == HlsSegmentType::MpegTs
+            && !matches!(encryption, Some(SegmentEncryption::Aes128 { .. }))
+            && (iframe_count < IFRAME_PLAYLIST_VARIANTS || variant.bitrate < lowest_ts_bitrate)
+        {
+            match write_iframe_playlist(&out_dir.join(variant.dir())).await {
+                Ok(bandwidth) => master.push_str(&iframe_stream_inf(variant, bandwidth)),
+                Err(e) => warn!("Skipping I-frame playlist of {}: {}", variant.dir(), e),
+            }
+        }
+
+        if variant.segment_type == HlsSegmentType::Fmp4
+            && let Some(current) = &manifest
+        {
+            manifest =
+                Some(add_dash_representation(current, &out_dir, &template.dir(), variant).await?);
+        }
+    }
+
+    // Segments first, so the playlists never point at files that aren't there yet
+    upload_dir_to_r2(state, &out_dir, &prefix, Some(upload_id)).await?;
+    put_object_to_r2(state, &master_key, master.into_bytes()).await?;
+    if let Some(manifest) = manifest {
+        put_object_to_r2(state, &manifest_key, manifest.into_bytes()).await?;
+    }
+
+    let mut available = video.available_resolutions.clone();
+    if !available.iter().any(|l| l == label) {
+        available.push(label.to_string());
+    }
+    available.sort_by_key(|l| tier_height(l));
+
+    let mut tx = state.db_pool.begin().await?;
+    for variant in &encoded {
+        save_rendition(&mut *tx, video_id, variant, complexity).await?;
+    }
+    update_available_resolutions(&mut *tx, video_id, &available).await?;
+    tx.commit().await?;
+
+    info!(
+        "Added {} to video {}: {}",
+        label,
+        video_id,
+        encoded
+            .iter()
+            .map(|v| v.dir())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    Ok(available)
+}
@@ -4,12 +4,12 @@ use aws_sdk_s3::primitives::ByteStream;
 use aws_sdk_s3::types::CompletedMultipartUpload;
 use aws_sdk_s3::types::CompletedPart;
 use futures::stream::{self, StreamExt};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::time::Duration;
 use tokio::fs::{self, File};
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tracing::{error, info, warn};
 
 // 100 MB threshold for multipart upload
@@ -21,9 +21,172 @@ const MAX_UPLOAD_RETRIES: u32 = 3;
 const INITIAL_RETRY_DELAY_MS: u64 = 1000;
 const MAX_RETRY_DELAY_MS: u64 = 10000;
 
+/// Bucket prefix of a video's encoded files. Revision 0 lives at the root of the
+/// video's prefix; each reprocess writes a new revision next to it.
+pub fn storage_prefix(video_id: &str, revision: i64) -> String {
+    if revision == 0 {
+        format!("{}/", video_id)
+    } else {
+        format!("{}/r{}/", video_id, revision)
+    }
+}
+
+/// Stream an object from R2/S3 to a local file
+pub async fn download_from_r2(state: &AppState, key: &str, path: &Path) -> Result<()> {
+    let resp = state
+        .s3
+        .get_object()
+        .bucket(&state.config.r2.bucket)
+        .key(key)
+        .send()
+        .await
+        .with_context(|| format!("Failed to fetch {}", key))?;
+
+    let mut body = resp.body;
+    let mut file = File::create(path)
+        .await
+        .with_context(|| format!("Failed to create {:?}", path))?;
+    while let Some(chunk) = body
+        .try_next()
+        .await
+        .with_context(|| format!("Failed to read {}", key))?
+    {
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+
+    Ok(())
+}
+
+/// Read a small text object (a playlist or manifest), None when it doesn't exist
+pub async fn read_text_from_r2(state: &AppState, key: &str) -> Result<Option<String>> {
+    let resp = match state
+        .s3
+        .get_object()
+        .bucket(&state.config.r2.bucket)
+        .key(key)
+        .send()
+        .await
+    {
+        Ok(resp) => resp,
+        Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => return Ok(None),
+        Err(e) => return Err(anyhow::anyhow!(e).context(format!("Failed to fetch {}", key))),
+    };
+    let bytes = resp
+        .body
+        .collect()
+        .await
+        .with_context(|| format!("Failed to read {}", key))?
+        .into_bytes();
+
+    Ok(Some(String::from_utf8(bytes.to_vec())?))
+}
+
+/// Store a small generated object such as a rewritten playlist
+pub async fn put_object_to_r2(state: &AppState, key: &str, body: Vec<u8>) -> Result<()> {
+    state
+        .s3
+        .put_object()
+        .bucket(&state.config.r2.bucket)
+        .key(key)
+        .body(ByteStream::from(body))
+        .send()
+        .await
+        .with_context(|| format!("Failed to upload {}", key))?;
+    Ok(())
+}
+
+/// Every object key under `prefix`
+async fn list_keys(state: &AppState, prefix: &str) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    let mut continuation_token = None;
+    loop {
+        let resp = state
+            .s3
+            .list_objects_v2()
+            .bucket(&state.config.r2.bucket)
+            .prefix(prefix)
+            .set_continuation_token(continuation_token)
+            .send()
+            .await
+            .with_context(|| format!("Failed to list {}", prefix))?;
+
+        keys.extend(
+            resp.contents
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|obj| obj.key),
+        );
+
+        match resp.next_continuation_token {
+            Some(token) if resp.is_truncated == Some(true) => continuation_token = Some(token),
+            _ => break,
+        }
+    }
+    Ok(keys)
+}
+
+/// Best-effort delete of `keys`, failures are only logged
+async fn delete_keys(state: &AppState, keys: &[String]) {
+    for key in keys {
+        if let Err(e) = state
+            .s3
+            .delete_object()
+            .bucket(&state.config.r2.bucket)
+            .key(key)
+            .send()
+            .await
+        {
+            warn!("Failed to delete {}: {}", key, e);
+        }
+    }
+}
+
+/// Download every object under `prefix` into `dir`, keeping the relative layout
+pub async fn download_prefix_from_r2(state: &AppState, prefix: &str, dir: &Path) -> Result<usize> {
+    let keys = list_keys(state, prefix).await?;
+    for key in &keys {
+        let path = dir.join(key.trim_start_matches(prefix));
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        download_from_r2(state, key, &path).await?;
+    }
+    Ok(keys.len())
+}
+
+/// Delete every object under `prefix`
+pub async fn delete_prefix_from_r2(state: &AppState, prefix: &str) -> Result<usize> {
+    let keys = list_keys(state, prefix).await?;
+    delete_keys(state, &keys).await;
+    Ok(keys.len())
+}
+
+/// Delete every object of a video except the current revision, the archived
+/// source and `keep` (e.g. a custom thumbnail stored outside the revision)
+pub async fn delete_stale_revisions(
+    state: &AppState,
+    video_id: &str,
+    current_prefix: &str,
+    keep: &[&str],
+) -> Result<usize> {
+    let source_prefix = format!("{}/source/", video_id);
+    let stale: Vec<String> = list_keys(state, &format!("{}/", video_id))
+        .await?
+        .into_iter()
+        .filter(|key| {
+            !key.starts_with(current_prefix)
+                && !key.starts_with(&source_prefix)
+                && !keep.contains(&key.as_str())
+        })
+        .collect();
+
+    delete_keys(state, &stale).await;
+    Ok(stale.len())
+}
+
 /// Upload a large file to R2/S3 using multipart upload to avoid Windows I/O buffer limits.
 /// This streams the file in chunks instead of loading the entire file into memory.
-#[allow(dead_code)]
 pub async fn upload_large_file_to_r2(
     state: &AppState,
     file_path: &PathBuf,
@@ -209,7 +372,22 @@ pub async fn upload_hls_to_r2(
     prefix: &str,
     upload_id: Option<&str>,
 ) -> Result<String> {
-    let mut master_playlist_key = None;
+    let master_playlist_key = format!("{}index.m3u8", prefix);
+    let keys = upload_dir_to_r2(state, hls_dir, prefix, upload_id).await?;
+    if !keys.contains(&master_playlist_key) {
+        anyhow::bail!("no master playlist (index.m3u8) generated");
+    }
+
+    Ok(master_playlist_key)
+}
+
+/// Upload every file under `dir` to `prefix`, returning the uploaded keys
+pub async fn upload_dir_to_r2(
+    state: &AppState,
+    dir: &PathBuf,
+    prefix: &str,
+    upload_id: Option<&str>,
+) -> Result<Vec<String>> {
     let mut files_to_upload = Vec::new();
 
     // Collect all files to upload
@@ -217,7 +395,6 @@ pub async fn upload_hls_to_r2(
         dir: &PathBuf,
         prefix: &str,
         files: &mut Vec<(PathBuf, String)>,
-        master_key: &mut Option<String>,
     ) -> Result<()> {
         let mut read_dir = fs::read_dir(dir).await.context("read dir")?;
 
@@ -227,29 +404,16 @@ pub async fn upload_hls_to_r2(
 
             if path.is_dir() {
                 let sub_prefix = format!("{}{}/", prefix, file_name);
-                Box::pin(collect_files(&path, &sub_prefix, files, master_key)).await?;
+                Box::pin(collect_files(&path, &sub_prefix, files)).await?;
             } else if path.is_file() {
-                let key = format!("{}{}", prefix, file_name);
-
-                // Track master playlist
-                if file_name == "index.m3u8" && prefix.matches('/').count() == 1 {
-                    *master_key = Some(key.clone());
-                }
-
-                files.push((path, key));
+                files.push((path, format!("{}{}", prefix, file_name)));
             }
         }
 
         Ok(())
     }
 
-    collect_files(
-        hls_dir,
-        prefix,
-        &mut files_to_upload,
-        &mut master_playlist_key,
-    )
-    .await?;
+    collect_files(dir, prefix, &mut files_to_upload).await?;
 
     // Upload all files in parallel with concurrency limit
     let max_concurrent_uploads = state.config.server.max_concurrent_uploads;
@@ -342,6 +506,8 @@ pub async fn upload_hls_to_r2(
                         video_name: existing_video_name,
                         created_at: existing_created_at,
                         variant_percentage: None,
+                        encode_speed: None,
+                        eta_seconds: None,
                     };
                     state.progress.write().await.insert(id, progress_update);
                 }
@@ -354,12 +520,5 @@ pub async fn upload_hls_to_r2(
         .await;
 
     // Check for any upload errors
-    for result in upload_results {
-        result?;
-    }
-
-    let playlist_key = master_playlist_key
-        .ok_or_else(|| anyhow::anyhow!("no master playlist (index.m3u8) generated"))?;
-
-    Ok(playlist_key)
+    upload_results.into_iter().collect()
 }
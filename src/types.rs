@@ -1,12 +1,13 @@
-use crate::config::Config;
+use crate::config::{Config, EncodingProfile, VideoConfig};
 use aws_sdk_s3::Client as S3Client;
 use serde::{Deserialize, Serialize};
 use sqlx::SqlitePool;
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::{RwLock, Semaphore};
+use tokio::sync::{RwLock, Semaphore, oneshot};
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ProgressUpdate {
     pub stage: String,
     pub current_chunk: u32,
@@ -20,39 +21,395 @@ pub struct ProgressUpdate {
     pub created_at: u64,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub variant_percentage: Option<u32>,
+    /// FFmpeg encode speed as a multiple of realtime
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encode_speed: Option<f32>,
+    /// Estimated seconds left in the current FFmpeg step
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eta_seconds: Option<u64>,
 }
 
 pub type ProgressMap = Arc<RwLock<HashMap<String, ProgressUpdate>>>;
 
+/// Video codec family of a rendition ladder
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum VideoCodec {
+    H264,
+    Hevc,
+    Av1,
+}
+
+impl VideoCodec {
+    /// Parse a codec family name from config.yml (e.g. "hevc", "av1")
+    pub fn from_config(s: &str) -> Option<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "h264" | "avc" | "x264" => Some(VideoCodec::H264),
+            "hevc" | "h265" | "x265" => Some(VideoCodec::Hevc),
+            "av1" => Some(VideoCodec::Av1),
+            _ => None,
+        }
+    }
+
+    /// Directory name of a rendition. H.264 keeps the bare label used by older uploads.
+    pub fn rendition_dir(&self, label: &str) -> String {
+        match self {
+            VideoCodec::H264 => label.to_string(),
+            VideoCodec::Hevc => format!("hevc_{}", label),
+            VideoCodec::Av1 => format!("av1_{}", label),
+        }
+    }
+
+    /// Segment container used for this codec. HEVC and AV1 are only packaged as CMAF.
+    pub fn segment_type(&self) -> HlsSegmentType {
+        match self {
+            VideoCodec::H264 => HlsSegmentType::MpegTs,
+            VideoCodec::Hevc | VideoCodec::Av1 => HlsSegmentType::Fmp4,
+        }
+    }
+
+    /// Name stored in the database and used in logs
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VideoCodec::H264 => "h264",
+            VideoCodec::Hevc => "hevc",
+            VideoCodec::Av1 => "av1",
+        }
+    }
+
+    /// Bitrate needed relative to H.264 for comparable quality
+    pub fn bitrate_factor(&self) -> f64 {
+        match self {
+            VideoCodec::H264 => 1.0,
+            VideoCodec::Hevc => 0.6,
+            VideoCodec::Av1 => 0.5,
+        }
+    }
+}
+
+/// HDR transfer function of a source or rendition
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum HdrFormat {
+    Pq,
+    Hlg,
+}
+
+impl HdrFormat {
+    /// Detect HDR from ffprobe's `color_transfer`
+    pub fn from_transfer(transfer: &str) -> Option<Self> {
+        match transfer {
+            "smpte2084" => Some(HdrFormat::Pq),
+            "arib-std-b67" => Some(HdrFormat::Hlg),
+            _ => None,
+        }
+    }
+
+    /// FFmpeg/zimg name of the transfer characteristic
+    pub fn transfer(&self) -> &'static str {
+        match self {
+            HdrFormat::Pq => "smpte2084",
+            HdrFormat::Hlg => "arib-std-b67",
+        }
+    }
+
+    /// `VIDEO-RANGE` attribute value in the master playlist
+    pub fn video_range(&self) -> &'static str {
+        match self {
+            HdrFormat::Pq => "PQ",
+            HdrFormat::Hlg => "HLG",
+        }
+    }
+}
+
+/// Segment container of an HLS media playlist
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum HlsSegmentType {
+    MpegTs,
+    Fmp4,
+}
+
+impl HlsSegmentType {
+    /// Value for FFmpeg's `-hls_segment_type`
+    pub fn ffmpeg_name(&self) -> &'static str {
+        match self {
+            HlsSegmentType::MpegTs => "mpegts",
+            HlsSegmentType::Fmp4 => "fmp4",
+        }
+    }
+
+    /// Segment file extension
+    pub fn extension(&self) -> &'static str {
+        match self {
+            HlsSegmentType::MpegTs => "ts",
+            HlsSegmentType::Fmp4 => "m4s",
+        }
+    }
+}
+
+/// Codec of an HLS audio rendition
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioCodec {
+    #[default]
+    Aac,
+    Eac3,
+}
+
+impl AudioCodec {
+    /// FFmpeg encoder name
+    pub fn encoder(&self) -> &'static str {
+        match self {
+            AudioCodec::Aac => "aac",
+            AudioCodec::Eac3 => "eac3",
+        }
+    }
+
+    /// RFC 6381 codec string for the master playlist
+    pub fn codec_string(&self) -> &'static str {
+        match self {
+            AudioCodec::Aac => "mp4a.40.2",
+            AudioCodec::Eac3 => "ec-3",
+        }
+    }
+
+    /// Most channels the FFmpeg encoder accepts (E-AC-3 tops out at 5.1)
+    pub fn max_channels(&self) -> u32 {
+        match self {
+            AudioCodec::Aac => 8,
+            AudioCodec::Eac3 => 6,
+        }
+    }
+}
+
+/// An encoded audio playlist referenced from the master playlist
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AudioRendition {
+    pub group_id: String,
+    pub dir: String,
+    pub language: String,
+    pub name: String,
+    pub is_default: bool,
+    /// Index of the source audio stream (`0:a:N`)
+    pub track: usize,
+    pub codec: AudioCodec,
+    pub channels: u32,
+    /// Target bitrate in kbps
+    pub bitrate: u32,
+}
+
+/// A segmented WebVTT playlist referenced from the master playlist
 #[derive(Clone, Debug)]
+pub struct SubtitleRendition {
+    pub group_id: String,
+    pub dir: String,
+    pub language: String,
+    pub name: String,
+    pub is_default: bool,
+    pub is_forced: bool,
+}
+
+/// Segment encryption applied while packaging
+#[derive(Clone, Debug)]
+pub enum SegmentEncryption {
+    /// Whole-segment AES-128, FFmpeg reads key and key URI from this `-hls_key_info_file`
+    Aes128 { key_info: PathBuf },
+    /// Common Encryption (cenc) of fMP4 samples, keys released by the ClearKey license route
+    Cenc { kid: [u8; 16], key: [u8; 16] },
+}
+
+/// Per-video content key. Whoever runs the encode turns it into a `SegmentEncryption`,
+/// so it can travel to a remote worker.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ContentKey {
+    Aes128 { key: [u8; 16] },
+    Cenc { kid: [u8; 16], key: [u8; 16] },
+}
+
+/// One keyframe of an I-frame playlist, a byte range inside a media segment
+#[derive(Clone, Debug)]
+pub struct IFrameEntry {
+    pub uri: String,
+    /// Bytes of program tables (PAT/PMT) at the start of the segment, the
+    /// `EXT-X-MAP` a keyframe range needs to be demuxed on its own
+    pub tables_length: u64,
+    pub offset: u64,
+    pub length: u64,
+    /// Seconds until the next keyframe
+    pub duration: f64,
+}
+
+/// Result of `encode_to_hls`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HlsOutput {
+    /// Every rendition that made it into the master playlist
+    pub variants: Vec<VideoVariant>,
+    /// Audio playlists, grouped by GROUP-ID as listed in the master playlist
+    pub audio: Vec<AudioRendition>,
+    /// Integrated loudness (LUFS) per source audio track, measured when normalization is on
+    pub audio_loudness: Vec<Option<f64>>,
+    /// Bitmap subtitle tracks with an OCR companion at `subtitles/track_{idx}_ocr.srt`
+    pub ocr_subtitles: Vec<usize>,
+    /// Renditions with a subtitle track burned in, listed in `burned.m3u8`
+    pub burned_variants: Vec<VideoVariant>,
+    /// `thumbnails.vtt` and its sprite sheets were generated
+    pub trickplay: bool,
+    /// `preview.mp4` and `preview.webp` hover loops were generated
+    pub hover_preview: bool,
+}
+
+/// Probed properties of the source video stream
+#[derive(Clone, Debug)]
+pub struct VideoMetadata {
+    /// Coded frame size as stored in the stream
+    pub width: u32,
+    pub height: u32,
+    pub duration: u32,
+    pub fps: f64,
+    /// Sample (pixel) aspect ratio, 1:1 when unknown
+    pub sar: (u32, u32),
+    /// Display aspect ratio as reported by the container
+    pub dar: Option<(u32, u32)>,
+    /// Display rotation in degrees (0, 90, 180, 270)
+    pub rotation: u32,
+    /// HDR transfer function, None for SDR sources
+    pub hdr: Option<HdrFormat>,
+}
+
+impl VideoMetadata {
+    /// Frame size as shown to the viewer, after applying DAR (or SAR) and rotation
+    pub fn display_size(&self) -> (u32, u32) {
+        let width = match (self.dar, self.sar) {
+            (Some((dar_num, dar_den)), _) => {
+                ((self.height as f64 * dar_num as f64) / dar_den as f64).round() as u32
+            }
+            (None, (sar_num, sar_den)) if sar_num > 0 && sar_den > 0 => {
+                ((self.width as f64 * sar_num as f64) / sar_den as f64).round() as u32
+            }
+            _ => self.width,
+        };
+
+        if self.rotation % 180 == 90 {
+            (self.height, width)
+        } else {
+            (width, self.height)
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct VideoVariant {
     pub label: String,
+    pub width: u32,
     pub height: u32,
+    pub fps: f64,
     pub bitrate: u32, // in kbps
+    pub codec: VideoCodec,
+    /// 10-bit HDR rendition; None for the SDR (or tone-mapped) ladder
+    pub hdr: Option<HdrFormat>,
+    /// Segment container; H.264 is packaged as CMAF too when DASH output is on
+    pub segment_type: HlsSegmentType,
+    /// Subtitle track rendered into the picture; listed in `burned.m3u8` only
+    pub burned_subtitles: bool,
 }
 
 impl VideoVariant {
     /// Create a new variant with dynamically calculated bitrate based on resolution
     /// Uses bits-per-pixel (BPP) formula for optimal quality/size balance
-    pub fn new(label: &str, height: u32) -> Self {
+    pub fn new(label: &str, width: u32, height: u32, fps: f64) -> Self {
         Self {
             label: label.to_string(),
+            width,
             height,
-            bitrate: Self::calculate_bitrate(height),
+            fps,
+            bitrate: Self::calculate_bitrate(width, height, fps),
+            codec: VideoCodec::H264,
+            hdr: None,
+            segment_type: VideoCodec::H264.segment_type(),
+            burned_subtitles: false,
+        }
+    }
+
+    /// Same resolution tier encoded with another codec, with the bitrate scaled
+    /// to that codec's compression efficiency
+    pub fn with_codec(&self, codec: VideoCodec) -> Self {
+        let h264_bitrate = self.bitrate as f64 / self.codec.bitrate_factor();
+        Self {
+            label: self.label.clone(),
+            width: self.width,
+            height: self.height,
+            fps: self.fps,
+            bitrate: ((h264_bitrate * codec.bitrate_factor()).round() as u32).max(300),
+            codec,
+            hdr: self.hdr,
+            segment_type: codec.segment_type(),
+            burned_subtitles: self.burned_subtitles,
+        }
+    }
+
+    /// Same variant kept as 10-bit HDR with the source transfer function
+    pub fn with_hdr(mut self, hdr: Option<HdrFormat>) -> Self {
+        self.hdr = hdr;
+        self
+    }
+
+    /// Same variant packaged as CMAF fMP4 regardless of codec
+    pub fn with_cmaf(mut self, cmaf: bool) -> Self {
+        if cmaf {
+            self.segment_type = HlsSegmentType::Fmp4;
+        }
+        self
+    }
+
+    /// Same variant with the chosen subtitle track burned in
+    pub fn with_burned_subtitles(mut self, burned: bool) -> Self {
+        self.burned_subtitles = burned;
+        self
+    }
+
+    /// Codec family name as recorded per rendition, HDR and burned-in ladders are kept apart
+    pub fn codec_id(&self) -> String {
+        let family = match self.hdr {
+            None => self.codec.as_str().to_string(),
+            Some(hdr) => format!(
+                "{}-{}",
+                self.codec.as_str(),
+                hdr.video_range().to_lowercase()
+            ),
+        };
+        if self.burned_subtitles {
+            format!("{}-burned", family)
+        } else {
+            family
+        }
+    }
+
+    /// Bitrate the BPP model gives this size, frame rate and codec before per-title scaling
+    pub fn model_bitrate(&self) -> u32 {
+        let h264 = Self::calculate_bitrate(self.width, self.height, self.fps);
+        (h264 as f64 * self.codec.bitrate_factor()).round() as u32
+    }
+
+    /// Scale the bitrate by a per-title complexity factor
+    pub fn scale_bitrate(&mut self, factor: f64) {
+        self.bitrate = ((self.bitrate as f64 * factor).round() as u32).clamp(300, 20000);
+    }
+
+    /// Directory holding this variant's media playlist and segments
+    pub fn dir(&self) -> String {
+        match (self.hdr, self.burned_subtitles) {
+            (None, false) => self.codec.rendition_dir(&self.label),
+            (None, true) => self.codec.rendition_dir(&format!("burned_{}", self.label)),
+            (Some(_), _) => self.codec.rendition_dir(&format!("hdr_{}", self.label)),
         }
     }
 
     /// Calculate optimal bitrate based on resolution using BPP (bits per pixel)
     /// BPP of 0.1 is good for H.264 with motion (anime/live action)
     /// Formula: bitrate = width * height * fps * bpp
-    pub fn calculate_bitrate(height: u32) -> u32 {
-        // Assume 16:9 aspect ratio and 24fps (common for anime/movies)
-        let width = (height as f64 * 16.0 / 9.0).round() as u32;
-        let fps = 24.0;
-
+    pub fn calculate_bitrate(width: u32, height: u32, fps: f64) -> u32 {
         // BPP values tuned for H.264 encoding quality
-        // Higher resolutions can use lower BPP due to better compression efficiency
-        let bpp = match height {
+        // Higher resolutions can use lower BPP due to better compression efficiency.
+        // Tiers follow the short edge so vertical video is treated like its landscape twin.
+        let mut bpp = match width.min(height) {
             0..=480 => 0.12,     // SD needs higher BPP for quality
             481..=720 => 0.10,   // HD sweet spot
             721..=1080 => 0.08,  // FHD - good compression
@@ -60,6 +417,11 @@ impl VideoVariant {
             _ => 0.06,           // 4K+ - very efficient
         };
 
+        // Consecutive frames are more alike at high frame rates
+        if fps > 30.0 {
+            bpp *= 0.75;
+        }
+
         let bitrate_bps = (width as f64) * (height as f64) * fps * bpp;
         let bitrate_kbps = (bitrate_bps / 1000.0).round() as u32;
 
@@ -102,9 +464,10 @@ pub struct AppState {
     pub ffmpeg_semaphore: Arc<Semaphore>,
     pub clickhouse: clickhouse::Client,
     pub chunked_uploads: ChunkedUploadsMap,
+    pub remote_jobs: RemoteJobMap,
 }
 
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct UploadResponse {
     pub player_url: String,
     pub upload_id: String,
@@ -126,6 +489,12 @@ pub struct ProgressResponse {
     pub status: String,
     pub result: Option<UploadResponse>,
     pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variant_percentage: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encode_speed: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eta_seconds: Option<u64>,
 }
 
 #[derive(Deserialize)]
@@ -144,7 +513,12 @@ pub struct VideoDto {
     pub available_resolutions: Vec<String>,
     pub duration: u32,
     pub thumbnail_url: String,
-    pub sprites_url: Option<String>,
+    /// WebVTT index of the trickplay sprite sheets, None for videos without one
+    pub thumbnails_vtt_url: Option<String>,
+    /// Muted hover loop as H.264 MP4, None unless previews were enabled at encode time
+    pub preview_mp4_url: Option<String>,
+    /// Same loop as animated WebP
+    pub preview_webp_url: Option<String>,
     pub player_url: String,
     pub view_count: i64,
     pub created_at: String,
@@ -173,6 +547,10 @@ pub struct QueueItem {
     pub created_at: u64,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub variant_percentage: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encode_speed: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eta_seconds: Option<u64>,
 }
 
 #[derive(Serialize)]
@@ -202,10 +580,21 @@ pub struct ChunkUploadResponse {
     pub hash_verified: bool,
 }
 
+/// Per-upload processing options, sent as multipart fields or in the finalize body
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct UploadOptions {
+    /// Subtitle track (relative index) to render into an extra burned-in ladder
+    pub burn_subtitles: Option<usize>,
+    /// Named encoding profile from config.yml, the default profile when unset
+    pub profile: Option<String>,
+}
+
 #[derive(Deserialize)]
 pub struct FinalizeUploadRequest {
     pub name: String,
     pub tags: Option<String>,
+    #[serde(flatten)]
+    pub options: UploadOptions,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -220,6 +609,8 @@ pub struct SubtitleTrack {
     pub idx_storage_key: Option<String>, // For VobSub subtitles (.idx file)
     pub is_default: bool,
     pub is_forced: bool,
+    /// Bitmap track this text track was OCR'd from
+    pub source_track_index: Option<i32>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -243,6 +634,8 @@ pub struct AudioTrack {
     pub sample_rate: Option<i32>,
     pub bit_rate: Option<i64>,
     pub is_default: bool,
+    /// Measured integrated loudness in LUFS, set when the track was normalized
+    pub integrated_loudness: Option<f64>,
 }
 
 #[derive(Serialize)]
@@ -287,9 +680,11 @@ pub struct Chapter {
     pub start_time: f64,
     pub end_time: f64,
     pub title: String,
+    /// Generated from scene changes rather than read from the container
+    pub is_auto: bool,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ChapterInfo {
     pub start_time: f64,
     pub end_time: f64,
@@ -310,3 +705,221 @@ pub struct AttachmentListResponse {
 pub struct ChapterListResponse {
     pub chapters: Vec<Chapter>,
 }
+
+/// Skippable part of an episode
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MarkerKind {
+    Intro,
+    Recap,
+    Credits,
+}
+
+impl MarkerKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Intro => "intro",
+            Self::Recap => "recap",
+            Self::Credits => "credits",
+        }
+    }
+
+    pub fn from_db(kind: &str) -> Option<Self> {
+        match kind {
+            "intro" => Some(Self::Intro),
+            "recap" => Some(Self::Recap),
+            "credits" => Some(Self::Credits),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Marker {
+    pub kind: MarkerKind,
+    pub start_time: f64,
+    pub end_time: f64,
+    /// Found by audio fingerprinting rather than set by an admin
+    #[serde(default)]
+    pub is_auto: bool,
+}
+
+#[derive(Serialize)]
+pub struct MarkerListResponse {
+    pub markers: Vec<Marker>,
+}
+
+/// Replaces every marker of a video with these manual ones
+#[derive(Deserialize)]
+pub struct SetMarkersRequest {
+    pub markers: Vec<Marker>,
+}
+
+#[derive(Deserialize)]
+pub struct DetectMarkersRequest {
+    pub tag: String,
+}
+
+/// Outcome of intro/credits detection over one series
+#[derive(Serialize)]
+pub struct DetectMarkersResponse {
+    /// Episodes with stored fingerprints
+    pub episodes: usize,
+    pub intros: usize,
+    pub credits: usize,
+}
+
+/// Chromaprint fingerprint of the audio in one window of a video
+#[derive(Clone, Debug)]
+pub struct AudioFingerprint {
+    pub video_id: String,
+    /// Window start in seconds
+    pub start_time: f64,
+    pub items: Vec<u32>,
+}
+
+/// An encoded rendition and how its bitrate was chosen
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VideoRendition {
+    pub id: i64,
+    pub video_id: String,
+    pub codec: String,
+    pub label: String,
+    pub width: u32,
+    pub height: u32,
+    pub fps: f64,
+    /// Target bitrate in kbps
+    pub bitrate: u32,
+    /// Bitrate the BPP model suggested before per-title scaling
+    pub model_bitrate: u32,
+    /// Per-title complexity factor, None when the analysis pass did not run
+    pub complexity: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct RenditionListResponse {
+    pub items: Vec<VideoRendition>,
+}
+
+/// Ladder tier to encode for an existing video, e.g. "1440p"
+#[derive(Deserialize)]
+pub struct AddRenditionRequest {
+    pub label: String,
+}
+
+#[derive(Serialize)]
+pub struct RemoveRenditionResponse {
+    pub label: String,
+    pub available_resolutions: Vec<String>,
+}
+
+/// A frame picked while encoding that can be made the video's thumbnail
+#[derive(Serialize)]
+pub struct ThumbnailCandidate {
+    pub index: u32,
+    pub url: String,
+}
+
+#[derive(Serialize)]
+pub struct ThumbnailListResponse {
+    pub thumbnail_url: String,
+    pub candidates: Vec<ThumbnailCandidate>,
+}
+
+#[derive(Serialize)]
+pub struct UpdateThumbnailResponse {
+    pub thumbnail_url: String,
+}
+
+/// Content key of an encrypted video
+#[derive(Clone, Debug)]
+pub struct VideoKey {
+    /// "aes-128" or "cenc"
+    pub method: String,
+    /// Key ID, only set for CENC
+    pub kid: Option<Vec<u8>>,
+    pub key: Vec<u8>,
+}
+
+/// EME ClearKey license request (W3C Encrypted Media Extensions, section 9.1.3)
+#[derive(Debug, Deserialize)]
+pub struct ClearKeyLicenseRequest {
+    /// Requested key IDs, base64url without padding
+    pub kids: Vec<String>,
+    #[serde(default, rename = "type")]
+    pub session_type: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClearKeyJwk {
+    pub kty: &'static str,
+    pub kid: String,
+    pub k: String,
+}
+
+/// EME ClearKey license: a JSON Web Key set
+#[derive(Debug, Serialize)]
+pub struct ClearKeyLicense {
+    pub keys: Vec<ClearKeyJwk>,
+    #[serde(rename = "type")]
+    pub session_type: String,
+}
+
+/// Everything the encode stage needs besides the source file, handed to remote workers as is
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EncodeSpec {
+    pub video_id: String,
+    /// Storage prefix the encoded files are uploaded under
+    pub prefix: String,
+    pub video_config: VideoConfig,
+    pub profile: EncodingProfile,
+    /// Ladder before the per-title pass, which the encode stage runs
+    pub variants: Vec<VideoVariant>,
+    pub burn_subtitles: Option<usize>,
+    pub content_key: Option<ContentKey>,
+}
+
+/// An encode queued for, or running on, a remote worker; keyed by upload ID
+pub struct RemoteJob {
+    pub spec: EncodeSpec,
+    pub source: PathBuf,
+    pub created_at: u64,
+    /// Claim token of the worker running it and when that worker last reported
+    pub lease: Option<(String, u64)>,
+    pub done: oneshot::Sender<Result<RemoteJobResult, String>>,
+}
+
+pub type RemoteJobMap = Arc<RwLock<HashMap<String, RemoteJob>>>;
+
+/// A job handed to a worker. `job_id` is the claim token used in later calls.
+#[derive(Serialize, Deserialize)]
+pub struct ClaimedJob {
+    pub job_id: String,
+    /// Extension of the source file, FFmpeg probes some containers by name
+    pub source_extension: String,
+    pub spec: EncodeSpec,
+}
+
+/// Result of the encode stage, wherever it ran
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EncodedSource {
+    pub output: HlsOutput,
+    /// Per-title factor the ladder bitrates were scaled by
+    pub complexity: Option<f64>,
+    /// Container chapters, or the scene-detected ones when `chapters_are_auto`
+    pub chapters: Vec<ChapterInfo>,
+    pub chapters_are_auto: bool,
+}
+
+/// What a worker sends back once the encoded files are in the bucket
+#[derive(Serialize, Deserialize)]
+pub struct RemoteJobResult {
+    pub encoded: EncodedSource,
+    /// Key of the uploaded master playlist
+    pub entrypoint: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RemoteJobFailure {
+    pub error: String,
+}
@@ -1,15 +1,31 @@
+use crate::config::{
+    AutoChaptersConfig, ChunkedEncodingConfig, EncodingProfile, HoverPreviewConfig, LoudnessConfig,
+    OcrConfig, PerTitleConfig, RateControl, TrickplayConfig, VideoConfig,
+};
+use crate::hls;
 use crate::types::{
-    AttachmentInfo, AudioStreamInfo, ChapterInfo, ProgressMap, ProgressUpdate, SubtitleStreamInfo,
-    VideoVariant,
+    AttachmentInfo, AudioCodec, AudioRendition, AudioStreamInfo, ChapterInfo, HdrFormat, HlsOutput,
+    HlsSegmentType, IFrameEntry, ProgressMap, ProgressUpdate, SegmentEncryption, SubtitleRendition,
+    SubtitleStreamInfo, VideoCodec, VideoMetadata, VideoVariant,
 };
 use anyhow::{Context, Result};
-use std::path::PathBuf;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::path::{Path, PathBuf};
 use tokio::io::AsyncReadExt;
+use tokio::sync::Semaphore;
 use tokio::time::{Duration, sleep};
 use tokio::{fs, process::Command};
 use tracing::{error, info, warn};
 
-pub async fn get_video_metadata(input: &PathBuf) -> Result<(u32, u32)> {
+/// Parse an ffprobe rational such as "30000/1001" or "16:9"
+fn parse_ratio(value: Option<&str>, separator: char) -> Option<(u32, u32)> {
+    let (num, den) = value?.split_once(separator)?;
+    let num: u32 = num.trim().parse().ok()?;
+    let den: u32 = den.trim().parse().ok()?;
+    (num > 0 && den > 0).then_some((num, den))
+}
+
+pub async fn get_video_metadata(input: &PathBuf) -> Result<VideoMetadata> {
     // Using JSON output
     let output = Command::new("ffprobe")
         .arg("-v")
@@ -17,7 +33,7 @@ pub async fn get_video_metadata(input: &PathBuf) -> Result<(u32, u32)> {
         .arg("-select_streams")
         .arg("v:0")
         .arg("-show_entries")
-        .arg("stream=height:format=duration")
+        .arg("stream=width,height,avg_frame_rate,r_frame_rate,sample_aspect_ratio,display_aspect_ratio,color_transfer:stream_tags=rotate:stream_side_data=rotation:format=duration")
         .arg("-of")
         .arg("json")
         .arg(input)
@@ -31,28 +47,52 @@ pub async fn get_video_metadata(input: &PathBuf) -> Result<(u32, u32)> {
 
     let json_str = String::from_utf8(output.stdout)?;
     let v: serde_json::Value = serde_json::from_str(&json_str)?;
+    let stream = &v["streams"][0];
 
-    let height = v["streams"][0]["height"]
-        .as_u64()
-        .context("no height found")? as u32;
+    let width = stream["width"].as_u64().context("no width found")? as u32;
+    let height = stream["height"].as_u64().context("no height found")? as u32;
     let duration_str = v["format"]["duration"]
         .as_str()
         .context("no duration found")?;
     let duration: f64 = duration_str.parse()?;
 
-    Ok((height, duration.round() as u32))
-}
-
-pub async fn get_video_height(input: &PathBuf) -> Result<u32> {
-    // Keep for backward compatibility or individual usage
-    let (h, _) = get_video_metadata(input).await?;
-    Ok(h)
-}
+    // avg_frame_rate is reliable for VFR containers where r_frame_rate can be a timebase
+    let fps = [
+        stream["avg_frame_rate"].as_str(),
+        stream["r_frame_rate"].as_str(),
+    ]
+    .into_iter()
+    .filter_map(|rate| parse_ratio(rate, '/'))
+    .map(|(num, den)| num as f64 / den as f64)
+    .find(|fps| (1.0..=240.0).contains(fps))
+    .unwrap_or(24.0);
+
+    let sar = parse_ratio(stream["sample_aspect_ratio"].as_str(), ':').unwrap_or((1, 1));
+    let dar = parse_ratio(stream["display_aspect_ratio"].as_str(), ':');
+
+    // Newer FFmpeg reports a display matrix in side data, older builds a "rotate" tag
+    let rotation = stream["side_data_list"]
+        .as_array()
+        .and_then(|list| list.iter().find_map(|sd| sd["rotation"].as_i64()))
+        .or_else(|| stream["tags"]["rotate"].as_str()?.parse().ok())
+        .unwrap_or(0)
+        .rem_euclid(360) as u32;
 
-pub async fn get_video_duration(input: &PathBuf) -> Result<u32> {
-    // Keep for backward compatibility or individual usage
-    let (_, d) = get_video_metadata(input).await?;
-    Ok(d)
+    // PQ (HDR10) and HLG are both signalled through the transfer characteristic
+    let hdr = stream["color_transfer"]
+        .as_str()
+        .and_then(HdrFormat::from_transfer);
+
+    Ok(VideoMetadata {
+        width,
+        height,
+        duration: duration.round() as u32,
+        fps,
+        sar,
+        dar,
+        rotation,
+        hdr,
+    })
 }
 
 // Get audio stream information from video file using ffprobe
@@ -254,6 +294,164 @@ pub async fn get_chapters(input: &PathBuf) -> Result<Vec<ChapterInfo>> {
     Ok(chapters)
 }
 
+/// Timestamps of shot changes whose `scene` score exceeds `threshold`
+async fn detect_scene_changes(input: &PathBuf, threshold: f64, duration: f64) -> Result<Vec<f64>> {
+    // Scene scores barely change with resolution, so analyse a small copy. Selected
+    // frames are listed in a file; on stderr they could fill the pipe and stall FFmpeg.
+    let frames_path = std::env::temp_dir().join(format!("scenes-{}.txt", uuid::Uuid::new_v4()));
+    let mut cmd = Command::new("ffmpeg");
+    cmd.arg("-loglevel")
+        .arg("error")
+        .arg("-i")
+        .arg(input)
+        .arg("-map")
+        .arg("0:v:0")
+        .arg("-an")
+        .arg("-sn")
+        .arg("-vf")
+        .arg(format!(
+            "scale=320:-2,select='gt(scene,{})',metadata=print:file={}",
+            threshold,
+            filter_path(&frames_path)
+        ))
+        .arg("-f")
+        .arg("null")
+        .arg("-");
+
+    let timeout = Duration::from_secs((duration as u64).saturating_mul(5).max(10 * 60));
+    let output = run_ffmpeg_with_timeout(cmd, timeout, "scene detection", None).await;
+    let frames = fs::read_to_string(&frames_path).await;
+    let _ = fs::remove_file(&frames_path).await;
+    let output = output?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "scene detection failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    // One "frame:N pts:P pts_time:T" line per selected frame, then its metadata
+    let frames = frames.unwrap_or_default();
+    let mut cuts: Vec<f64> = frames
+        .lines()
+        .filter(|line| line.starts_with("frame:"))
+        .filter_map(|line| {
+            line.split_once("pts_time:")?
+                .1
+                .split_whitespace()
+                .next()?
+                .parse()
+                .ok()
+        })
+        .collect();
+    cuts.sort_by(f64::total_cmp);
+    Ok(cuts)
+}
+
+/// Merge shot changes into chapters: the video is split into evenly sized parts
+/// (no shorter than `min_chapter_duration`, at most `max_chapters`) and each inner
+/// boundary snaps to the nearest shot change within half a part. Boundaries
+/// without a nearby shot change are dropped, merging their neighbours.
+fn chapters_from_scene_changes(
+    cuts: &[f64],
+    duration: f64,
+    config: &AutoChaptersConfig,
+) -> Vec<ChapterInfo> {
+    let min_length = config.min_chapter_duration.max(1.0);
+    let parts = ((duration / min_length).floor() as u32).min(config.max_chapters);
+    if parts < 2 {
+        return Vec::new();
+    }
+    let part_length = duration / parts as f64;
+
+    let mut boundaries = vec![0.0];
+    for k in 1..parts {
+        let target = k as f64 * part_length;
+        let previous = *boundaries.last().unwrap_or(&0.0);
+        let nearest = cuts
+            .iter()
+            .copied()
+            .filter(|cut| cut - previous >= min_length && duration - cut >= min_length)
+            .filter(|cut| (cut - target).abs() <= part_length / 2.0)
+            .min_by(|a, b| (a - target).abs().total_cmp(&(b - target).abs()));
+        if let Some(cut) = nearest {
+            boundaries.push(cut);
+        }
+    }
+    if boundaries.len() < 2 {
+        return Vec::new();
+    }
+    boundaries.push(duration);
+
+    boundaries
+        .windows(2)
+        .enumerate()
+        .map(|(i, bounds)| ChapterInfo {
+            start_time: bounds[0],
+            end_time: bounds[1],
+            title: format!("Chapter {}", i + 1),
+        })
+        .collect()
+}
+
+/// Chapters generated from scene-change detection, for sources without container chapters
+pub async fn generate_scene_chapters(
+    input: &PathBuf,
+    duration: f64,
+    config: &AutoChaptersConfig,
+) -> Result<Vec<ChapterInfo>> {
+    let cuts = detect_scene_changes(input, config.scene_threshold, duration).await?;
+    info!("Scene detection found {} shot changes", cuts.len());
+    Ok(chapters_from_scene_changes(&cuts, duration, config))
+}
+
+/// Raw chromaprint fingerprint of `length` seconds of the first audio track from `start`
+pub async fn fingerprint_audio(input: &PathBuf, start: f64, length: f64) -> Result<Vec<u32>> {
+    let out_path = std::env::temp_dir().join(format!("fingerprint-{}.raw", uuid::Uuid::new_v4()));
+
+    let mut cmd = Command::new("ffmpeg");
+    cmd.arg("-loglevel")
+        .arg("error")
+        .arg("-y")
+        .arg("-ss")
+        .arg(format!("{:.3}", start))
+        .arg("-t")
+        .arg(format!("{:.3}", length))
+        .arg("-i")
+        .arg(input)
+        .arg("-map")
+        .arg("0:a:0")
+        .arg("-ac")
+        .arg("1")
+        .arg("-f")
+        .arg("chromaprint")
+        .arg("-fp_format")
+        .arg("raw")
+        .arg(&out_path);
+
+    let result = async {
+        let output =
+            run_ffmpeg_with_timeout(cmd, Duration::from_secs(10 * 60), "audio fingerprint", None)
+                .await?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "audio fingerprint failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        // The raw format is the fingerprint's u32 items, little-endian like the stored blobs
+        let data = fs::read(&out_path).await?;
+        Ok(data
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect())
+    }
+    .await;
+
+    let _ = fs::remove_file(&out_path).await;
+    result
+}
+
 /// Check if a subtitle codec is a bitmap-based format (PGS, VobSub, DVB)
 pub fn is_bitmap_subtitle(codec: &str) -> bool {
     matches!(
@@ -397,7 +595,7 @@ pub struct VobSubExtractionResult {
 pub async fn extract_vobsub_subtitle(
     input: &PathBuf,
     subtitle_index: i32,
-    output_dir: &PathBuf,
+    output_dir: &Path,
     track_idx: usize,
 ) -> Result<VobSubExtractionResult> {
     let idx_filename = format!("track_{}.idx", track_idx);
@@ -506,32 +704,208 @@ pub async fn extract_all_attachments(input: &PathBuf, output_dir: &PathBuf) -> R
     Ok(())
 }
 
-pub fn get_variants_for_height(original_height: u32) -> Vec<VideoVariant> {
-    let resolution_tiers: [(u32, &str); 6] = [
-        (360, "360p"),
-        (480, "480p"),
-        (720, "720p"),
-        (1080, "1080p"),
-        (1440, "1440p"),
-        (2160, "4K"),
-    ];
+/// Highest output frame rate; faster sources are decimated by an integer factor
+const MAX_OUTPUT_FPS: f64 = 60.0;
+
+/// Label of a ladder tier given by its short edge
+pub fn tier_label(tier: u32) -> String {
+    match tier {
+        2160 => "4K".to_string(),
+        _ => format!("{}p", tier),
+    }
+}
+
+/// Build the rendition ladder for a source from the profile's `tiers`.
+///
+/// Each tier is a 16:9 box (rotated for vertical video) that the display frame is
+/// fitted into, so letterboxed, 4:3 and portrait sources keep their aspect ratio.
+/// Tiers that would upscale the source are skipped.
+pub fn build_variant_ladder(meta: &VideoMetadata, tiers: &[u32]) -> Vec<VideoVariant> {
+    let mut tiers = tiers.to_vec();
+    tiers.sort_unstable();
+    tiers.dedup();
+
+    let (src_width, src_height) = meta.display_size();
+    let portrait = src_height > src_width;
+
+    let mut fps = meta.fps;
+    while fps > MAX_OUTPUT_FPS + 0.5 {
+        fps /= 2.0;
+    }
+
+    let even = |value: f64| (((value / 2.0).round() as u32) * 2).max(2);
 
     // Generate variants dynamically with calculated bitrates
-    let mut variants: Vec<VideoVariant> = resolution_tiers
+    let mut variants: Vec<VideoVariant> = tiers
         .iter()
-        .filter(|(height, _)| *height <= original_height)
-        .map(|(height, label)| VideoVariant::new(label, *height))
+        .filter_map(|tier| {
+            let box_long = (*tier as f64 * 16.0 / 9.0).round();
+            let box_short = *tier as f64;
+            let (box_width, box_height) = if portrait {
+                (box_short, box_long)
+            } else {
+                (box_long, box_short)
+            };
+
+            let factor = (box_width / src_width as f64).min(box_height / src_height as f64);
+            // Small tolerance so e.g. 1912x1072 still counts as 1080p
+            if factor > 1.01 {
+                return None;
+            }
+            let factor = factor.min(1.0);
+
+            Some(VideoVariant::new(
+                &tier_label(*tier),
+                even(src_width as f64 * factor),
+                even(src_height as f64 * factor),
+                fps,
+            ))
+        })
         .collect();
 
-    // If no standard tier fits (video shorter than 360p), use original height
+    // If no standard tier fits (video smaller than 360p), use the original size
     if variants.is_empty() {
-        let label = format!("{}p", original_height);
-        variants.push(VideoVariant::new(&label, original_height));
+        let label = format!("{}p", src_width.min(src_height));
+        variants.push(VideoVariant::new(
+            &label,
+            even(src_width as f64),
+            even(src_height as f64),
+            fps,
+        ));
     }
 
     variants
 }
 
+/// Bounds of the per-title complexity factor applied to the BPP ladder
+const MIN_COMPLEXITY: f64 = 0.4;
+const MAX_COMPLEXITY: f64 = 1.6;
+
+/// Measure how hard a title is to compress.
+///
+/// Sampled segments are encoded with x264 at a fixed CRF at the ladder rung closest
+/// to 720p. The ratio between the bitrate CRF needed and the BPP model bitrate for
+/// that rung is the complexity factor: static anime lands well below 1.0, grainy or
+/// high-motion footage above it.
+pub async fn probe_complexity(
+    input: &PathBuf,
+    meta: &VideoMetadata,
+    ladder: &[VideoVariant],
+    config: &PerTitleConfig,
+) -> Result<f64> {
+    let probe = ladder
+        .iter()
+        .rfind(|v| v.width.min(v.height) <= 720)
+        .or_else(|| ladder.first())
+        .context("empty ladder")?;
+
+    let samples = config.samples.max(1);
+    let sample_duration = config.sample_duration.max(1) as f64;
+    let duration = meta.duration.max(1) as f64;
+
+    // Short videos are probed in one go
+    let windows: Vec<(f64, f64)> = if duration <= samples as f64 * sample_duration {
+        vec![(0.0, duration)]
+    } else {
+        (0..samples)
+            .map(|i| {
+                let center = duration * (i as f64 + 0.5) / samples as f64;
+                ((center - sample_duration / 2.0).max(0.0), sample_duration)
+            })
+            .collect()
+    };
+
+    let probe_dir = std::env::temp_dir().join(format!("probe-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&probe_dir).await?;
+
+    let result = async {
+        let mut total_bytes = 0u64;
+        let mut total_seconds = 0.0;
+
+        for (idx, (start, length)) in windows.iter().enumerate() {
+            let out_path = probe_dir.join(format!("sample_{}.ts", idx));
+
+            let mut cmd = Command::new("ffmpeg");
+            cmd.arg("-loglevel")
+                .arg("error")
+                .arg("-y")
+                .arg("-ss")
+                .arg(format!("{:.3}", start))
+                .arg("-t")
+                .arg(format!("{:.3}", length))
+                .arg("-i")
+                .arg(input)
+                .arg("-map")
+                .arg("0:v:0")
+                .arg("-an")
+                .arg("-sn")
+                .arg("-vf")
+                .arg(format!(
+                    "{}scale={}:{},setsar=1",
+                    meta.hdr
+                        .map(|hdr| tonemap_filter(hdr) + ",")
+                        .unwrap_or_default(),
+                    probe.width,
+                    probe.height
+                ))
+                .arg("-r")
+                .arg(format!("{:.3}", probe.fps))
+                .arg("-c:v")
+                .arg("libx264")
+                .arg("-preset")
+                .arg("veryfast")
+                .arg("-crf")
+                .arg(config.crf.to_string())
+                .arg("-pix_fmt")
+                .arg("yuv420p")
+                .arg("-f")
+                .arg("mpegts")
+                .arg(&out_path);
+
+            let output = run_ffmpeg_with_timeout(
+                cmd,
+                Duration::from_secs(10 * 60),
+                "complexity probe",
+                None,
+            )
+            .await?;
+            if !output.status.success() {
+                anyhow::bail!(
+                    "complexity probe failed: {}",
+                    String::from_utf8_lossy(&output.stderr)
+                );
+            }
+
+            total_bytes += fs::metadata(&out_path).await?.len();
+            total_seconds += length.min(duration - start);
+        }
+
+        if total_seconds <= 0.0 {
+            anyhow::bail!("complexity probe produced no samples");
+        }
+
+        let measured_kbps = total_bytes as f64 * 8.0 / total_seconds / 1000.0;
+        let factor =
+            (measured_kbps / probe.model_bitrate() as f64).clamp(MIN_COMPLEXITY, MAX_COMPLEXITY);
+
+        info!(
+            "Complexity probe at {}x{}: {:.0}kbps at CRF {} vs {}kbps model, factor {:.2}",
+            probe.width,
+            probe.height,
+            measured_kbps,
+            config.crf,
+            probe.model_bitrate(),
+            factor
+        );
+
+        Ok(factor)
+    }
+    .await;
+
+    let _ = fs::remove_dir_all(&probe_dir).await;
+    result
+}
+
 #[derive(Debug, Clone, PartialEq)]
 enum EncoderType {
     Nvenc,
@@ -556,14 +930,24 @@ impl EncoderType {
         }
     }
 
-    /// Get the video codec name for this encoder type
-    fn video_codec(&self) -> &'static str {
-        match self {
-            EncoderType::Nvenc => "h264_nvenc",
-            EncoderType::Amf => "h264_amf",
-            EncoderType::Vaapi => "h264_vaapi",
-            EncoderType::Qsv => "h264_qsv",
-            EncoderType::Cpu => "libx264",
+    /// Get the FFmpeg encoder name for this encoder type and codec family
+    fn video_codec(&self, codec: VideoCodec) -> &'static str {
+        match (self, codec) {
+            (EncoderType::Nvenc, VideoCodec::H264) => "h264_nvenc",
+            (EncoderType::Nvenc, VideoCodec::Hevc) => "hevc_nvenc",
+            (EncoderType::Nvenc, VideoCodec::Av1) => "av1_nvenc",
+            (EncoderType::Amf, VideoCodec::H264) => "h264_amf",
+            (EncoderType::Amf, VideoCodec::Hevc) => "hevc_amf",
+            (EncoderType::Amf, VideoCodec::Av1) => "av1_amf",
+            (EncoderType::Vaapi, VideoCodec::H264) => "h264_vaapi",
+            (EncoderType::Vaapi, VideoCodec::Hevc) => "hevc_vaapi",
+            (EncoderType::Vaapi, VideoCodec::Av1) => "av1_vaapi",
+            (EncoderType::Qsv, VideoCodec::H264) => "h264_qsv",
+            (EncoderType::Qsv, VideoCodec::Hevc) => "hevc_qsv",
+            (EncoderType::Qsv, VideoCodec::Av1) => "av1_qsv",
+            (EncoderType::Cpu, VideoCodec::H264) => "libx264",
+            (EncoderType::Cpu, VideoCodec::Hevc) => "libx265",
+            (EncoderType::Cpu, VideoCodec::Av1) => "libsvtav1",
         }
     }
 }
@@ -630,607 +1014,2652 @@ fn get_language_display_name(lang_code: &str) -> String {
     }
 }
 
-pub async fn encode_to_hls(
-    input: &PathBuf,
-    out_dir: &PathBuf,
-    progress: &ProgressMap,
-    upload_id: &str,
-    encoder: &str,
-    duration: u32,
-    audio_streams: &[AudioStreamInfo],
-) -> Result<()> {
-    fs::create_dir_all(out_dir).await?;
+/// Add `-progress pipe:1` so `run_ffmpeg_with_timeout` can report live progress.
+/// Must be added before the first input, FFmpeg ignores trailing global options.
+fn push_progress_args(cmd: &mut Command) {
+    cmd.arg("-progress").arg("pipe:1").arg("-nostats");
+}
 
-    // Get original video height to determine appropriate variants
-    let (original_height, _) = get_video_metadata(input).await?;
-    let variants = get_variants_for_height(original_height);
+/// Parse FFmpeg `-progress` key=value blocks from stdout and publish per-step
+/// percentage, speed and ETA until the stream closes.
+async fn forward_ffmpeg_progress(
+    stdout: tokio::process::ChildStdout,
+    progress: ProgressMap,
+    upload_id: String,
+    duration: f64,
+) {
+    use tokio::io::AsyncBufReadExt;
+
+    let mut lines = tokio::io::BufReader::new(stdout).lines();
+    let mut out_time = 0.0f64;
+    let mut speed: Option<f32> = None;
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        match key {
+            // Both keys are microseconds; out_time_ms is misnamed upstream
+            "out_time_us" | "out_time_ms" => {
+                if let Ok(us) = value.trim().parse::<i64>() {
+                    out_time = us.max(0) as f64 / 1_000_000.0;
+                }
+            }
+            "speed" => {
+                speed = value
+                    .trim()
+                    .trim_end_matches('x')
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|s| *s > 0.0);
+            }
+            // Each block ends with progress=continue|end
+            "progress" => {
+                let percentage = if duration > 0.0 {
+                    ((out_time / duration) * 100.0).clamp(0.0, 100.0) as u32
+                } else {
+                    0
+                };
+                let eta_seconds =
+                    speed.map(|s| ((duration - out_time).max(0.0) / s as f64).round() as u64);
+
+                let mut progress_map = progress.write().await;
+                if let Some(entry) = progress_map.get_mut(&upload_id)
+                    && entry.status == "processing"
+                {
+                    entry.variant_percentage = Some(percentage);
+                    entry.encode_speed = speed;
+                    entry.eta_seconds = eta_seconds;
+                }
+            }
+            _ => {}
+        }
+    }
+}
 
-    if variants.is_empty() {
-        anyhow::bail!("No suitable variants for video height {}", original_height);
+/// Helper that runs ffmpeg and kills it on timeout, or when the returned future is
+/// dropped.
+///
+/// With `live` set, the command must have been built with `push_progress_args`;
+/// its progress is then published to the job's upload entry while it runs.
+async fn run_ffmpeg_with_timeout(
+    mut cmd: Command,
+    timeout_duration: Duration,
+    context_label: &str,
+    live: Option<&EncodeJob>,
+) -> Result<std::process::Output> {
+    let stdout = if live.is_some() {
+        std::process::Stdio::piped()
+    } else {
+        std::process::Stdio::null()
+    };
+    cmd.stdout(stdout)
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true);
+
+    let mut child = cmd.spawn().context("failed to spawn ffmpeg")?;
+
+    let progress_task = match (live, child.stdout.take()) {
+        (Some(job), Some(stdout)) => Some(tokio::spawn(forward_ffmpeg_progress(
+            stdout,
+            job.progress.clone(),
+            job.upload_id.clone(),
+            job.duration,
+        ))),
+        _ => None,
+    };
+
+    // Capture stderr without moving `child` (wait_with_output consumes Child).
+    let mut stderr_handle = child.stderr.take();
+    let mut stderr = Vec::new();
+
+    let result = tokio::select! {
+        status = child.wait() => {
+            let status = status.context("failed to wait for ffmpeg")?;
+            if let Some(ref mut err) = stderr_handle {
+                let _ = err.read_to_end(&mut stderr).await;
+            }
+            Ok(std::process::Output { status, stdout: Vec::new(), stderr })
+        }
+        _ = sleep(timeout_duration) => {
+            let _ = child.kill().await;
+            let _ = child.wait().await;
+            if let Some(ref mut err) = stderr_handle {
+                let _ = err.read_to_end(&mut stderr).await;
+            }
+            Err(anyhow::anyhow!("ffmpeg timed out during {context_label} after {:?}", timeout_duration))
+        }
+    };
+
+    if let Some(task) = progress_task {
+        let _ = task.await;
     }
 
-    let encoder_type = EncoderType::from_string(encoder);
+    result
+}
 
-    // GOP size - use 48 for 24fps content (2 seconds), adjust for HLS segment alignment
-    let gop = 48;
+/// Shared state of one `encode_to_hls` run
+struct EncodeJob {
+    input: PathBuf,
+    source_fps: f64,
+    /// Source duration in seconds, the denominator of live progress
+    duration: f64,
+    /// HDR transfer of the source; SDR variants are tone-mapped when set
+    source_hdr: Option<HdrFormat>,
+    progress: ProgressMap,
+    upload_id: String,
+    ffmpeg_timeout: Duration,
+    total_steps: u32,
+    /// Segment encryption (AES-128 or CENC)
+    encryption: Option<SegmentEncryption>,
+    /// Subtitle track rendered into the burned-in ladder
+    burn_in: Option<BurnIn>,
+    profile: EncodingProfile,
+}
 
-    let input = input.clone();
-    let out_dir = out_dir.clone();
-    let upload_id = upload_id.to_string();
-    let audio_streams = audio_streams.to_vec();
+/// Part of the source encoded by its own FFmpeg process in a chunked encode
+#[derive(Debug, Clone, Copy)]
+struct TimeRange {
+    /// Start in seconds, always on a segment boundary
+    start: u64,
+    /// Length in seconds; the last range runs to the end of the source
+    length: Option<u64>,
+    /// Number of the range's first segment in the joined playlist
+    first_segment: u64,
+}
 
-    // Total tasks = video variants + audio streams + thumbnail + sprites
-    let total_steps = variants.len() as u32 + audio_streams.len() as u32 + 2;
+/// Ranges a source of `duration` seconds is encoded in, or none for a single pass.
+/// Ranges are whole segments long: each starts on a keyframe forced at a segment
+/// boundary, so the joined segments match those of a single-pass encode.
+fn chunk_ranges(
+    config: &ChunkedEncodingConfig,
+    duration: f64,
+    segment_duration: u32,
+) -> Vec<TimeRange> {
+    if !config.enabled || duration < config.min_duration as f64 {
+        return Vec::new();
+    }
+    let segment = segment_duration.max(1) as u64;
+    let length = (config.chunk_duration as u64 / segment).max(1) * segment;
+    // The remainder is added to the last range rather than left as a short one
+    let count = (duration / length as f64).floor() as u64;
+    if count < 2 {
+        return Vec::new();
+    }
 
-    // Timeout heuristic: long enough for slow encodes, but not infinite.
-    //  - minimum 30 minutes
-    //  - ~20x realtime based on duration
-    //  - cap at 6 hours per ffmpeg invocation
-    let ffmpeg_timeout = Duration::from_secs((duration as u64).saturating_mul(20).max(30 * 60))
-        .min(Duration::from_secs(6 * 60 * 60));
+    (0..count)
+        .map(|idx| TimeRange {
+            start: idx * length,
+            length: (idx + 1 < count).then_some(length),
+            first_segment: idx * length / segment,
+        })
+        .collect()
+}
 
-    // Helper that runs ffmpeg and kills it on timeout.
-    async fn run_ffmpeg_with_timeout(
-        mut cmd: Command,
-        timeout_duration: Duration,
-        context_label: &str,
-    ) -> Result<std::process::Output> {
-        cmd.stdout(std::process::Stdio::null())
-            .stderr(std::process::Stdio::piped());
+/// How a subtitle track is drawn onto the burned-in ladder
+enum BurnIn {
+    /// `ass`/`subtitles` filter over a track extracted with the source's fonts
+    Text(String),
+    /// Bitmap track overlaid straight from the source's Nth subtitle stream
+    Bitmap(usize),
+}
 
-        let mut child = cmd.spawn().context("failed to spawn ffmpeg")?;
+/// Escape a path for a filter option value inside a filtergraph
+fn filter_path(path: &Path) -> String {
+    path.to_string_lossy()
+        .replace('\\', "/")
+        .replace(':', "\\\\:")
+}
 
-        // Capture stderr without moving `child` (wait_with_output consumes Child).
-        let mut stderr_handle = child.stderr.take();
-        let mut stderr = Vec::new();
+/// Prepare subtitle track `idx` for burning in. Text tracks are extracted into
+/// `work_dir` together with the font attachments their styling refers to.
+async fn prepare_burn_in(
+    input: &PathBuf,
+    sub: &SubtitleStreamInfo,
+    idx: usize,
+    work_dir: &Path,
+) -> Result<BurnIn> {
+    if is_bitmap_subtitle(&sub.codec_name) {
+        return Ok(BurnIn::Bitmap(idx));
+    }
 
-        tokio::select! {
-            status = child.wait() => {
-                let status = status.context("failed to wait for ffmpeg")?;
-                if let Some(ref mut err) = stderr_handle {
-                    let _ = err.read_to_end(&mut stderr).await;
-                }
-                Ok(std::process::Output { status, stdout: Vec::new(), stderr })
-            }
-            _ = sleep(timeout_duration) => {
-                let _ = child.kill().await;
-                let _ = child.wait().await;
-                if let Some(ref mut err) = stderr_handle {
-                    let _ = err.read_to_end(&mut stderr).await;
-                }
-                anyhow::bail!("ffmpeg timed out during {context_label} after {:?}", timeout_duration);
-            }
-        }
+    let fonts_dir = work_dir.join("fonts");
+    fs::create_dir_all(&fonts_dir).await?;
+    if let Err(e) = extract_all_attachments(input, &fonts_dir).await {
+        warn!("Burning subtitles without font attachments: {}", e);
     }
 
-    // Encode video variants sequentially (avoids spawning many tasks for large batches).
-    for (index, variant) in variants.clone().iter().enumerate() {
-        let seg_dir = out_dir.join(&variant.label);
-        fs::create_dir_all(&seg_dir).await?;
-        let playlist_path = seg_dir.join("index.m3u8");
-        let segment_pattern = seg_dir.join("segment_%03d.ts");
+    let extension = get_subtitle_extension(&sub.codec_name);
+    let track_path = work_dir.join(format!("track.{}", extension));
+    extract_subtitle(input, idx as i32, &track_path, &sub.codec_name).await?;
 
-        info!(
-            "Encoding variant: {} at {}p with bitrate {}kbps (max: {}kbps)",
-            variant.label,
-            variant.height,
-            variant.bitrate,
-            variant.max_bitrate()
+    // libass renders ASS typesetting as authored; other text formats get default styling
+    let filter = if extension == "ass" {
+        "ass"
+    } else {
+        "subtitles"
+    };
+    Ok(BurnIn::Text(format!(
+        "{}=filename={}:fontsdir={}",
+        filter,
+        filter_path(&track_path),
+        filter_path(&fonts_dir)
+    )))
+}
+
+impl EncodeJob {
+    /// Publish an FFmpeg step, keeping the name and creation time set by the upload handler
+    async fn report(&self, current_chunk: u32, details: String) {
+        let percentage = ((current_chunk as f32 / self.total_steps as f32) * 100.0) as u32;
+        let mut progress_map = self.progress.write().await;
+        let (video_name, created_at) = progress_map
+            .get(&self.upload_id)
+            .map(|p| (p.video_name.clone(), p.created_at))
+            .unwrap_or((None, 0));
+        progress_map.insert(
+            self.upload_id.clone(),
+            ProgressUpdate {
+                stage: "FFmpeg processing".to_string(),
+                current_chunk,
+                total_chunks: self.total_steps,
+                percentage,
+                details: Some(details),
+                status: "processing".to_string(),
+                result: None,
+                error: None,
+                video_name,
+                created_at,
+                variant_percentage: None,
+                encode_speed: None,
+                eta_seconds: None,
+            },
         );
+    }
+}
 
-        // Update progress before starting this variant
-        let current_chunk = (index + 1) as u32;
-        let percentage = ((current_chunk as f32 / total_steps as f32) * 100.0) as u32;
-        let (existing_video_name, existing_created_at) = {
-            let progress_map = progress.read().await;
-            progress_map
-                .get(&upload_id)
-                .map(|p| (p.video_name.clone(), p.created_at))
-                .unwrap_or((None, 0))
-        };
-        let start_progress = ProgressUpdate {
-            stage: "FFmpeg processing".to_string(),
-            current_chunk,
-            total_chunks: total_steps,
-            percentage,
-            details: Some(format!(
-                "Encoding variant: {} ({}p)",
-                variant.label, variant.height
-            )),
-            status: "processing".to_string(),
-            result: None,
-            error: None,
-            video_name: existing_video_name.clone(),
-            created_at: existing_created_at,
-            variant_percentage: None,
-        };
-        progress
-            .write()
-            .await
-            .insert(upload_id.clone(), start_progress);
+/// Add HLS muxer arguments writing `index.m3u8` and its segments into `dir`,
+/// numbered from `start_number`
+fn push_hls_output_args(
+    cmd: &mut Command,
+    job: &EncodeJob,
+    dir: &Path,
+    segment_type: HlsSegmentType,
+    start_number: u64,
+) {
+    let segment_pattern = dir.join(format!("segment_%03d.{}", segment_type.extension()));
+
+    cmd.arg("-hls_time")
+        .arg(job.profile.segment_duration.max(1).to_string())
+        .arg("-hls_list_size")
+        .arg("0")
+        .arg("-hls_playlist_type")
+        .arg("vod")
+        .arg("-hls_segment_type")
+        .arg(segment_type.ffmpeg_name());
+
+    if segment_type == HlsSegmentType::Fmp4 {
+        cmd.arg("-hls_fmp4_init_filename").arg("init.mp4");
+    }
 
-        // Try encoding with configured encoder, fallback to CPU if hardware fails
-        let mut current_encoder = encoder_type.clone();
-        let mut last_error: Option<String> = None;
+    match &job.encryption {
+        Some(SegmentEncryption::Aes128 { key_info }) => {
+            cmd.arg("-hls_key_info_file").arg(key_info);
+        }
+        // CENC is applied by the fMP4 muxer to every sample
+        Some(SegmentEncryption::Cenc { kid, key }) if segment_type == HlsSegmentType::Fmp4 => {
+            cmd.arg("-hls_segment_options").arg(format!(
+                "encryption_scheme=cenc-aes-ctr:encryption_key={}:encryption_kid={}",
+                hex::encode(key),
+                hex::encode(kid)
+            ));
+        }
+        _ => {}
+    }
 
-        loop {
-            // Clean up any partial output from previous attempt
-            if last_error.is_some() {
-                let _ = fs::remove_dir_all(&seg_dir).await;
-                fs::create_dir_all(&seg_dir).await?;
-            }
+    cmd.arg("-start_number")
+        .arg(start_number.to_string())
+        .arg("-hls_segment_filename")
+        .arg(&segment_pattern)
+        .arg(dir.join("index.m3u8"));
+}
 
-            let mut cmd = Command::new("ffmpeg");
-            cmd.arg("-loglevel").arg("error").arg("-y");
+/// Add preset and tuning arguments for an encoder/codec combination
+fn push_encoder_settings(
+    cmd: &mut Command,
+    encoder: &EncoderType,
+    variant: &VideoVariant,
+    profile: &EncodingProfile,
+) {
+    let codec = variant.codec;
+    let codec_profile = if variant.hdr.is_some() {
+        "main10"
+    } else {
+        "main"
+    };
+    let preset = |default: &str| {
+        profile
+            .preset
+            .clone()
+            .unwrap_or_else(|| default.to_string())
+    };
+    // Same level the playlists advertise in the avc1 codec string
+    let level_idc = hls::h264_level_idc(variant.width, variant.height, variant.fps);
+    let h264_level = format!("{}.{}", level_idc / 10, level_idc % 10);
+
+    match (encoder, codec) {
+        (EncoderType::Nvenc, VideoCodec::H264) => {
+            cmd.arg("-preset")
+                .arg(preset("p3"))
+                .arg("-profile:v")
+                .arg(codec_profile)
+                .arg("-level:v")
+                .arg(&h264_level)
+                .arg("-rc-lookahead")
+                .arg("20")
+                .arg("-bf")
+                .arg("3")
+                .arg("-spatial-aq")
+                .arg("1")
+                .arg("-temporal-aq")
+                .arg("1")
+                .arg("-aq-strength")
+                .arg("8");
+        }
+        (EncoderType::Nvenc, _) => {
+            cmd.arg("-preset")
+                .arg(preset("p4"))
+                .arg("-profile:v")
+                .arg(codec_profile)
+                .arg("-rc-lookahead")
+                .arg("20")
+                .arg("-spatial-aq")
+                .arg("1");
+        }
+        (EncoderType::Amf, VideoCodec::H264) => {
+            cmd.arg("-quality")
+                .arg(preset("balanced"))
+                .arg("-profile:v")
+                .arg(codec_profile)
+                .arg("-level")
+                .arg(&h264_level)
+                .arg("-bf")
+                .arg("3");
+        }
+        (EncoderType::Amf, _) => {
+            cmd.arg("-quality").arg(preset("balanced"));
+        }
+        (EncoderType::Vaapi, _) => {
+            cmd.arg("-compression_level")
+                .arg("20")
+                .arg("-profile:v")
+                .arg(codec_profile);
+        }
+        (EncoderType::Qsv, VideoCodec::H264) => {
+            cmd.arg("-preset")
+                .arg(preset("faster"))
+                .arg("-profile:v")
+                .arg(codec_profile)
+                .arg("-look_ahead")
+                .arg("1")
+                .arg("-look_ahead_depth")
+                .arg("40");
+        }
+        (EncoderType::Qsv, _) => {
+            cmd.arg("-preset")
+                .arg(preset("faster"))
+                .arg("-profile:v")
+                .arg(codec_profile);
+        }
+        (EncoderType::Cpu, VideoCodec::H264) => {
+            cmd.arg("-preset")
+                .arg(preset("veryfast"))
+                .arg("-profile:v")
+                .arg(codec_profile)
+                .arg("-level:v")
+                .arg(&h264_level);
+        }
+        (EncoderType::Cpu, VideoCodec::Hevc) => {
+            // Closed GOPs without scenecut keyframes keep segments aligned with the H.264 ladder
+            cmd.arg("-preset")
+                .arg(preset("veryfast"))
+                .arg("-profile:v")
+                .arg(codec_profile)
+                .arg("-x265-params")
+                .arg(match variant.hdr {
+                    // HDR10 needs the SEI repeated in every keyframe for mid-stream joins
+                    Some(HdrFormat::Pq) => {
+                        "scenecut=0:open-gop=0:log-level=error:hdr10=1:hdr10-opt=1:repeat-headers=1"
+                    }
+                    _ => "scenecut=0:open-gop=0:log-level=error",
+                });
+        }
+        (EncoderType::Cpu, VideoCodec::Av1) => {
+            cmd.arg("-preset")
+                .arg(profile.av1_preset.to_string())
+                .arg("-svtav1-params")
+                .arg("scd=0");
+        }
+    }
+
+    // Apple players only accept HEVC in fMP4 when tagged as hvc1
+    if codec == VideoCodec::Hevc {
+        cmd.arg("-tag:v").arg("hvc1");
+    }
+}
 
-            // Hardware acceleration setup
-            match current_encoder {
+/// Add rate control arguments. VBR and CBR target the variant bitrate, CRF aims for
+/// the profile's quality under the variant's max bitrate, CQ holds the quantizer.
+fn push_rate_control(
+    cmd: &mut Command,
+    encoder: &EncoderType,
+    variant: &VideoVariant,
+    profile: &EncodingProfile,
+) {
+    let quality = profile.quality.to_string();
+    // SVT-AV1 only honours a max bitrate in CRF mode
+    let svt_av1 = *encoder == EncoderType::Cpu && variant.codec == VideoCodec::Av1;
+
+    match profile.rate_control {
+        RateControl::Vbr | RateControl::Cbr => {
+            let cbr = profile.rate_control == RateControl::Cbr;
+            match encoder {
                 EncoderType::Nvenc => {
-                    cmd.arg("-hwaccel")
-                        .arg("cuda")
-                        .arg("-hwaccel_output_format")
-                        .arg("cuda");
+                    cmd.arg("-rc:v").arg(if cbr { "cbr" } else { "vbr" });
                 }
                 EncoderType::Amf => {
-                    cmd.arg("-hwaccel")
-                        .arg("d3d11va")
-                        .arg("-hwaccel_output_format")
-                        .arg("d3d11");
+                    cmd.arg("-rc").arg(if cbr { "cbr" } else { "vbr_latency" });
                 }
                 EncoderType::Vaapi => {
-                    cmd.arg("-hwaccel")
-                        .arg("vaapi")
-                        .arg("-hwaccel_output_format")
-                        .arg("vaapi")
-                        .arg("-vaapi_device")
-                        .arg("/dev/dri/renderD128");
+                    cmd.arg("-rc_mode").arg(if cbr { "CBR" } else { "VBR" });
                 }
-                EncoderType::Qsv => {
-                    cmd.arg("-hwaccel")
-                        .arg("qsv")
-                        .arg("-hwaccel_output_format")
-                        .arg("qsv");
-                }
-                EncoderType::Cpu => {}
+                EncoderType::Qsv | EncoderType::Cpu => {}
             }
-
-            cmd.arg("-i").arg(&input);
-
-            // Scaling filter
-            let scale_filter = match current_encoder {
-                EncoderType::Nvenc => format!("scale_cuda=-2:{}", variant.height),
-                EncoderType::Amf => format!("scale=-2:{}", variant.height), // AMF uses software scale
-                EncoderType::Vaapi => format!("scale_vaapi=-2:{}", variant.height),
-                EncoderType::Qsv => format!("vpp_qsv=w=-2:h={}", variant.height),
-                EncoderType::Cpu => format!("scale=-2:{}", variant.height),
-            };
-
-            cmd.arg("-c:v").arg(current_encoder.video_codec());
-
-            // Encoder specific settings
-            match current_encoder {
+            cmd.arg("-b:v").arg(variant.bitrate_str());
+            if !svt_av1 {
+                let max_bitrate = if cbr {
+                    variant.bitrate
+                } else {
+                    variant.max_bitrate()
+                };
+                cmd.arg("-maxrate")
+                    .arg(format!("{}k", max_bitrate))
+                    .arg("-bufsize")
+                    .arg(format!("{}k", variant.bufsize()));
+            }
+        }
+        RateControl::Crf => {
+            match encoder {
                 EncoderType::Nvenc => {
-                    cmd.arg("-preset")
-                        .arg("p3")
-                        .arg("-profile:v")
-                        .arg("main")
-                        .arg("-level:v")
-                        .arg("4.1")
-                        .arg("-rc:v")
+                    cmd.arg("-rc:v")
                         .arg("vbr")
-                        .arg("-rc-lookahead")
-                        .arg("20")
-                        .arg("-bf")
-                        .arg("3")
-                        .arg("-spatial-aq")
-                        .arg("1")
-                        .arg("-temporal-aq")
-                        .arg("1")
-                        .arg("-aq-strength")
-                        .arg("8");
+                        .arg("-cq")
+                        .arg(&quality)
+                        .arg("-b:v")
+                        .arg("0");
                 }
                 EncoderType::Amf => {
-                    cmd.arg("-quality")
-                        .arg("balanced")
-                        .arg("-profile:v")
-                        .arg("main")
-                        .arg("-level")
-                        .arg("4.1")
-                        .arg("-rc")
-                        .arg("vbr_latency")
-                        .arg("-bf")
-                        .arg("3");
+                    cmd.arg("-rc")
+                        .arg("qvbr")
+                        .arg("-qvbr_quality_level")
+                        .arg(&quality);
                 }
                 EncoderType::Vaapi => {
-                    cmd.arg("-compression_level")
-                        .arg("20")
-                        .arg("-rc_mode")
-                        .arg("VBR")
-                        .arg("-profile:v")
-                        .arg("main");
+                    cmd.arg("-rc_mode")
+                        .arg("ICQ")
+                        .arg("-global_quality")
+                        .arg(&quality);
                 }
                 EncoderType::Qsv => {
-                    cmd.arg("-preset")
-                        .arg("faster")
-                        .arg("-profile:v")
-                        .arg("main")
-                        .arg("-look_ahead")
-                        .arg("1")
-                        .arg("-look_ahead_depth")
-                        .arg("40");
+                    cmd.arg("-global_quality").arg(&quality);
                 }
                 EncoderType::Cpu => {
-                    cmd.arg("-preset")
-                        .arg("veryfast")
-                        .arg("-profile:v")
-                        .arg("main")
-                        .arg("-level:v")
-                        .arg("4.0");
+                    cmd.arg("-crf").arg(&quality);
                 }
             }
-
-            cmd.arg("-b:v")
-                .arg(variant.bitrate_str())
-                .arg("-maxrate")
+            cmd.arg("-maxrate")
                 .arg(format!("{}k", variant.max_bitrate()))
                 .arg("-bufsize")
-                .arg(format!("{}k", variant.bufsize()))
-                .arg("-vf")
-                .arg(&scale_filter);
-
-            if matches!(current_encoder, EncoderType::Cpu) {
-                cmd.arg("-pix_fmt").arg("yuv420p");
+                .arg(format!("{}k", variant.bufsize()));
+        }
+        RateControl::Cq => match encoder {
+            EncoderType::Nvenc => {
+                cmd.arg("-rc:v").arg("constqp").arg("-qp").arg(&quality);
             }
+            EncoderType::Amf => {
+                cmd.arg("-rc")
+                    .arg("cqp")
+                    .arg("-qp_i")
+                    .arg(&quality)
+                    .arg("-qp_p")
+                    .arg(&quality)
+                    .arg("-qp_b")
+                    .arg(&quality);
+            }
+            EncoderType::Vaapi => {
+                cmd.arg("-rc_mode").arg("CQP").arg("-qp").arg(&quality);
+            }
+            EncoderType::Qsv => {
+                cmd.arg("-q:v").arg(&quality);
+            }
+            EncoderType::Cpu => {
+                cmd.arg("-qp").arg(&quality);
+            }
+        },
+    }
+}
 
-            cmd.arg("-g")
-                .arg(gop.to_string())
-                .arg("-keyint_min")
-                .arg(gop.to_string())
-                .arg("-sc_threshold")
-                .arg("0")
-                .arg("-force_key_frames")
-                .arg("expr:gte(t,n_forced*4)");
-
-            // Don't include audio in video variants - audio is encoded separately
-            cmd.arg("-an");
-
-            // Don't include subtitles in HLS output - they are extracted separately
-            cmd.arg("-sn");
-
-            cmd.arg("-hls_time")
-                .arg("4")
-                .arg("-hls_list_size")
-                .arg("0")
-                .arg("-hls_playlist_type")
-                .arg("vod")
-                .arg("-hls_segment_type")
-                .arg("mpegts")
-                .arg("-start_number")
-                .arg("0")
-                .arg("-hls_segment_filename")
-                .arg(&segment_pattern)
-                .arg(&playlist_path);
-
-            let output = run_ffmpeg_with_timeout(
-                cmd,
-                ffmpeg_timeout,
-                &format!("encoding variant {}", variant.label),
-            )
-            .await?;
+/// Software filter chain tone-mapping an HDR source to BT.709 SDR
+fn tonemap_filter(hdr: HdrFormat) -> String {
+    format!(
+        "zscale=tin={}:min=bt2020nc:pin=bt2020:t=linear:npl=100,format=gbrpf32le,\
+         zscale=p=bt709,tonemap=tonemap=hable:desat=0,\
+         zscale=t=bt709:m=bt709:r=tv,format=yuv420p",
+        hdr.transfer()
+    )
+}
 
-            if output.status.success() {
-                break;
+/// Add decode arguments for an encoder type.
+///
+/// With `gpu_frames` decoded frames stay in GPU memory for the hardware scaler.
+/// Otherwise frames are filtered in system memory (needed for tone mapping) and
+/// only the device the encoder uploads to is set up.
+fn push_hwaccel_args(cmd: &mut Command, encoder: &EncoderType, gpu_frames: bool) {
+    if !gpu_frames {
+        match encoder {
+            EncoderType::Vaapi => {
+                cmd.arg("-vaapi_device").arg("/dev/dri/renderD128");
             }
+            EncoderType::Qsv => {
+                cmd.arg("-init_hw_device")
+                    .arg("qsv=hw")
+                    .arg("-filter_hw_device")
+                    .arg("hw");
+            }
+            // NVENC and AMF accept frames from system memory
+            EncoderType::Nvenc | EncoderType::Amf | EncoderType::Cpu => {}
+        }
+        return;
+    }
 
-            let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-
-            // Check if this is a hardware encoder error and we can fallback
-            if current_encoder != EncoderType::Cpu && is_hardware_encoder_error(&stderr) {
-                warn!(
-                    "Hardware encoder {:?} failed for variant {}, falling back to CPU: {}",
-                    current_encoder,
-                    variant.label,
-                    stderr.lines().next().unwrap_or(&stderr)
-                );
-                current_encoder = EncoderType::Cpu;
-                last_error = Some(stderr);
+    match encoder {
+        EncoderType::Nvenc => {
+            cmd.arg("-hwaccel")
+                .arg("cuda")
+                .arg("-hwaccel_output_format")
+                .arg("cuda");
+        }
+        EncoderType::Amf => {
+            cmd.arg("-hwaccel")
+                .arg("d3d11va")
+                .arg("-hwaccel_output_format")
+                .arg("d3d11");
+        }
+        EncoderType::Vaapi => {
+            cmd.arg("-hwaccel")
+                .arg("vaapi")
+                .arg("-hwaccel_output_format")
+                .arg("vaapi")
+                .arg("-vaapi_device")
+                .arg("/dev/dri/renderD128");
+        }
+        EncoderType::Qsv => {
+            cmd.arg("-hwaccel")
+                .arg("qsv")
+                .arg("-hwaccel_output_format")
+                .arg("qsv");
+        }
+        EncoderType::Cpu => {}
+    }
+}
 
-                // Update progress to indicate fallback
-                let fallback_progress = ProgressUpdate {
-                    stage: "FFmpeg processing".to_string(),
-                    current_chunk,
-                    total_chunks: total_steps,
-                    percentage,
-                    details: Some(format!(
-                        "Encoding variant: {} ({}p) - using CPU fallback",
-                        variant.label, variant.height
-                    )),
-                    status: "processing".to_string(),
-                    result: None,
-                    error: None,
-                    video_name: existing_video_name.clone(),
-                    created_at: existing_created_at,
-                    variant_percentage: None,
-                };
-                progress
-                    .write()
-                    .await
-                    .insert(upload_id.clone(), fallback_progress);
+/// Scaling filter for a variant. The ladder already accounts for SAR, so output square pixels.
+fn scale_filter(encoder: &EncoderType, variant: &VideoVariant, gpu_frames: bool) -> String {
+    let (w, h) = (variant.width, variant.height);
 
-                continue;
-            }
+    // Frames in system memory are scaled in software, then uploaded for VAAPI/QSV
+    if !gpu_frames {
+        let upload = match encoder {
+            EncoderType::Vaapi => ",format=nv12,hwupload",
+            EncoderType::Qsv => ",format=nv12,hwupload=extra_hw_frames=64",
+            _ => "",
+        };
+        return format!("scale={}:{},setsar=1{}", w, h, upload);
+    }
 
-            // Non-recoverable error
-            error!("FFmpeg failed for variant {}: {}", variant.label, stderr);
-            anyhow::bail!(
-                "ffmpeg exited with status: {} for variant {}",
-                output.status,
-                variant.label
-            );
-        }
+    let scale = match encoder {
+        EncoderType::Nvenc => format!("scale_cuda={}:{}", w, h),
+        EncoderType::Amf => format!("scale={}:{}", w, h), // AMF uses software scale
+        EncoderType::Vaapi => format!("scale_vaapi=w={}:h={}", w, h),
+        EncoderType::Qsv => format!("vpp_qsv=w={}:h={}", w, h),
+        EncoderType::Cpu => format!("scale={}:{}", w, h),
+    };
+    scale + ",setsar=1"
+}
 
-        // Update progress for this variant
-        let updated_progress = ProgressUpdate {
-            stage: "FFmpeg processing".to_string(),
-            current_chunk,
-            total_chunks: total_steps,
-            percentage,
-            details: Some(format!("Encoded variant: {}", variant.label)),
-            status: "processing".to_string(),
-            result: None,
-            error: None,
-            video_name: existing_video_name,
-            created_at: existing_created_at,
-            variant_percentage: None,
+/// Add encoder, rate control, GOP and HLS muxer arguments for one variant output
+fn push_video_output_args(
+    cmd: &mut Command,
+    job: &EncodeJob,
+    variant: &VideoVariant,
+    encoder: &EncoderType,
+    gop: u32,
+    seg_dir: &Path,
+    range: Option<&TimeRange>,
+) {
+    cmd.arg("-c:v").arg(encoder.video_codec(variant.codec));
+
+    push_encoder_settings(cmd, encoder, variant, &job.profile);
+    push_rate_control(cmd, encoder, variant, &job.profile);
+
+    if matches!(encoder, EncoderType::Cpu) {
+        let pix_fmt = if variant.hdr.is_some() {
+            "yuv420p10le"
+        } else {
+            "yuv420p"
         };
-        progress
-            .write()
-            .await
-            .insert(upload_id.clone(), updated_progress);
+        cmd.arg("-pix_fmt").arg(pix_fmt);
     }
 
-    // Encode each audio stream as a separate HLS audio playlist (sequential)
-    let num_video_variants = variants.len();
-    for (audio_idx, audio_stream) in audio_streams.iter().enumerate() {
-        let audio_label = audio_stream
-            .language
-            .clone()
-            .unwrap_or_else(|| format!("track_{}", audio_idx));
-        let audio_dir = out_dir.join(format!("audio_{}", audio_label));
-        fs::create_dir_all(&audio_dir).await?;
-        let playlist_path = audio_dir.join("index.m3u8");
-        let segment_pattern = audio_dir.join("segment_%03d.ts");
+    // Signal colour explicitly: BT.2020 for HDR renditions, BT.709 for tone-mapped ones
+    match variant.hdr {
+        Some(hdr) => {
+            cmd.arg("-color_primaries")
+                .arg("bt2020")
+                .arg("-color_trc")
+                .arg(hdr.transfer())
+                .arg("-colorspace")
+                .arg("bt2020nc");
+        }
+        None if job.source_hdr.is_some() => {
+            cmd.arg("-color_primaries")
+                .arg("bt709")
+                .arg("-color_trc")
+                .arg("bt709")
+                .arg("-colorspace")
+                .arg("bt709");
+        }
+        None => {}
+    }
 
-        info!(
-            "Encoding audio track {}: {} (codec: {}, channels: {:?})",
-            audio_idx, audio_label, audio_stream.codec_name, audio_stream.channels
-        );
+    // Sources above the frame rate cap are decimated
+    if variant.fps < job.source_fps - 0.01 {
+        cmd.arg("-r").arg(format!("{:.3}", variant.fps));
+    }
 
-        let current_chunk = (num_video_variants + audio_idx + 1) as u32;
-        let percentage = ((current_chunk as f32 / total_steps as f32) * 100.0) as u32;
-        let (existing_video_name, existing_created_at) = {
-            let progress_map = progress.read().await;
-            progress_map
-                .get(&upload_id)
-                .map(|p| (p.video_name.clone(), p.created_at))
-                .unwrap_or((None, 0))
-        };
-        let audio_progress = ProgressUpdate {
-            stage: "FFmpeg processing".to_string(),
-            current_chunk,
-            total_chunks: total_steps,
-            percentage,
-            details: Some(format!("Encoding audio track: {}", audio_label)),
-            status: "processing".to_string(),
-            result: None,
-            error: None,
-            video_name: existing_video_name.clone(),
-            created_at: existing_created_at,
-            variant_percentage: None,
-        };
-        progress
-            .write()
-            .await
-            .insert(upload_id.clone(), audio_progress);
+    cmd.arg("-g")
+        .arg(gop.to_string())
+        .arg("-keyint_min")
+        .arg(gop.to_string())
+        .arg("-sc_threshold")
+        .arg("0")
+        .arg("-force_key_frames")
+        .arg(format!(
+            "expr:gte(t,n_forced*{})",
+            job.profile.segment_duration.max(1)
+        ));
+
+    // Don't include audio in video variants - audio is encoded separately
+    cmd.arg("-an");
+
+    // Don't include subtitles in HLS output - they are extracted separately
+    cmd.arg("-sn");
+
+    // A range keeps its place on the source timeline and in the segment numbering
+    let start_number = match range {
+        Some(range) => {
+            cmd.arg("-output_ts_offset").arg(range.start.to_string());
+            range.first_segment
+        }
+        None => 0,
+    };
+    push_hls_output_args(cmd, job, seg_dir, variant.segment_type, start_number);
+}
 
-        let mut cmd = Command::new("ffmpeg");
-        cmd.arg("-loglevel")
-            .arg("error")
-            .arg("-y")
-            .arg("-i")
-            .arg(&input)
-            .arg("-map")
-            .arg(format!("0:a:{}", audio_idx))
-            .arg("-vn")
-            .arg("-c:a")
-            .arg("aac")
-            .arg("-b:a")
-            .arg("128k")
-            .arg("-ac")
-            .arg(if audio_stream.channels.unwrap_or(2) <= 2 {
-                audio_stream.channels.unwrap_or(2).to_string()
-            } else {
-                "2".to_string()
-            })
-            .arg("-hls_time")
-            .arg("4")
-            .arg("-hls_list_size")
-            .arg("0")
-            .arg("-hls_playlist_type")
-            .arg("vod")
-            .arg("-hls_segment_type")
-            .arg("mpegts")
-            .arg("-start_number")
-            .arg("0")
-            .arg("-hls_segment_filename")
-            .arg(&segment_pattern)
-            .arg(&playlist_path);
+/// Encode video variants with a single FFmpeg process, retrying on CPU when the
+/// hardware encoder fails.
+///
+/// One variant is scaled with `-vf`. Several variants share one decode: a
+/// `filter_complex` splits the source into one scaled branch per variant, and
+/// each branch is mapped to its own HLS output. With `range` only that part of
+/// the source is encoded, without live progress.
+async fn encode_video_variants(
+    job: &EncodeJob,
+    variants: &[VideoVariant],
+    out_dir: &Path,
+    encoder_type: &EncoderType,
+    gop: u32,
+    current_chunk: u32,
+    range: Option<&TimeRange>,
+) -> Result<()> {
+    let mut names = variants
+        .iter()
+        .map(|v| v.dir())
+        .collect::<Vec<_>>()
+        .join(", ");
+    if let Some(range) = range {
+        names = format!("{} from {}s", names, range.start);
+    }
+
+    let mut current_encoder = encoder_type.clone();
+    let mut last_error: Option<String> = None;
+
+    loop {
+        // Clean up any partial output from previous attempt
+        for variant in variants {
+            let seg_dir = out_dir.join(variant.dir());
+            if last_error.is_some() {
+                let _ = fs::remove_dir_all(&seg_dir).await;
+            }
+            fs::create_dir_all(&seg_dir).await?;
+        }
+
+        let mut cmd = Command::new("ffmpeg");
+        cmd.arg("-loglevel").arg("error").arg("-y");
+        push_progress_args(&mut cmd);
+
+        // SDR variants of an HDR source are tone-mapped in system memory first,
+        // subtitles are burned in after tone mapping
+        let tonemap = match (job.source_hdr, variants[0].hdr) {
+            (Some(hdr), None) => Some(tonemap_filter(hdr)),
+            _ => None,
+        };
+        let burn_in = job
+            .burn_in
+            .as_ref()
+            .filter(|_| variants[0].burned_subtitles);
+        let gpu_frames = tonemap.is_none() && burn_in.is_none();
+
+        // Hardware acceleration setup
+        push_hwaccel_args(&mut cmd, &current_encoder, gpu_frames);
+
+        // Input seeking decodes from the keyframe before the range start, but
+        // output starts exactly on it
+        if let Some(range) = range {
+            cmd.arg("-ss").arg(range.start.to_string());
+            if let Some(length) = range.length {
+                cmd.arg("-t").arg(length.to_string());
+            }
+        }
+        cmd.arg("-i").arg(&job.input);
+
+        let mut pre_filter = tonemap.map(|f| f + ",").unwrap_or_default();
+        match burn_in {
+            Some(BurnIn::Text(filter)) => pre_filter = format!("{}{},", pre_filter, filter),
+            Some(BurnIn::Bitmap(idx)) => {
+                pre_filter = format!(
+                    "{}null[base];[base][0:s:{}]overlay=eof_action=pass,",
+                    pre_filter, idx
+                )
+            }
+            None => {}
+        }
+
+        if let ([variant], None) = (variants, burn_in) {
+            cmd.arg("-vf").arg(format!(
+                "{}{}",
+                pre_filter,
+                scale_filter(&current_encoder, variant, gpu_frames)
+            ));
+            push_video_output_args(
+                &mut cmd,
+                job,
+                variant,
+                &current_encoder,
+                gop,
+                &out_dir.join(variant.dir()),
+                range,
+            );
+        } else {
+            let mut graph = format!("[0:v:0]{}split={}", pre_filter, variants.len());
+            for idx in 0..variants.len() {
+                graph.push_str(&format!("[s{}]", idx));
+            }
+            for (idx, variant) in variants.iter().enumerate() {
+                graph.push_str(&format!(
+                    ";[s{}]{}[v{}]",
+                    idx,
+                    scale_filter(&current_encoder, variant, gpu_frames),
+                    idx
+                ));
+            }
+            cmd.arg("-filter_complex").arg(&graph);
+
+            for (idx, variant) in variants.iter().enumerate() {
+                cmd.arg("-map").arg(format!("[v{}]", idx));
+                push_video_output_args(
+                    &mut cmd,
+                    job,
+                    variant,
+                    &current_encoder,
+                    gop,
+                    &out_dir.join(variant.dir()),
+                    range,
+                );
+            }
+        }
 
         let output = run_ffmpeg_with_timeout(
             cmd,
-            ffmpeg_timeout,
-            &format!("encoding audio track {}", audio_label),
+            job.ffmpeg_timeout,
+            &format!("encoding variants {}", names),
+            range.is_none().then_some(job),
         )
-        .await
-        .context("failed to run ffmpeg for audio")?;
+        .await?;
 
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            error!(
-                "FFmpeg audio encoding failed for track {}: {}",
-                audio_idx, stderr
+        if output.status.success() {
+            return Ok(());
+        }
+
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+
+        // Check if this is a hardware encoder error and we can fallback
+        if current_encoder != EncoderType::Cpu && is_hardware_encoder_error(&stderr) {
+            warn!(
+                "Hardware encoder {:?} failed for {}, falling back to CPU: {}",
+                current_encoder,
+                names,
+                stderr.lines().next().unwrap_or(&stderr)
             );
+            current_encoder = EncoderType::Cpu;
+            last_error = Some(stderr);
+
+            // Update progress to indicate fallback
+            job.report(
+                current_chunk,
+                format!("Encoding variants: {} - using CPU fallback", names),
+            )
+            .await;
+
+            continue;
+        }
+
+        // Non-recoverable error
+        error!("FFmpeg failed for {}: {}", names, stderr);
+        anyhow::bail!("ffmpeg exited with status: {} for {}", output.status, names);
+    }
+}
+
+/// Encode video variants range by range, then join the ranges of each variant into
+/// one media playlist in `out_dir`.
+///
+/// The job's own encode slot always runs a range; further ranges run on slots of
+/// `encode_slots` that are idle when they start, and hand them back when done so
+/// queued uploads wait for one range at most.
+#[allow(clippy::too_many_arguments)]
+async fn encode_video_variants_chunked(
+    job: &EncodeJob,
+    variants: &[VideoVariant],
+    out_dir: &Path,
+    encoder_type: &EncoderType,
+    gop: u32,
+    current_chunk: u32,
+    ranges: &[TimeRange],
+    encode_slots: &Semaphore,
+) -> Result<()> {
+    let names = variants
+        .iter()
+        .map(|v| format!("{} ({}p)", v.dir(), v.height))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let range_dirs: Vec<PathBuf> = (0..ranges.len())
+        .map(|idx| out_dir.join(format!("range_{:03}", idx)))
+        .collect();
+
+    let result = async {
+        let mut pending = ranges.iter().zip(&range_dirs).peekable();
+        let mut running = FuturesUnordered::new();
+        let mut finished = 0;
+        loop {
+            while let Some((range, range_dir)) = pending.peek().copied() {
+                let permit = if running.is_empty() {
+                    None
+                } else {
+                    match encode_slots.try_acquire() {
+                        Ok(permit) => Some(permit),
+                        Err(_) => break,
+                    }
+                };
+                pending.next();
+                running.push(async move {
+                    let result = encode_video_variants(
+                        job,
+                        variants,
+                        range_dir,
+                        encoder_type,
+                        gop,
+                        current_chunk,
+                        Some(range),
+                    )
+                    .await;
+                    drop(permit);
+                    result
+                });
+            }
+
+            let Some(result) = running.next().await else {
+                break;
+            };
+            result?;
+            finished += 1;
+            job.report(
+                current_chunk,
+                format!(
+                    "Encoding variant: {} - {}/{} ranges",
+                    names,
+                    finished,
+                    ranges.len()
+                ),
+            )
+            .await;
+        }
+
+        for variant in variants {
+            join_ranges(&range_dirs, ranges, &out_dir.join(variant.dir()), variant).await?;
+        }
+        Ok(())
+    }
+    .await;
+
+    for range_dir in &range_dirs {
+        let _ = fs::remove_dir_all(range_dir).await;
+    }
+    result
+}
+
+/// Move the range encodes of `variant` into `dir` behind one joined media playlist.
+/// Fails when a range's segments don't continue the numbering of the one before.
+async fn join_ranges(
+    range_dirs: &[PathBuf],
+    ranges: &[TimeRange],
+    dir: &Path,
+    variant: &VideoVariant,
+) -> Result<()> {
+    let _ = fs::remove_dir_all(dir).await;
+    fs::create_dir_all(dir).await?;
+
+    let mut playlists = Vec::with_capacity(ranges.len());
+    for (idx, (range_dir, range)) in range_dirs.iter().zip(ranges).enumerate() {
+        let range_dir = range_dir.join(variant.dir());
+        let playlist = fs::read_to_string(range_dir.join("index.m3u8"))
+            .await
+            .with_context(|| format!("failed to read range {} of {}", idx, variant.dir()))?;
+
+        let segments = hls::playlist_segments(&playlist);
+        let first = segments.first().and_then(|(_, uri)| {
+            uri.strip_prefix("segment_")?
+                .split('.')
+                .next()?
+                .parse::<u64>()
+                .ok()
+        });
+        let expected = ranges
+            .get(idx + 1)
+            .map(|next| next.first_segment - range.first_segment);
+        if first != Some(range.first_segment)
+            || expected.is_some_and(|count| count != segments.len() as u64)
+        {
             anyhow::bail!(
-                "ffmpeg audio encoding failed for track {}: {}",
-                audio_idx,
-                stderr
+                "range {} of {} is off the segment grid ({} segments from {:?})",
+                idx,
+                variant.dir(),
+                segments.len(),
+                first
             );
         }
 
-        info!("Audio track {} encoded successfully", audio_label);
+        // Every range writes the same init segment, the first one is kept
+        let mut entries = fs::read_dir(&range_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            if name == "index.m3u8" || (idx > 0 && name == "init.mp4") {
+                continue;
+            }
+            fs::rename(entry.path(), dir.join(&name)).await?;
+        }
+        playlists.push(playlist);
     }
 
-    // Generate thumbnail (single frame at 10% of video)
-    {
-        let thumbnail_path = out_dir.join("thumbnail.jpg");
-        info!("Generating thumbnail: {:?}", thumbnail_path);
+    fs::write(
+        dir.join("index.m3u8"),
+        hls::join_media_playlists(&playlists),
+    )
+    .await
+    .with_context(|| format!("failed to write media playlist of {}", variant.dir()))?;
+    Ok(())
+}
 
-        let seek_time = (duration as f64 * 0.1).max(1.0);
+/// First-pass `loudnorm` statistics of one audio stream
+struct LoudnessMeasurement {
+    input_i: f64,
+    input_tp: f64,
+    input_lra: f64,
+    input_thresh: f64,
+    target_offset: f64,
+}
+
+impl LoudnessMeasurement {
+    /// Second-pass filter applying the measured correction as a linear gain
+    fn filter(&self, config: &LoudnessConfig) -> String {
+        format!(
+            "loudnorm=I={}:TP={}:LRA={}:measured_I={}:measured_TP={}:measured_LRA={}:measured_thresh={}:offset={}:linear=true,aresample=48000",
+            config.target_lufs,
+            config.true_peak,
+            config.loudness_range,
+            self.input_i,
+            self.input_tp,
+            self.input_lra,
+            self.input_thresh,
+            self.target_offset
+        )
+    }
+}
+
+/// Measure an audio stream with a `loudnorm` analysis pass (EBU R128)
+async fn measure_loudness(
+    job: &EncodeJob,
+    audio_idx: usize,
+    config: &LoudnessConfig,
+) -> Result<LoudnessMeasurement> {
+    let mut cmd = Command::new("ffmpeg");
+    // loudnorm prints its JSON summary at info level
+    cmd.arg("-hide_banner")
+        .arg("-nostats")
+        .arg("-i")
+        .arg(&job.input)
+        .arg("-map")
+        .arg(format!("0:a:{}", audio_idx))
+        .arg("-vn")
+        .arg("-af")
+        .arg(format!(
+            "loudnorm=I={}:TP={}:LRA={}:print_format=json",
+            config.target_lufs, config.true_peak, config.loudness_range
+        ))
+        .arg("-f")
+        .arg("null")
+        .arg("-");
+
+    let output = run_ffmpeg_with_timeout(
+        cmd,
+        job.ffmpeg_timeout,
+        &format!("measuring loudness of audio track {}", audio_idx),
+        None,
+    )
+    .await?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        anyhow::bail!("loudnorm analysis failed: {}", stderr);
+    }
+
+    let json = stderr
+        .rfind('{')
+        .and_then(|start| {
+            stderr[start..]
+                .find('}')
+                .map(|end| &stderr[start..=start + end])
+        })
+        .context("loudnorm printed no measurement")?;
+    let stats: serde_json::Value =
+        serde_json::from_str(json).context("failed to parse loudnorm measurement")?;
+    let field = |name: &str| -> Result<f64> {
+        stats[name]
+            .as_str()
+            .and_then(|v| v.trim().parse::<f64>().ok())
+            .filter(|v| v.is_finite())
+            .with_context(|| format!("loudnorm measurement has no usable {}", name))
+    };
+
+    Ok(LoudnessMeasurement {
+        input_i: field("input_i")?,
+        input_tp: field("input_tp")?,
+        input_lra: field("input_lra")?,
+        input_thresh: field("input_thresh")?,
+        target_offset: field("target_offset")?,
+    })
+}
+
+/// Convert a text subtitle stream to a WebVTT file (styling is dropped)
+async fn extract_webvtt(job: &EncodeJob, subtitle_index: usize, output_path: &Path) -> Result<()> {
+    let mut cmd = Command::new("ffmpeg");
+    cmd.arg("-loglevel")
+        .arg("error")
+        .arg("-y")
+        .arg("-i")
+        .arg(&job.input)
+        .arg("-map")
+        .arg(format!("0:s:{}", subtitle_index))
+        .arg("-c:s")
+        .arg("webvtt")
+        .arg("-f")
+        .arg("webvtt")
+        .arg(output_path);
+
+    let output = run_ffmpeg_with_timeout(
+        cmd,
+        job.ffmpeg_timeout,
+        &format!("converting subtitle track {} to WebVTT", subtitle_index),
+        None,
+    )
+    .await?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "ffmpeg WebVTT conversion failed for track {}: {}",
+            subtitle_index,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    Ok(())
+}
+
+/// A timed subtitle cue, times in seconds
+type Cue = (f64, f64, String);
+
+/// Format seconds as a subtitle timestamp, `HH:MM:SS<sep>mmm`
+fn format_cue_time(seconds: f64, separator: char) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}{}{:03}",
+        millis / 3_600_000,
+        (millis / 60_000) % 60,
+        (millis / 1000) % 60,
+        separator,
+        millis % 1000
+    )
+}
+
+fn cues_to_srt(cues: &[Cue]) -> String {
+    cues.iter()
+        .enumerate()
+        .map(|(i, (start, end, text))| {
+            format!(
+                "{}\n{} --> {}\n{}\n",
+                i + 1,
+                format_cue_time(*start, ','),
+                format_cue_time(*end, ','),
+                text
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn cues_to_webvtt(cues: &[Cue]) -> String {
+    let mut vtt = String::from("WEBVTT\n");
+    for (start, end, text) in cues {
+        vtt.push_str(&format!(
+            "\n{} --> {}\n{}\n",
+            format_cue_time(*start, '.'),
+            format_cue_time(*end, '.'),
+            text
+        ));
+    }
+    vtt
+}
+
+/// Tesseract language for a subtitle track: ISO 639-2/B codes mapped to the
+/// /T names tesseract uses, falling back when no traineddata is installed
+fn tesseract_language(language: Option<&str>, installed: &[String], fallback: &str) -> String {
+    let code = language.unwrap_or("und").to_lowercase();
+    let code = match code.as_str() {
+        "fre" => "fra",
+        "ger" => "deu",
+        "dut" => "nld",
+        "cze" => "ces",
+        "gre" => "ell",
+        "per" => "fas",
+        "rum" => "ron",
+        "slo" => "slk",
+        "chi" | "zho" => "chi_sim",
+        "ice" => "isl",
+        "mac" => "mkd",
+        "may" => "msa",
+        "wel" => "cym",
+        other => other,
+    };
+    if installed.iter().any(|l| l == code) {
+        code.to_string()
+    } else {
+        fallback.to_string()
+    }
+}
+
+/// Read the text of one rendered subtitle image, lines trimmed
+async fn ocr_image(tesseract: &str, image: &Path, lang: &str) -> Result<String> {
+    let output = Command::new(tesseract)
+        .arg(image)
+        .arg("stdout")
+        .arg("-l")
+        .arg(lang)
+        .arg("--psm")
+        .arg("6")
+        .output()
+        .await
+        .context("failed to run tesseract")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "tesseract failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+/// OCR a bitmap subtitle stream (PGS, VobSub, DVB) into text cues.
+///
+/// FFmpeg overlays the subtitle stream on a black canvas at 10 fps and keeps only
+/// frames that change (`mpdecimate`), so every image is one display state named
+/// after its timestamp. tesseract reads each image; blank states end a cue.
+/// Images are left in `work_dir` for the caller to remove.
+async fn ocr_bitmap_subtitle(
+    job: &EncodeJob,
+    subtitle_index: usize,
+    canvas: (u32, u32),
+    language: Option<&str>,
+    config: &OcrConfig,
+    work_dir: &Path,
+) -> Result<Vec<Cue>> {
+    const OCR_FPS: u32 = 10;
+
+    let installed = Command::new(&config.tesseract_path)
+        .arg("--list-langs")
+        .output()
+        .await
+        .context("failed to run tesseract")?;
+    let installed: Vec<String> = String::from_utf8_lossy(&installed.stdout)
+        .lines()
+        .skip(1)
+        .map(|l| l.trim().to_string())
+        .collect();
+    let lang = tesseract_language(language, &installed, &config.default_language);
+
+    fs::create_dir_all(work_dir).await?;
+    let mut cmd = Command::new("ffmpeg");
+    cmd.arg("-loglevel")
+        .arg("error")
+        .arg("-y")
+        .arg("-i")
+        .arg(&job.input)
+        .arg("-filter_complex")
+        .arg(format!(
+            "color=c=black:s={}x{}:r={}:d={}[bg];[bg][0:s:{}]overlay=eof_action=pass,mpdecimate,negate,format=gray",
+            canvas.0, canvas.1, OCR_FPS, job.duration, subtitle_index
+        ))
+        .arg("-an")
+        .arg("-fps_mode")
+        .arg("vfr")
+        .arg("-enc_time_base")
+        .arg(format!("1:{}", OCR_FPS))
+        .arg("-frame_pts")
+        .arg("1")
+        .arg("-f")
+        .arg("image2")
+        .arg(work_dir.join("%08d.png"));
+
+    let output = run_ffmpeg_with_timeout(
+        cmd,
+        job.ffmpeg_timeout,
+        &format!("rendering bitmap subtitle track {}", subtitle_index),
+        None,
+    )
+    .await?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "ffmpeg bitmap subtitle rendering failed for track {}: {}",
+            subtitle_index,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    // Display states in time order: (start seconds, image)
+    let mut frames: Vec<(f64, PathBuf)> = Vec::new();
+    let mut entries = fs::read_dir(work_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if let Some(pts) = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse::<u64>().ok())
+        {
+            frames.push((pts as f64 / OCR_FPS as f64, path));
+        }
+    }
+    frames.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    // A few tesseract processes at a time
+    let mut texts: Vec<Result<String>> = Vec::with_capacity(frames.len());
+    for chunk in frames.chunks(4) {
+        let batch = chunk
+            .iter()
+            .map(|(_, image)| ocr_image(&config.tesseract_path, image, &lang));
+        texts.extend(futures::future::join_all(batch).await);
+    }
+
+    let mut cues: Vec<Cue> = Vec::new();
+    for (i, ((start, _), text)) in frames.iter().zip(texts).enumerate() {
+        let text = text?;
+        let end = frames.get(i + 1).map(|(t, _)| *t).unwrap_or(job.duration);
+        if text.is_empty() || end <= *start {
+            continue;
+        }
+        // Fades and re-renders of the same text continue the previous cue
+        match cues.last_mut() {
+            Some(last) if last.2 == text && (last.1 - start).abs() < 0.001 => last.1 = end,
+            _ => cues.push((*start, end, text)),
+        }
+    }
+
+    Ok(cues)
+}
+
+/// Write an AES-128 key and the `-hls_key_info_file` pointing players at `key_uri`.
+///
+/// Both files go to `dir`, which must not be uploaded with the segments.
+pub async fn write_hls_key_info(dir: &Path, key_uri: &str, key: &[u8]) -> Result<PathBuf> {
+    fs::create_dir_all(dir).await?;
+    let key_path = dir.join("enc.key");
+    let key_info_path = dir.join("enc.keyinfo");
+
+    fs::write(&key_path, key)
+        .await
+        .context("failed to write encryption key")?;
+    fs::write(
+        &key_info_path,
+        format!("{}\n{}\n", key_uri, key_path.display()),
+    )
+    .await
+    .context("failed to write key info file")?;
+
+    Ok(key_info_path)
+}
+
+/// Encode one audio stream as the HLS audio playlist described by `rendition`,
+/// through `audio_filter` (loudness normalization) when given
+async fn encode_audio_rendition(
+    job: &EncodeJob,
+    audio_idx: usize,
+    rendition: &AudioRendition,
+    audio_filter: Option<&str>,
+    out_dir: &Path,
+    segment_type: HlsSegmentType,
+) -> Result<()> {
+    let audio_dir = out_dir.join(&rendition.dir);
+    fs::create_dir_all(&audio_dir).await?;
+
+    let mut cmd = Command::new("ffmpeg");
+    cmd.arg("-loglevel").arg("error").arg("-y");
+    push_progress_args(&mut cmd);
+    cmd.arg("-i")
+        .arg(&job.input)
+        .arg("-map")
+        .arg(format!("0:a:{}", audio_idx))
+        .arg("-vn");
+    if let Some(filter) = audio_filter {
+        cmd.arg("-af").arg(filter);
+    }
+    cmd.arg("-c:a")
+        .arg(rendition.codec.encoder())
+        .arg("-b:a")
+        .arg(format!("{}k", rendition.bitrate))
+        .arg("-ac")
+        .arg(rendition.channels.to_string());
+    if let Some(sample_rate) = job.profile.audio.sample_rate {
+        cmd.arg("-ar").arg(sample_rate.to_string());
+    }
+
+    push_hls_output_args(&mut cmd, job, &audio_dir, segment_type, 0);
+
+    let output = run_ffmpeg_with_timeout(
+        cmd,
+        job.ffmpeg_timeout,
+        &format!("encoding audio track {}", audio_idx),
+        Some(job),
+    )
+    .await
+    .context("failed to run ffmpeg for audio")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        error!(
+            "FFmpeg audio encoding failed for track {}: {}",
+            audio_idx, stderr
+        );
+        anyhow::bail!(
+            "ffmpeg audio encoding failed for track {}: {}",
+            audio_idx,
+            stderr
+        );
+    }
+
+    Ok(())
+}
+
+/// Windows of the video searched for a thumbnail candidate
+const THUMBNAIL_CANDIDATES: u32 = 5;
+
+/// Frame metadata key of the `entropy` filter's normalized luma entropy
+const ENTROPY_KEY: &str = "lavfi.entropy.normalized_entropy.normal.Y";
+
+/// Pick one representative frame per window into `dir/candidate_{n}.jpg`.
+///
+/// Frames are sampled at 2 fps; black and flat frames (title cards, fades) are
+/// dropped by their luma entropy before FFmpeg's `thumbnail` filter picks the
+/// frame closest to the window's average. Windows without a usable frame are
+/// skipped. Returns each written candidate with its normalized luma entropy.
+async fn generate_thumbnail_candidates(job: &EncodeJob, dir: &Path) -> Vec<(PathBuf, f64)> {
+    if let Err(e) = fs::create_dir_all(dir).await {
+        error!("Failed to create thumbnail candidate dir: {}", e);
+        return Vec::new();
+    }
+
+    let window = (job.duration / THUMBNAIL_CANDIDATES as f64).clamp(1.0, 30.0);
+    // HDR frames are tone-mapped before the entropy check so it sees what viewers do
+    let tonemap = job
+        .source_hdr
+        .map(|hdr| tonemap_filter(hdr) + ",")
+        .unwrap_or_default();
+    let mut candidates = Vec::new();
+    for n in 0..THUMBNAIL_CANDIDATES {
+        // Windows start at 10%, 28%, 46%, 64% and 82% of the video
+        let start = job.duration * (0.1 + 0.18 * n as f64);
+        let path = dir.join(format!("candidate_{}.jpg", candidates.len()));
+        // The picked frame keeps the entropy measured for it; list it in a file
+        let entropy_path = dir.join(format!("candidate_{}.txt", candidates.len()));
 
         let mut cmd = Command::new("ffmpeg");
         cmd.arg("-loglevel")
             .arg("error")
             .arg("-y")
             .arg("-ss")
-            .arg(format!("{}", seek_time))
+            .arg(format!("{:.3}", start))
+            .arg("-t")
+            .arg(format!("{:.3}", window))
             .arg("-i")
-            .arg(&input)
+            .arg(&job.input)
             .arg("-vf")
-            .arg("scale=480:-1")
+            .arg(format!(
+                "fps=2,{}scale={}:-2,entropy,\
+                 metadata=mode=select:key={}:value=0.6:function=greater,\
+                 thumbnail=n={},metadata=print:key={}:file={}",
+                tonemap,
+                job.profile.thumbnail_width,
+                ENTROPY_KEY,
+                (window * 2.0).ceil() as u32,
+                ENTROPY_KEY,
+                filter_path(&entropy_path)
+            ))
             .arg("-frames:v")
             .arg("1")
             .arg("-q:v")
             .arg("2")
-            .arg(&thumbnail_path);
-
-        match run_ffmpeg_with_timeout(cmd, ffmpeg_timeout, "generating thumbnail").await {
-            Ok(output) => {
-                if !output.status.success() {
-                    let stderr = String::from_utf8_lossy(&output.stderr);
-                    error!("Thumbnail generation failed: {}", stderr);
+            .arg(&path);
+
+        let picked =
+            match run_ffmpeg_with_timeout(cmd, job.ffmpeg_timeout, "picking thumbnail", None).await
+            {
+                Ok(output) if output.status.success() => {
+                    let written = fs::metadata(&path).await.is_ok_and(|m| m.len() > 0);
+                    let entropy = fs::read_to_string(&entropy_path).await.ok().and_then(|s| {
+                        s.lines()
+                            .find_map(|line| line.strip_prefix(ENTROPY_KEY)?.strip_prefix('='))
+                            .and_then(|value| value.trim().parse::<f64>().ok())
+                    });
+                    entropy.filter(|_| written)
+                }
+                Ok(output) => {
+                    warn!(
+                        "Thumbnail candidate {} failed: {}",
+                        n,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    );
+                    None
+                }
+                Err(e) => {
+                    warn!("Thumbnail candidate {} failed: {}", n, e);
+                    None
                 }
+            };
+        let _ = fs::remove_file(&entropy_path).await;
+        match picked {
+            Some(entropy) => candidates.push((path, entropy)),
+            None => {
+                info!("No usable thumbnail frame in window {}", n);
+                let _ = fs::remove_file(&path).await;
             }
-            Err(e) => {
-                error!("Thumbnail generation failed: {}", e);
+        }
+    }
+    candidates
+}
+
+/// Number of variants, lowest bitrate first, that get an I-frame playlist
+pub const IFRAME_PLAYLIST_VARIANTS: usize = 2;
+
+/// Length of the program tables ahead of the first packet of one MPEG-TS segment, and
+/// its keyframes as `(pts seconds, byte offset, byte length)`. A keyframe's range
+/// runs until the next video packet; segments hold no audio.
+async fn segment_keyframes(segment: &Path) -> Result<(u64, Vec<(f64, u64, u64)>)> {
+    let output = Command::new("ffprobe")
+        .arg("-v")
+        .arg("error")
+        .arg("-select_streams")
+        .arg("v:0")
+        .arg("-show_entries")
+        .arg("packet=pts_time,pos,flags")
+        .arg("-of")
+        .arg("json")
+        .arg(segment)
+        .output()
+        .await
+        .context("failed to run ffprobe for keyframes")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("ffprobe for keyframes failed: {stderr}");
+    }
+
+    let v: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    let packets: Vec<(f64, u64, bool)> = v["packets"]
+        .as_array()
+        .map(|arr| {
+            arr.iter()
+                .filter_map(|p| {
+                    let pts = p["pts_time"].as_str()?.parse().ok()?;
+                    let pos = p["pos"].as_str()?.parse().ok()?;
+                    let key = p["flags"].as_str().is_some_and(|f| f.starts_with('K'));
+                    Some((pts, pos, key))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let size = fs::metadata(segment).await?.len();
+    // The muxer writes the SDT/PAT/PMT before the first packet of every segment
+    let tables_length = packets.first().map(|(_, pos, _)| *pos).unwrap_or(0);
+
+    let keyframes = packets
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, _, key))| *key)
+        .map(|(i, (pts, pos, _))| {
+            let end = packets.get(i + 1).map(|(_, next, _)| *next).unwrap_or(size);
+            (*pts, *pos, end.saturating_sub(*pos))
+        })
+        .collect();
+    Ok((tables_length, keyframes))
+}
+
+/// Write `iframes.m3u8` for the MPEG-TS rendition in `dir`, returning its peak bandwidth
+pub async fn write_iframe_playlist(dir: &Path) -> Result<u32> {
+    let playlist = fs::read_to_string(dir.join("index.m3u8")).await?;
+    let segments = hls::playlist_segments(&playlist);
+    let total: f64 = segments.iter().map(|(d, _)| d).sum();
+
+    let mut keyframes: Vec<(f64, u64, u64, &str, u64)> = Vec::new();
+    for (_, uri) in &segments {
+        let (tables_length, segment_keyframes) = segment_keyframes(&dir.join(uri)).await?;
+        for (pts, offset, length) in segment_keyframes {
+            keyframes.push((pts, offset, length, uri, tables_length));
+        }
+    }
+    let Some(&(first_pts, ..)) = keyframes.first() else {
+        anyhow::bail!("no keyframes found");
+    };
+
+    // Each keyframe lasts until the next one, the last one until the end of the media
+    let entries: Vec<IFrameEntry> = keyframes
+        .iter()
+        .enumerate()
+        .map(|(i, (pts, offset, length, uri, tables_length))| {
+            let end = keyframes
+                .get(i + 1)
+                .map(|(next, ..)| *next)
+                .unwrap_or(first_pts + total);
+            IFrameEntry {
+                uri: uri.to_string(),
+                tables_length: *tables_length,
+                offset: *offset,
+                length: *length,
+                duration: (end - pts).max(0.001),
             }
+        })
+        .collect();
+
+    fs::write(
+        dir.join("iframes.m3u8"),
+        hls::build_iframe_playlist(&entries),
+    )
+    .await?;
+
+    Ok(entries
+        .iter()
+        .map(|e| (e.length as f64 * 8.0 / e.duration).ceil() as u32)
+        .max()
+        .unwrap_or(0))
+}
+
+/// WebVTT index of trickplay tiles: one cue per preview frame pointing at its
+/// `sprites_{sheet}.jpg#xywh=` region
+fn build_thumbnails_vtt(
+    frames: u32,
+    duration: f64,
+    config: &TrickplayConfig,
+    tile_height: u32,
+) -> String {
+    let per_sheet = config.columns * config.rows;
+    let mut vtt = String::from("WEBVTT\n");
+    for frame in 0..frames {
+        let start = frame as f64 * config.interval;
+        if start >= duration {
+            break;
         }
+        let end = (start + config.interval).min(duration);
+        let tile = frame % per_sheet;
+        vtt.push_str(&format!(
+            "\n{} --> {}\nsprites_{:03}.jpg#xywh={},{},{},{}\n",
+            format_cue_time(start, '.'),
+            format_cue_time(end, '.'),
+            frame / per_sheet,
+            (tile % config.columns) * config.width,
+            (tile / config.columns) * tile_height,
+            config.width,
+            tile_height
+        ));
     }
+    vtt
+}
 
-    // Generate sprites (preview thumbnails grid)
+/// Tile one frame every `config.interval` seconds into `sprites_{n}.jpg` sheets and
+/// index them in `thumbnails.vtt`
+async fn generate_trickplay(
+    job: &EncodeJob,
+    metadata: &VideoMetadata,
+    config: &TrickplayConfig,
+    out_dir: &Path,
+) -> Result<()> {
+    if config.interval <= 0.0 || config.columns == 0 || config.rows == 0 || config.width == 0 {
+        anyhow::bail!("invalid trickplay settings");
+    }
+
+    // Even tile height following the display aspect ratio
+    let (display_width, display_height) = metadata.display_size();
+    let tile_height = if display_width > 0 {
+        ((config.width as f64 * display_height as f64 / display_width as f64 / 2.0).round() as u32
+            * 2)
+        .max(2)
+    } else {
+        config.width * 9 / 16
+    };
+    info!(
+        "Generating trickplay sheets: one {}x{} tile every {}s",
+        config.width, tile_height, config.interval
+    );
+
+    // HDR sources are tone-mapped after decimation, only the sampled frames pay for it
+    let tonemap = job
+        .source_hdr
+        .map(|hdr| tonemap_filter(hdr) + ",")
+        .unwrap_or_default();
+
+    // The tile filter flushes the last, partially filled sheet at end of stream
+    let mut cmd = Command::new("ffmpeg");
+    cmd.arg("-loglevel")
+        .arg("error")
+        .arg("-y")
+        .arg("-i")
+        .arg(&job.input)
+        .arg("-vf")
+        .arg(format!(
+            "fps=1/{},{}scale={}:{},setsar=1,tile={}x{}",
+            config.interval, tonemap, config.width, tile_height, config.columns, config.rows
+        ))
+        .arg("-q:v")
+        .arg("5")
+        .arg("-start_number")
+        .arg("0")
+        .arg(out_dir.join("sprites_%03d.jpg"));
+
+    let output =
+        run_ffmpeg_with_timeout(cmd, job.ffmpeg_timeout, "generating trickplay sheets", None)
+            .await?;
+    if !output.status.success() {
+        anyhow::bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    // Only index frames whose sheet was written
+    let mut sheets = 0u32;
+    while fs::try_exists(out_dir.join(format!("sprites_{:03}.jpg", sheets)))
+        .await
+        .unwrap_or(false)
     {
-        let sprite_path = out_dir.join("sprites.jpg");
-        info!("Generating thumbnail sprite: {:?}", sprite_path);
+        sheets += 1;
+    }
+    if sheets == 0 {
+        anyhow::bail!("ffmpeg wrote no sprite sheets");
+    }
+
+    let frames = sheets * config.columns * config.rows;
+    fs::write(
+        out_dir.join("thumbnails.vtt"),
+        build_thumbnails_vtt(frames, job.duration, config, tile_height),
+    )
+    .await
+    .context("failed to write thumbnails.vtt")?;
 
-        let target_frames = 100.0;
-        let fps = if duration > 0 {
-            (target_frames / duration as f64).max(0.01)
+    Ok(())
+}
+
+/// Join `config.clips` muted clips, centred in equal slices of the video, into one loop
+/// written as `preview.mp4` and animated `preview.webp`
+async fn generate_hover_preview(
+    job: &EncodeJob,
+    config: &HoverPreviewConfig,
+    out_dir: &Path,
+) -> Result<()> {
+    if config.clips == 0 || config.clip_duration <= 0.0 || config.width < 2 || config.fps == 0 {
+        anyhow::bail!("invalid hover preview settings");
+    }
+
+    // Short videos get fewer clips so they don't overlap
+    let clip_duration = config.clip_duration.min(job.duration);
+    let clips = config
+        .clips
+        .min((job.duration / clip_duration).floor() as u32)
+        .max(1);
+    info!(
+        "Generating hover preview: {} clips of {}s",
+        clips, clip_duration
+    );
+
+    let tonemap = job
+        .source_hdr
+        .map(|hdr| tonemap_filter(hdr) + ",")
+        .unwrap_or_default();
+    let mut cmd = Command::new("ffmpeg");
+    cmd.arg("-loglevel").arg("error").arg("-y");
+    let mut filter = String::new();
+    for n in 0..clips {
+        let start = (job.duration * (n as f64 + 0.5) / clips as f64 - clip_duration / 2.0).max(0.0);
+        cmd.arg("-ss")
+            .arg(format!("{:.3}", start))
+            .arg("-t")
+            .arg(format!("{:.3}", clip_duration))
+            .arg("-i")
+            .arg(&job.input);
+        filter.push_str(&format!(
+            "[{}:v:0]{}fps={},scale={}:-2,setsar=1,format=yuv420p[c{}];",
+            n,
+            tonemap,
+            config.fps,
+            config.width / 2 * 2,
+            n
+        ));
+    }
+    for n in 0..clips {
+        filter.push_str(&format!("[c{}]", n));
+    }
+    filter.push_str(&format!("concat=n={}:v=1:a=0,split=2[mp4][webp]", clips));
+
+    let mp4_path = out_dir.join("preview.mp4");
+    let webp_path = out_dir.join("preview.webp");
+    cmd.arg("-filter_complex")
+        .arg(filter)
+        .arg("-map")
+        .arg("[mp4]")
+        .arg("-an")
+        .arg("-c:v")
+        .arg("libx264")
+        .arg("-preset")
+        .arg("veryfast")
+        .arg("-crf")
+        .arg("28")
+        .arg("-movflags")
+        .arg("+faststart")
+        .arg(&mp4_path)
+        .arg("-map")
+        .arg("[webp]")
+        .arg("-an")
+        .arg("-c:v")
+        .arg("libwebp")
+        .arg("-quality")
+        .arg("60")
+        .arg("-loop")
+        .arg("0")
+        .arg(&webp_path);
+
+    let result =
+        match run_ffmpeg_with_timeout(cmd, job.ffmpeg_timeout, "generating hover preview", None)
+            .await
+        {
+            Ok(output) if output.status.success() => Ok(()),
+            Ok(output) => Err(anyhow::anyhow!(
+                "{}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )),
+            Err(e) => Err(e),
+        };
+    // Publish both formats or neither
+    if result.is_err() {
+        let _ = fs::remove_file(&mp4_path).await;
+        let _ = fs::remove_file(&webp_path).await;
+    }
+    result
+}
+
+/// Timeout heuristic: long enough for slow encodes, but not infinite.
+///  - minimum 30 minutes
+///  - ~20x realtime based on duration
+///  - cap at 6 hours per ffmpeg invocation
+fn encode_timeout(duration: u32) -> Duration {
+    Duration::from_secs((duration as u64).saturating_mul(20).max(30 * 60))
+        .min(Duration::from_secs(6 * 60 * 60))
+}
+
+/// Add the ClearKey `EXT-X-KEY` tag FFmpeg doesn't write for CENC to the media
+/// playlists of the rendition directories `dirs`
+async fn tag_cenc_playlists(out_dir: &Path, dirs: &[String], kid: &[u8; 16]) -> Result<()> {
+    let tag = format!("#EXT-X-KEY:{}", hls::clearkey_key_attributes(kid));
+    for dir in dirs {
+        let playlist_path = out_dir.join(dir).join("index.m3u8");
+        let playlist = fs::read_to_string(&playlist_path)
+            .await
+            .with_context(|| format!("failed to read media playlist of {}", dir))?;
+        fs::write(
+            &playlist_path,
+            hls::insert_tag_before(&playlist, &["#EXT-X-MAP", "#EXTINF"], &tag),
+        )
+        .await
+        .with_context(|| format!("failed to write media playlist of {}", dir))?;
+    }
+    Ok(())
+}
+
+/// Encode the H.264 ladder `variants` (plus any extra codec families) to HLS in `out_dir`.
+/// Returns every rendition that made it into the master playlist and the measured
+/// loudness of each audio track. With `burn_subtitles` that subtitle track is rendered
+/// into a copy of the H.264 ladder, served from its own `burned.m3u8` master.
+/// Long sources are split into ranges when `chunked_encoding` is enabled, using idle
+/// permits of `encode_slots` next to the one the caller holds.
+#[allow(clippy::too_many_arguments)]
+pub async fn encode_to_hls(
+    input: &PathBuf,
+    out_dir: &PathBuf,
+    progress: &ProgressMap,
+    upload_id: &str,
+    video_config: &VideoConfig,
+    metadata: &VideoMetadata,
+    variants: &[VideoVariant],
+    audio_streams: &[AudioStreamInfo],
+    subtitle_streams: &[SubtitleStreamInfo],
+    encryption: Option<&SegmentEncryption>,
+    burn_subtitles: Option<usize>,
+    profile: &EncodingProfile,
+    encode_slots: &Semaphore,
+) -> Result<HlsOutput> {
+    fs::create_dir_all(out_dir).await?;
+
+    if variants.is_empty() {
+        anyhow::bail!(
+            "No suitable variants for video size {}x{}",
+            metadata.width,
+            metadata.height
+        );
+    }
+    let duration = metadata.duration;
+
+    let encoder_type = EncoderType::from_string(&video_config.encoder);
+    // SDR families (tone-mapped for HDR sources), plus a 10-bit HEVC ladder when HDR is kept
+    let mut codec_families: Vec<(VideoCodec, Option<HdrFormat>, bool)> = video_config
+        .codec_families()
+        .into_iter()
+        .map(|codec| (codec, None, false))
+        .collect();
+    if let Some(hdr) = metadata.hdr {
+        info!(
+            "HDR source detected ({}), tone-mapping SDR ladder",
+            hdr.video_range()
+        );
+        if video_config.hdr_renditions {
+            codec_families.push((VideoCodec::Hevc, Some(hdr), false));
+        }
+    }
+
+    // Burned-in subtitles get an extra SDR H.264 ladder; a track that can't be
+    // prepared only drops that ladder
+    let burn_dir = out_dir.join("burn-in");
+    let burn_in = match burn_subtitles.map(|idx| (idx, subtitle_streams.get(idx))) {
+        None => None,
+        Some((idx, None)) => {
+            warn!("Skipping burned-in subtitles: no subtitle track {}", idx);
+            None
+        }
+        Some((idx, Some(sub))) => match prepare_burn_in(input, sub, idx, &burn_dir).await {
+            Ok(burn_in) => {
+                info!("Burning subtitle track {} into an extra ladder", idx);
+                codec_families.push((VideoCodec::H264, None, true));
+                Some(burn_in)
+            }
+            Err(e) => {
+                warn!("Skipping burned-in subtitles of track {}: {}", idx, e);
+                None
+            }
+        },
+    };
+
+    // GOP size - the profile's keyframe interval at the output frame rate, keeps HLS segments aligned
+    let gop = (variants[0].fps * profile.keyframe_interval)
+        .round()
+        .max(1.0) as u32;
+
+    // DASH and CENC need every rendition, H.264 included, packaged as CMAF
+    let cmaf = video_config.dash || matches!(encryption, Some(SegmentEncryption::Cenc { .. }));
+
+    // CMAF families need their own fMP4 copy of every audio track
+    let audio_copies = if cmaf {
+        1
+    } else if codec_families.len() > 1 {
+        2
+    } else {
+        1
+    };
+    // Tracks with more than two channels get a multichannel rendition next to the stereo one
+    let audio_formats: usize = audio_streams
+        .iter()
+        .map(|s| if s.channels.unwrap_or(2) > 2 { 2 } else { 1 })
+        .sum();
+
+    // Total tasks = video variants per codec family + audio renditions + thumbnail + trickplay
+    let total_steps =
+        (variants.len() * codec_families.len()) as u32 + (audio_formats * audio_copies) as u32 + 2;
+
+    let ffmpeg_timeout = encode_timeout(duration);
+
+    let job = EncodeJob {
+        input: input.clone(),
+        source_fps: metadata.fps,
+        duration: metadata.duration as f64,
+        source_hdr: metadata.hdr,
+        progress: progress.clone(),
+        upload_id: upload_id.to_string(),
+        ffmpeg_timeout,
+        total_steps,
+        encryption: encryption.cloned(),
+        burn_in,
+        profile: profile.clone(),
+    };
+
+    let ranges = chunk_ranges(
+        &video_config.chunked_encoding,
+        job.duration,
+        profile.segment_duration,
+    );
+    if !ranges.is_empty() {
+        info!("Encoding {}s source in {} ranges", duration, ranges.len());
+    }
+
+    // Encode video variants sequentially (avoids spawning many tasks for large batches).
+    // With `decode_once` a whole codec family shares one FFmpeg process and one decode.
+    // The H.264 ladder is required; extra codec families are dropped if their encoder fails.
+    let mut encoded_variants: Vec<VideoVariant> = Vec::new();
+    let mut current_chunk = 0u32;
+    for (codec, hdr, burned) in &codec_families {
+        let family_variants: Vec<VideoVariant> = variants
+            .iter()
+            .map(|v| {
+                v.with_codec(*codec)
+                    .with_hdr(*hdr)
+                    .with_cmaf(cmaf)
+                    .with_burned_subtitles(*burned)
+            })
+            .collect();
+        let batches: Vec<&[VideoVariant]> = if video_config.decode_once {
+            vec![&family_variants[..]]
+        } else {
+            family_variants.chunks(1).collect()
+        };
+
+        let mut family: Vec<VideoVariant> = Vec::new();
+        for batch in batches {
+            current_chunk += batch.len() as u32;
+
+            for variant in batch {
+                info!(
+                    "Encoding variant: {} at {}x{} with bitrate {}kbps (max: {}kbps)",
+                    variant.dir(),
+                    variant.width,
+                    variant.height,
+                    variant.bitrate,
+                    variant.max_bitrate()
+                );
+            }
+
+            let names = batch
+                .iter()
+                .map(|v| format!("{} ({}p)", v.dir(), v.height))
+                .collect::<Vec<_>>()
+                .join(", ");
+            job.report(current_chunk, format!("Encoding variant: {}", names))
+                .await;
+
+            // Burned-in text subtitles are timed from the start of the source, so that
+            // ladder is encoded in one pass
+            let encoded = if ranges.is_empty() || *burned {
+                encode_video_variants(
+                    &job,
+                    batch,
+                    out_dir,
+                    &encoder_type,
+                    gop,
+                    current_chunk,
+                    None,
+                )
+                .await
+            } else {
+                match encode_video_variants_chunked(
+                    &job,
+                    batch,
+                    out_dir,
+                    &encoder_type,
+                    gop,
+                    current_chunk,
+                    &ranges,
+                    encode_slots,
+                )
+                .await
+                {
+                    Ok(()) => Ok(()),
+                    Err(e) => {
+                        warn!(
+                            "Chunked encode of {} failed, encoding in one pass: {}",
+                            names, e
+                        );
+                        for variant in batch {
+                            let _ = fs::remove_dir_all(out_dir.join(variant.dir())).await;
+                        }
+                        encode_video_variants(
+                            &job,
+                            batch,
+                            out_dir,
+                            &encoder_type,
+                            gop,
+                            current_chunk,
+                            None,
+                        )
+                        .await
+                    }
+                }
+            };
+            match encoded {
+                Ok(()) => {
+                    job.report(current_chunk, format!("Encoded variant: {}", names))
+                        .await;
+                    family.extend(batch.iter().cloned());
+                }
+                Err(e) if *codec != VideoCodec::H264 || hdr.is_some() || *burned => {
+                    warn!("Skipping {:?} renditions, {} failed: {}", codec, names, e);
+                    for variant in family.iter().chain(batch) {
+                        let _ = fs::remove_dir_all(out_dir.join(variant.dir())).await;
+                    }
+                    family.clear();
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        encoded_variants.extend(family);
+    }
+    let _ = fs::remove_dir_all(&burn_dir).await;
+    let (burned_variants, encoded_variants): (Vec<VideoVariant>, Vec<VideoVariant>) =
+        encoded_variants
+            .into_iter()
+            .partition(|v| v.burned_subtitles);
+    // Encode each audio stream as a separate HLS audio playlist (sequential).
+    // MPEG-TS renditions serve the H.264 ladder, fMP4 copies serve the CMAF ladders.
+    // Surround tracks are kept as a multichannel rendition in their own group.
+    let segment_types: Vec<HlsSegmentType> = [HlsSegmentType::MpegTs, HlsSegmentType::Fmp4]
+        .into_iter()
+        .filter(|t| encoded_variants.iter().any(|v| v.segment_type == *t))
+        .collect();
+
+    let mut audio_renditions: Vec<AudioRendition> = Vec::new();
+    let mut stereo_only: Vec<AudioRendition> = Vec::new();
+    let mut audio_loudness: Vec<Option<f64>> = Vec::new();
+    for (audio_idx, audio_stream) in audio_streams.iter().enumerate() {
+        let audio_label = audio_stream
+            .language
+            .clone()
+            .unwrap_or_else(|| format!("track_{}", audio_idx));
+        let language = audio_stream.language.as_deref().unwrap_or("und");
+        let name = audio_stream
+            .title
+            .clone()
+            .unwrap_or_else(|| get_language_display_name(language));
+        let source_channels = audio_stream.channels.unwrap_or(2).max(1) as u32;
+
+        // Two-pass EBU R128: measure once, then apply the same gain to every rendition
+        let loudness = &video_config.loudness;
+        let measurement = if loudness.enabled {
+            job.report(
+                current_chunk,
+                format!("Measuring loudness: {}", audio_label),
+            )
+            .await;
+            match measure_loudness(&job, audio_idx, loudness).await {
+                Ok(m) => {
+                    info!(
+                        "Audio track {} measured {:.1} LUFS, normalizing to {} LUFS",
+                        audio_label, m.input_i, loudness.target_lufs
+                    );
+                    Some(m)
+                }
+                Err(e) => {
+                    warn!(
+                        "Skipping loudness normalization of audio track {}: {}",
+                        audio_label, e
+                    );
+                    None
+                }
+            }
         } else {
-            1.0
+            None
         };
+        let audio_filter = measurement.as_ref().map(|m| m.filter(loudness));
+        audio_loudness.push(measurement.map(|m| m.input_i));
 
-        let vf_filter = format!("fps={:.4},scale=160:-1,tile=10x10", fps);
+        info!(
+            "Encoding audio track {}: {} (codec: {}, channels: {:?})",
+            audio_idx, audio_label, audio_stream.codec_name, audio_stream.channels
+        );
+
+        for segment_type in &segment_types {
+            let suffix = match segment_type {
+                HlsSegmentType::MpegTs => "",
+                HlsSegmentType::Fmp4 => "_fmp4",
+            };
+            let (stereo_group, surround_group) = hls::audio_group_ids(*segment_type);
+
+            let stereo_channels = source_channels.min(2);
+            let mut targets = vec![(
+                stereo_group,
+                format!("audio_{}{}", audio_label, suffix),
+                AudioCodec::Aac,
+                stereo_channels,
+            )];
+            if source_channels > 2 {
+                let codec = video_config.surround_codec;
+                targets.push((
+                    surround_group,
+                    format!("audio_{}_surround{}", audio_label, suffix),
+                    codec,
+                    source_channels.min(codec.max_channels()),
+                ));
+            }
+
+            for (group_id, dir, codec, channels) in targets {
+                current_chunk += 1;
+                job.report(
+                    current_chunk,
+                    format!("Encoding audio track: {} ({}ch)", audio_label, channels),
+                )
+                .await;
+
+                let rendition = AudioRendition {
+                    group_id: group_id.to_string(),
+                    dir,
+                    language: language.to_string(),
+                    name: name.clone(),
+                    is_default: audio_stream.is_default || audio_idx == 0,
+                    track: audio_idx,
+                    codec,
+                    channels,
+                    bitrate: job.profile.audio.bitrate(channels),
+                };
+                encode_audio_rendition(
+                    &job,
+                    audio_idx,
+                    &rendition,
+                    audio_filter.as_deref(),
+                    out_dir,
+                    *segment_type,
+                )
+                .await?;
+
+                if source_channels <= 2 {
+                    // Listed in the surround group too, so every group offers every language
+                    stereo_only.push(AudioRendition {
+                        group_id: surround_group.to_string(),
+                        ..rendition.clone()
+                    });
+                }
+                audio_renditions.push(rendition);
+            }
+        }
+
+        info!("Audio track {} encoded successfully", audio_label);
+    }
+    let has_surround = audio_streams.iter().any(|s| s.channels.unwrap_or(2) > 2);
+    if has_surround {
+        audio_renditions.extend(stereo_only);
+    }
+
+    // Text subtitles become segmented WebVTT renditions so native HLS players see them.
+    // Bitmap formats are OCR'd into a companion SRT when enabled, otherwise skipped;
+    // a failed conversion only drops that track.
+    let mut subtitle_renditions: Vec<SubtitleRendition> = Vec::new();
+    let mut ocr_subtitles: Vec<usize> = Vec::new();
+    for (sub_idx, sub) in subtitle_streams.iter().enumerate() {
+        let bitmap = is_bitmap_subtitle(&sub.codec_name);
+        if bitmap && !video_config.ocr.enabled {
+            continue;
+        }
+
+        let vtt = if bitmap {
+            job.report(
+                current_chunk,
+                format!("Running OCR on subtitle track {}", sub_idx),
+            )
+            .await;
+            let work_dir = out_dir.join(format!("ocr-{}", sub_idx));
+            let vtt = async {
+                let cues = ocr_bitmap_subtitle(
+                    &job,
+                    sub_idx,
+                    (metadata.width, metadata.height),
+                    sub.language.as_deref(),
+                    &video_config.ocr,
+                    &work_dir,
+                )
+                .await?;
+                if cues.is_empty() {
+                    anyhow::bail!("OCR found no text");
+                }
+                let subtitles_dir = out_dir.join("subtitles");
+                fs::create_dir_all(&subtitles_dir).await?;
+                fs::write(
+                    subtitles_dir.join(format!("track_{}_ocr.srt", sub_idx)),
+                    cues_to_srt(&cues),
+                )
+                .await?;
+                Ok(cues_to_webvtt(&cues))
+            }
+            .await;
+            let _ = fs::remove_dir_all(&work_dir).await;
+            vtt
+        } else {
+            let vtt_path = out_dir.join(format!("subs_{}.vtt", sub_idx));
+            job.report(
+                current_chunk,
+                format!("Converting subtitle track {} to WebVTT", sub_idx),
+            )
+            .await;
+            let vtt = async {
+                extract_webvtt(&job, sub_idx, &vtt_path).await?;
+                Ok::<_, anyhow::Error>(fs::read_to_string(&vtt_path).await?)
+            }
+            .await;
+            let _ = fs::remove_file(&vtt_path).await;
+            vtt
+        };
+        let vtt = match vtt {
+            Ok(vtt) => vtt,
+            Err(e) => {
+                warn!(
+                    "Skipping WebVTT rendition of subtitle track {}: {}",
+                    sub_idx, e
+                );
+                continue;
+            }
+        };
+        if bitmap {
+            info!("OCR text track created for subtitle track {}", sub_idx);
+            ocr_subtitles.push(sub_idx);
+        }
+
+        let language = sub.language.as_deref().unwrap_or("und");
+        let mut name = sub
+            .title
+            .clone()
+            .unwrap_or_else(|| get_language_display_name(language));
+        if bitmap {
+            name.push_str(" (OCR)");
+        }
+
+        for segment_type in &segment_types {
+            let suffix = match segment_type {
+                HlsSegmentType::MpegTs => "",
+                HlsSegmentType::Fmp4 => "_fmp4",
+            };
+            let dir = format!("subs_{}{}", sub_idx, suffix);
+            let sub_dir = out_dir.join(&dir);
+            fs::create_dir_all(&sub_dir).await?;
+
+            let segments = hls::segment_webvtt(
+                &vtt,
+                duration as f64,
+                hls::SUBTITLE_SEGMENT_SECONDS,
+                hls::subtitle_mpegts_offset(*segment_type),
+            );
+            for (i, segment) in segments.iter().enumerate() {
+                fs::write(sub_dir.join(format!("segment_{:03}.vtt", i)), segment).await?;
+            }
+            if *segment_type == HlsSegmentType::Fmp4 {
+                fs::write(sub_dir.join(hls::SUBTITLE_WEBVTT_FILE), &vtt).await?;
+            }
+            fs::write(
+                sub_dir.join("index.m3u8"),
+                hls::build_subtitle_playlist(
+                    segments.len(),
+                    duration as f64,
+                    hls::SUBTITLE_SEGMENT_SECONDS,
+                ),
+            )
+            .await?;
+
+            subtitle_renditions.push(SubtitleRendition {
+                group_id: hls::subtitle_group_id(*segment_type).to_string(),
+                dir,
+                language: language.to_string(),
+                name: name.clone(),
+                is_default: sub.is_default,
+                is_forced: sub.is_forced,
+            });
+        }
+    }
+
+    // Thumbnail: the candidate with the highest entropy, or a single frame at 10% of the video
+    let thumbnail_path = out_dir.join("thumbnail.jpg");
+    let candidates = generate_thumbnail_candidates(&job, &out_dir.join("thumbnails")).await;
+    let best = candidates.iter().max_by(|(_, a), (_, b)| a.total_cmp(b));
+    if let Some((best, _)) = best
+        && fs::copy(best, &thumbnail_path).await.is_ok()
+    {
+        info!(
+            "Thumbnail picked from {} candidates: {:?}",
+            candidates.len(),
+            best
+        );
+    } else {
+        info!("Generating thumbnail: {:?}", thumbnail_path);
+
+        let seek_time = (duration as f64 * 0.1).max(1.0);
 
         let mut cmd = Command::new("ffmpeg");
         cmd.arg("-loglevel")
             .arg("error")
             .arg("-y")
+            .arg("-ss")
+            .arg(format!("{}", seek_time))
             .arg("-i")
-            .arg(&input)
+            .arg(input)
             .arg("-vf")
-            .arg(&vf_filter)
+            .arg(format!(
+                "{}scale={}:-2",
+                job.source_hdr
+                    .map(|hdr| tonemap_filter(hdr) + ",")
+                    .unwrap_or_default(),
+                job.profile.thumbnail_width
+            ))
             .arg("-frames:v")
             .arg("1")
             .arg("-q:v")
-            .arg("5")
-            .arg(&sprite_path);
+            .arg("2")
+            .arg(&thumbnail_path);
 
-        match run_ffmpeg_with_timeout(cmd, ffmpeg_timeout, "generating sprites").await {
+        match run_ffmpeg_with_timeout(cmd, ffmpeg_timeout, "generating thumbnail", None).await {
             Ok(output) => {
                 if !output.status.success() {
                     let stderr = String::from_utf8_lossy(&output.stderr);
-                    error!("Thumbnail sprite generation failed: {}", stderr);
+                    error!("Thumbnail generation failed: {}", stderr);
                 }
             }
             Err(e) => {
-                error!("Thumbnail sprite generation failed: {}", e);
+                error!("Thumbnail generation failed: {}", e);
             }
         }
     }
 
+    // Generate trickplay sprite sheets and their thumbnails.vtt index
+    let trickplay = match generate_trickplay(
+        &job,
+        metadata,
+        profile
+            .trickplay
+            .as_ref()
+            .unwrap_or(&video_config.trickplay),
+        out_dir,
+    )
+    .await
+    {
+        Ok(()) => true,
+        Err(e) => {
+            error!("Trickplay generation failed: {}", e);
+            false
+        }
+    };
+
+    let hover_preview = video_config.hover_preview.enabled
+        && match generate_hover_preview(&job, &video_config.hover_preview, out_dir).await {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Hover preview generation failed: {}", e);
+                false
+            }
+        };
+
     // Create master playlist with audio track support
     let master_playlist_path = out_dir.join("index.m3u8");
-    let mut master_content = String::from("#EXTM3U\n#EXT-X-VERSION:3\n\n");
-
-    let variants_ref = get_variants_for_height(get_video_height(&input).await?);
-
-    // Add audio tracks as EXT-X-MEDIA entries
-    if !audio_streams.is_empty() {
-        for (idx, audio) in audio_streams.iter().enumerate() {
-            let audio_label = audio
-                .language
-                .clone()
-                .unwrap_or_else(|| format!("track_{}", idx));
-            let language = audio.language.as_deref().unwrap_or("und");
-            let name = audio
-                .title
-                .clone()
-                .unwrap_or_else(|| get_language_display_name(language));
-            let is_default = if audio.is_default || idx == 0 {
-                "YES"
-            } else {
-                "NO"
-            };
-            let autoselect = if audio.is_default || idx == 0 {
-                "YES"
-            } else {
-                "NO"
-            };
+    let mut master_content =
+        hls::build_master_playlist(&encoded_variants, &audio_renditions, &subtitle_renditions);
+
+    // I-frame playlists for trick play on the lowest MPEG-TS variants. CMAF fragments
+    // span a whole segment, and AES-128 CBC can't be entered mid-segment, so both are skipped.
+    if !matches!(encryption, Some(SegmentEncryption::Aes128 { .. })) {
+        let mut ts_variants: Vec<&VideoVariant> = encoded_variants
+            .iter()
+            .filter(|v| v.segment_type == HlsSegmentType::MpegTs)
+            .collect();
+        ts_variants.sort_by_key(|v| v.bitrate);
+        for variant in ts_variants.into_iter().take(IFRAME_PLAYLIST_VARIANTS) {
+            match write_iframe_playlist(&out_dir.join(variant.dir())).await {
+                Ok(bandwidth) => {
+                    master_content.push_str(&hls::iframe_stream_inf(variant, bandwidth))
+                }
+                Err(e) => warn!("Skipping I-frame playlist of {}: {}", variant.dir(), e),
+            }
+        }
+    }
 
-            master_content.push_str(&format!(
-                "#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"audio\",LANGUAGE=\"{}\",NAME=\"{}\",DEFAULT={},AUTOSELECT={},URI=\"audio_{}/index.m3u8\"\n",
-                language,
-                name,
-                is_default,
-                autoselect,
-                audio_label
-            ));
+    // The burned-in ladder shares the audio renditions; soft subtitles would double up
+    let mut burned_content = (!burned_variants.is_empty())
+        .then(|| hls::build_master_playlist(&burned_variants, &audio_renditions, &[]));
+
+    // FFmpeg writes no key tags for CENC; announce the ClearKey system in every playlist
+    if let Some(SegmentEncryption::Cenc { kid, .. }) = encryption {
+        let attributes = hls::clearkey_key_attributes(kid);
+        let mut dirs: Vec<String> = encoded_variants
+            .iter()
+            .chain(&burned_variants)
+            .map(|v| v.dir())
+            .collect();
+        for rendition in &audio_renditions {
+            if !dirs.contains(&rendition.dir) {
+                dirs.push(rendition.dir.clone());
+            }
         }
-        master_content.push('\n');
+        tag_cenc_playlists(out_dir, &dirs, kid).await?;
+        let session_key = format!("#EXT-X-SESSION-KEY:{}", attributes);
+        master_content = hls::insert_tag_before(
+            &master_content,
+            &["#EXT-X-MEDIA", "#EXT-X-STREAM-INF"],
+            &session_key,
+        );
+        burned_content = burned_content.map(|content| {
+            hls::insert_tag_before(
+                &content,
+                &["#EXT-X-MEDIA", "#EXT-X-STREAM-INF"],
+                &session_key,
+            )
+        });
     }
 
-    // Add video stream variants with audio group reference
-    for variant in &variants_ref {
-        let audio_group = if !audio_streams.is_empty() {
-            ",AUDIO=\"audio\""
-        } else {
-            ""
-        };
+    fs::write(&master_playlist_path, master_content)
+        .await
+        .context("failed to write master playlist")?;
+    if let Some(content) = burned_content {
+        fs::write(out_dir.join("burned.m3u8"), content)
+            .await
+            .context("failed to write burned-in master playlist")?;
+    }
+
+    Ok(HlsOutput {
+        variants: encoded_variants,
+        audio: audio_renditions,
+        audio_loudness,
+        ocr_subtitles,
+        burned_variants,
+        trickplay,
+        hover_preview,
+    })
+}
+
+/// Encode single renditions for an existing video into `out_dir`, one directory
+/// per variant. Used to add a tier after the fact, so only video is encoded; the
+/// audio and subtitle renditions already in the bucket are shared. An H.264 SDR
+/// failure fails the call, other families are dropped like in `encode_to_hls`.
+#[allow(clippy::too_many_arguments)]
+pub async fn encode_variants(
+    input: &Path,
+    out_dir: &PathBuf,
+    progress: &ProgressMap,
+    upload_id: &str,
+    video_config: &VideoConfig,
+    metadata: &VideoMetadata,
+    variants: &[VideoVariant],
+    encryption: Option<&SegmentEncryption>,
+    profile: &EncodingProfile,
+) -> Result<Vec<VideoVariant>> {
+    fs::create_dir_all(out_dir).await?;
+    let Some(first) = variants.first() else {
+        return Ok(Vec::new());
+    };
 
-        let stream_inf = format!(
-            "#EXT-X-STREAM-INF:BANDWIDTH={},RESOLUTION={}x{}{}\n",
-            variant.bandwidth(),
-            (((variant.height as f32) * 16.0) / 9.0) as u32,
+    let job = EncodeJob {
+        input: input.to_path_buf(),
+        source_fps: metadata.fps,
+        duration: metadata.duration as f64,
+        source_hdr: metadata.hdr,
+        progress: progress.clone(),
+        upload_id: upload_id.to_string(),
+        ffmpeg_timeout: encode_timeout(metadata.duration),
+        total_steps: variants.len() as u32,
+        encryption: encryption.cloned(),
+        burn_in: None,
+        profile: profile.clone(),
+    };
+    let encoder_type = EncoderType::from_string(&video_config.encoder);
+    let gop = (first.fps * profile.keyframe_interval).round().max(1.0) as u32;
+
+    let mut encoded: Vec<VideoVariant> = Vec::new();
+    for (idx, variant) in variants.iter().enumerate() {
+        let current_chunk = idx as u32 + 1;
+        let name = format!("{} ({}p)", variant.dir(), variant.height);
+        info!(
+            "Encoding added variant: {} at {}x{} with bitrate {}kbps",
+            variant.dir(),
+            variant.width,
             variant.height,
-            audio_group
+            variant.bitrate
         );
+        job.report(current_chunk, format!("Encoding variant: {}", name))
+            .await;
+
+        let batch = std::slice::from_ref(variant);
+        match encode_video_variants(
+            &job,
+            batch,
+            out_dir,
+            &encoder_type,
+            gop,
+            current_chunk,
+            None,
+        )
+        .await
+        {
+            Ok(()) => encoded.push(variant.clone()),
+            Err(e) if variant.codec != VideoCodec::H264 || variant.hdr.is_some() => {
+                warn!("Skipping {}, encoding failed: {}", name, e);
+                let _ = fs::remove_dir_all(out_dir.join(variant.dir())).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
 
-        master_content.push_str(&stream_inf);
-        master_content.push_str(&format!("{}/index.m3u8\n", variant.label));
+    if let Some(SegmentEncryption::Cenc { kid, .. }) = encryption {
+        let dirs: Vec<String> = encoded.iter().map(|v| v.dir()).collect();
+        tag_cenc_playlists(out_dir, &dirs, kid).await?;
     }
 
-    fs::write(&master_playlist_path, master_content)
-        .await
-        .context("failed to write master playlist")?;
+    Ok(encoded)
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunking(min_duration: u32, chunk_duration: u32) -> ChunkedEncodingConfig {
+        ChunkedEncodingConfig {
+            enabled: true,
+            min_duration,
+            chunk_duration,
+        }
+    }
+
+    #[test]
+    fn test_chunk_ranges_align_to_segments() {
+        // 301s rounds down to 75 segments of 4s; the 50.5s remainder joins the last range
+        let ranges = chunk_ranges(&chunking(600, 301), 1250.5, 4);
+        let starts: Vec<u64> = ranges.iter().map(|r| r.start).collect();
+        let lengths: Vec<Option<u64>> = ranges.iter().map(|r| r.length).collect();
+        let first_segments: Vec<u64> = ranges.iter().map(|r| r.first_segment).collect();
+        assert_eq!(starts, [0, 300, 600, 900]);
+        assert_eq!(lengths, [Some(300), Some(300), Some(300), None]);
+        assert_eq!(first_segments, [0, 75, 150, 225]);
+    }
+
+    #[test]
+    fn test_chunk_ranges_uneven_segment_length() {
+        // 6s segments: 100s ranges become 96s, 16 segments each
+        let ranges = chunk_ranges(&chunking(0, 100), 300.0, 6);
+        assert_eq!(ranges.len(), 3);
+        for (idx, range) in ranges.iter().enumerate() {
+            assert_eq!(range.start, idx as u64 * 96);
+            assert_eq!(range.start % 6, 0);
+            assert_eq!(range.first_segment, idx as u64 * 16);
+        }
+        assert_eq!(ranges[2].length, None);
+    }
+
+    #[test]
+    fn test_chunk_ranges_shorter_than_a_chunk_use_one_pass() {
+        assert!(chunk_ranges(&chunking(0, 300), 250.0, 4).is_empty());
+        // A single range and its remainder is still one pass
+        assert!(chunk_ranges(&chunking(0, 300), 599.0, 4).is_empty());
+        // Chunks shorter than a segment grow to one segment
+        assert_eq!(chunk_ranges(&chunking(0, 1), 12.0, 4).len(), 3);
+    }
+
+    #[test]
+    fn test_chunk_ranges_disabled_or_short_source() {
+        let mut config = chunking(1800, 300);
+        assert!(chunk_ranges(&config, 1799.0, 4).is_empty());
+        assert_eq!(chunk_ranges(&config, 1800.0, 4).len(), 6);
+        config.enabled = false;
+        assert!(chunk_ranges(&config, 7200.0, 4).is_empty());
+    }
 }
@@ -0,0 +1,277 @@
+//! `akane worker`: a remote encoding process. It claims jobs from the main server's
+//! job API, downloads the source, runs the same encode stage as the server with this
+//! machine's encoder, uploads the result to R2 and reports progress back. Metadata
+//! stays with the server, which finishes the upload once the job completes.
+
+use crate::clickhouse;
+use crate::config::Config;
+use crate::handlers::common::now_millis;
+use crate::handlers::upload::{SourceProbe, encode_source, update_progress};
+use crate::storage::upload_hls_to_r2;
+use crate::types::{
+    AppState, ClaimedJob, ProgressMap, ProgressUpdate, RemoteJobFailure, RemoteJobResult,
+};
+
+use anyhow::{Context, Result};
+use aws_sdk_s3::Client as S3Client;
+use reqwest::{StatusCode, header};
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{OwnedSemaphorePermit, RwLock, Semaphore};
+use tracing::{error, info, warn};
+
+/// How often the local progress of a job is sent to the server, keeping its claim alive
+const REPORT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Client of the server's `/api/worker/jobs` routes
+struct JobApi {
+    client: reqwest::Client,
+    base_url: String,
+    token: String,
+}
+
+impl JobApi {
+    fn url(&self, path: &str) -> String {
+        format!(
+            "{}/api/worker/jobs/{}",
+            self.base_url.trim_end_matches('/'),
+            path
+        )
+    }
+
+    async fn post<T: Serialize>(&self, path: &str, body: &T) -> Result<reqwest::Response> {
+        Ok(self
+            .client
+            .post(self.url(path))
+            .bearer_auth(&self.token)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(body)?)
+            .send()
+            .await?)
+    }
+
+    async fn claim(&self) -> Result<Option<ClaimedJob>> {
+        let response = self
+            .client
+            .post(self.url("claim"))
+            .bearer_auth(&self.token)
+            .send()
+            .await?;
+        match response.status() {
+            StatusCode::NO_CONTENT => Ok(None),
+            status if status.is_success() => {
+                Ok(Some(serde_json::from_slice(&response.bytes().await?)?))
+            }
+            status => anyhow::bail!("server answered {}", status),
+        }
+    }
+
+    async fn download_source(&self, job_id: &str, path: &Path) -> Result<()> {
+        let mut response = self
+            .client
+            .get(self.url(&format!("{}/source", job_id)))
+            .bearer_auth(&self.token)
+            .send()
+            .await?
+            .error_for_status()?;
+        let mut file = fs::File::create(path).await?;
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+/// Claim and encode jobs until the process is stopped, up to
+/// `server.max_concurrent_encodes` at a time
+pub async fn run(config: Config, s3: S3Client) -> Result<()> {
+    if config.worker.token.is_empty() {
+        anyhow::bail!("worker.token must be set to run a worker");
+    }
+
+    let api = Arc::new(JobApi {
+        client: reqwest::Client::new(),
+        base_url: config.worker.server_url.clone(),
+        token: config.worker.token.clone(),
+    });
+    let poll_interval = Duration::from_secs(config.worker.poll_interval.max(1));
+
+    // The storage helpers take the whole state; the database is never opened here
+    let state = AppState {
+        db_pool: SqlitePool::connect_lazy("sqlite::memory:")?,
+        clickhouse: clickhouse::initialize_client(&config.clickhouse),
+        ffmpeg_semaphore: Arc::new(Semaphore::new(config.server.max_concurrent_encodes)),
+        config,
+        s3,
+        progress: Arc::new(RwLock::new(HashMap::new())),
+        active_viewers: Arc::new(RwLock::new(HashMap::new())),
+        chunked_uploads: Arc::new(RwLock::new(HashMap::new())),
+        remote_jobs: Arc::new(RwLock::new(HashMap::new())),
+    };
+
+    info!(
+        "Worker claiming jobs from {} with {}",
+        api.base_url, state.config.video.encoder
+    );
+    loop {
+        let permit = state.ffmpeg_semaphore.clone().acquire_owned().await?;
+        match api.claim().await {
+            Ok(Some(job)) => {
+                let state = state.clone();
+                let api = api.clone();
+                tokio::spawn(async move { run_job(&state, &api, job, permit).await });
+            }
+            Ok(None) => {
+                drop(permit);
+                tokio::time::sleep(poll_interval).await;
+            }
+            Err(e) => {
+                warn!("Claiming a job failed: {}", e);
+                drop(permit);
+                tokio::time::sleep(poll_interval).await;
+            }
+        }
+    }
+}
+
+/// Run one claimed job and tell the server how it went
+async fn run_job(
+    state: &AppState,
+    api: &Arc<JobApi>,
+    job: ClaimedJob,
+    permit: OwnedSemaphorePermit,
+) {
+    let job_id = job.job_id.clone();
+    info!("Encoding video {} (job {})", job.spec.video_id, job_id);
+
+    let download_progress = ProgressUpdate {
+        stage: "Downloading source".to_string(),
+        current_chunk: 0,
+        total_chunks: 1,
+        percentage: 0,
+        details: Some("Fetching the source on a remote worker...".to_string()),
+        status: "processing".to_string(),
+        result: None,
+        error: None,
+        video_name: None,
+        created_at: now_millis(),
+        variant_percentage: None,
+        encode_speed: None,
+        eta_seconds: None,
+    };
+    update_progress(&state.progress, &job_id, download_progress).await;
+
+    // Once the server drops the job (cancelled, or handed to another worker after a
+    // lapsed claim) the encode is abandoned, FFmpeg killed and nothing more uploaded
+    let work_dir = std::env::temp_dir().join(format!("akane-worker-{}", job_id));
+    let result = tokio::select! {
+        result = encode_job(state, api, &job, &work_dir, permit) => Some(result),
+        () = report_progress(state.progress.clone(), api.clone(), job_id.clone()) => None,
+    };
+    let _ = fs::remove_dir_all(&work_dir).await;
+    state.progress.write().await.remove(&job_id);
+
+    let Some(result) = result else {
+        warn!("Server dropped job {}, stopped encoding it", job_id);
+        return;
+    };
+    let response = match result {
+        Ok(result) => {
+            info!("Finished video {} (job {})", job.spec.video_id, job_id);
+            api.post(&format!("{}/complete", job_id), &result).await
+        }
+        Err(e) => {
+            error!("Job {} failed: {:?}", job_id, e);
+            let failure = RemoteJobFailure {
+                error: e.to_string(),
+            };
+            api.post(&format!("{}/fail", job_id), &failure).await
+        }
+    };
+    match response {
+        Ok(response) if !response.status().is_success() => warn!(
+            "Server rejected the outcome of job {}: {}",
+            job_id,
+            response.status()
+        ),
+        Ok(_) => {}
+        Err(e) => warn!("Failed to report the outcome of job {}: {}", job_id, e),
+    }
+}
+
+async fn encode_job(
+    state: &AppState,
+    api: &JobApi,
+    job: &ClaimedJob,
+    work_dir: &Path,
+    permit: OwnedSemaphorePermit,
+) -> Result<RemoteJobResult> {
+    fs::create_dir_all(work_dir).await?;
+    let source_path = work_dir.join(format!("source.{}", job.source_extension));
+    api.download_source(&job.job_id, &source_path)
+        .await
+        .context("failed to download the source")?;
+
+    // Encoder hardware differs per machine; every other setting comes from the server
+    let mut spec = job.spec.clone();
+    spec.video_config.encoder = state.config.video.encoder.clone();
+
+    let hls_dir = work_dir.join("hls");
+    let source = SourceProbe::probe(&source_path).await?;
+    let encoded = encode_source(
+        &state.progress,
+        &state.ffmpeg_semaphore,
+        &job.job_id,
+        &source_path,
+        &hls_dir,
+        &source,
+        &spec,
+    )
+    .await?;
+    drop(permit);
+
+    let upload_progress = ProgressUpdate {
+        stage: "Upload to R2".to_string(),
+        current_chunk: 0,
+        total_chunks: 1,
+        percentage: 0,
+        details: Some("Uploading segments to storage...".to_string()),
+        status: "processing".to_string(),
+        result: None,
+        error: None,
+        video_name: None,
+        created_at: now_millis(),
+        variant_percentage: None,
+        encode_speed: None,
+        eta_seconds: None,
+    };
+    update_progress(&state.progress, &job.job_id, upload_progress).await;
+
+    let entrypoint = upload_hls_to_r2(state, &hls_dir, &spec.prefix, Some(&job.job_id)).await?;
+    Ok(RemoteJobResult {
+        encoded,
+        entrypoint,
+    })
+}
+
+/// Send the job's local progress to the server; returns once the server no longer knows the job
+async fn report_progress(progress: ProgressMap, api: Arc<JobApi>, job_id: String) {
+    loop {
+        tokio::time::sleep(REPORT_INTERVAL).await;
+        let Some(update) = progress.read().await.get(&job_id).cloned() else {
+            continue;
+        };
+        match api.post(&format!("{}/progress", job_id), &update).await {
+            Ok(response) if response.status() == StatusCode::NOT_FOUND => return,
+            Ok(_) => {}
+            Err(e) => warn!("Failed to report progress of job {}: {}", job_id, e),
+        }
+    }
+}